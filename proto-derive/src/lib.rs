@@ -0,0 +1,422 @@
+//! `#[derive(Model)]` for proto, see `proto::ports`.
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote, quote_spanned};
+use syn::{parse_macro_input, spanned::Spanned, Data, DeriveInput, Expr, Fields, Ident, LitStr};
+
+/// Implements `Model` for a struct with named fields.
+///
+/// Fields of type `Jack` marked `#[input(voltage)]`, `#[input(midi)]`, `#[output(voltage)]`
+/// or `#[output(midi)]` are ports, `f32` fields marked `#[parameter]` are parameters. Ports
+/// and parameters are named after their field in CamelCase unless given a `name = "..."`,
+/// and parameters can be clamped with `min = ...` and `max = ...`.
+///
+/// Next to the struct a `<Name>Ports` struct is generated with a slice of the buffer of every
+/// port, and the struct implements `Process` to evaluate the model with it.
+#[proc_macro_derive(Model, attributes(input, output, parameter))]
+pub fn derive_model(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(input) {
+        Ok(tokens) => tokens.into(),
+        Err(error) => error.to_compile_error().into(),
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Io {
+    Voltage,
+    Midi,
+}
+
+struct PortField {
+    field: Ident,
+    //Where to point at if the field isn't a `Jack`
+    span: Span,
+    name: String,
+    io: Io,
+}
+
+struct ParameterField {
+    field: Ident,
+    name: String,
+    min: Option<Expr>,
+    max: Option<Expr>,
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    &input.ident,
+                    "`Model` can only be derived for structs with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "`Model` can only be derived for structs",
+            ))
+        }
+    };
+
+    let mut inputs = Vec::new();
+    let mut outputs = Vec::new();
+    let mut parameters = Vec::new();
+    let mut errors: Option<syn::Error> = None;
+    for field in fields {
+        let ident = field.ident.clone().expect("named fields have names");
+        let mut kinds = 0;
+        for attribute in &field.attrs {
+            let parsed = if attribute.path().is_ident("input") {
+                port(attribute, field).map(|port| inputs.push(port))
+            } else if attribute.path().is_ident("output") {
+                port(attribute, field).map(|port| outputs.push(port))
+            } else if attribute.path().is_ident("parameter") {
+                parameter(attribute, &ident).map(|parameter| parameters.push(parameter))
+            } else {
+                continue;
+            };
+            kinds += 1;
+            let error = match parsed {
+                Err(error) => error,
+                Ok(()) if kinds > 1 => {
+                    syn::Error::new_spanned(attribute, "a field can only be one port or parameter")
+                }
+                Ok(()) => continue,
+            };
+            match &mut errors {
+                Some(errors) => errors.combine(error),
+                None => errors = Some(error),
+            }
+        }
+    }
+    let names = [
+        ("input", names(&inputs)),
+        ("output", names(&outputs)),
+        (
+            "parameter",
+            parameters.iter().map(|p| (&p.field, &p.name)).collect(),
+        ),
+    ];
+    for (kind, names) in names {
+        for (i, (field, name)) in names.iter().enumerate() {
+            if names[..i].iter().any(|(_, other)| other == name) {
+                let error = syn::Error::new_spanned(
+                    field,
+                    format!("`{}` is already the name of another {}", name, kind),
+                );
+                match &mut errors {
+                    Some(errors) => errors.combine(error),
+                    None => errors = Some(error),
+                }
+            }
+        }
+    }
+    if let Some(errors) = errors {
+        return Err(errors);
+    }
+    if inputs.is_empty() && outputs.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &input.ident,
+            "a model needs at least one `#[input(..)]` or `#[output(..)]` field",
+        ));
+    }
+
+    let name = &input.ident;
+    let vis = &input.vis;
+    let ports_name = format_ident!("{}Ports", name);
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+
+    //Port fields only mark ports, make sure they aren't mistaken for state
+    let jack_checks = inputs.iter().chain(outputs.iter()).map(|port| {
+        let field = &port.field;
+        quote_spanned!(port.span=> let _: &::proto::ports::Jack = &self.#field;)
+    });
+    let input_types = inputs.iter().map(|port| match port.io {
+        Io::Voltage => quote!(&'a [f32]),
+        Io::Midi => quote!(&'a [::std::option::Option<::proto::midi_types::MidiMessage>]),
+    });
+    let output_types = outputs.iter().map(|port| match port.io {
+        Io::Voltage => quote!(&'a mut [f32]),
+        Io::Midi => quote!(&'a mut [::std::option::Option<::proto::midi_types::MidiMessage>]),
+    });
+    let input_fields = inputs.iter().map(|port| &port.field);
+    let output_fields = outputs.iter().map(|port| &port.field);
+    let ports_doc = format!("Buffers of the ports of a `{}` for one block", name);
+
+    let format = |ports: &[PortField]| {
+        let entries = ports.iter().map(|port| {
+            let name = &port.name;
+            let io = io_type(port.io);
+            quote!((::std::string::String::from(#name), #io))
+        });
+        quote!(::std::collections::HashMap::from([#(#entries),*]))
+    };
+    let input_format = format(&inputs);
+    let output_format = format(&outputs);
+    let constants = |ports: &[PortField]| {
+        let entries = ports.iter().map(|port| {
+            let name = &port.name;
+            let io = io_type(port.io);
+            quote!((#name, #io))
+        });
+        quote!(&[#(#entries),*])
+    };
+    let input_constants = constants(&inputs);
+    let output_constants = constants(&outputs);
+
+    let buffers = |io: Io| match io {
+        Io::Voltage => quote!(voltages),
+        Io::Midi => quote!(midi_events),
+    };
+    //Outputs are borrowed all at once and in place, so they stay put even if the model
+    //panics. Names are unique, so every port gets its buffer.
+    let of_type =
+        |io: Io| -> Vec<&PortField> { outputs.iter().filter(|port| port.io == io).collect() };
+    let (voltage_outputs, midi_outputs) = (of_type(Io::Voltage), of_type(Io::Midi));
+    let locals = |ports: &[&PortField]| -> Vec<Ident> {
+        ports.iter().map(|port| buffer(&port.field)).collect()
+    };
+    let (voltage_locals, midi_locals) = (locals(&voltage_outputs), locals(&midi_outputs));
+    let voltage_names = voltage_outputs.iter().map(|port| &port.name);
+    let midi_names = midi_outputs.iter().map(|port| &port.name);
+    let output_locals: Vec<Ident> = outputs.iter().map(|port| buffer(&port.field)).collect();
+    //A port the engine didn't hand over leaves the model out of the block, the engine has
+    //already silenced the outputs it did hand over
+    let check_outputs = if outputs.is_empty() {
+        quote!()
+    } else {
+        quote! {
+            let (#(::std::option::Option::Some(#output_locals),)*) = (#(#output_locals,)*) else {
+                return;
+            };
+        }
+    };
+    let borrow_outputs = quote! {
+        let ([#(#voltage_locals),*], [#(#midi_locals),*]) =
+            outputs.buffers_mut([#(#voltage_names),*], [#(#midi_names),*]);
+        #check_outputs
+    };
+    let borrow_inputs = inputs.iter().map(|port| {
+        let (local, name, buffers) = (buffer(&port.field), &port.name, buffers(port.io));
+        quote! {
+            let ::std::option::Option::Some(#local) = inputs.#buffers.get(#name) else {
+                return;
+            };
+        }
+    });
+    let read_inputs = inputs.iter().map(|port| {
+        let (field, local) = (&port.field, buffer(&port.field));
+        quote!(#field: #local.as_slice())
+    });
+    let lend_outputs = outputs.iter().map(|port| {
+        let (field, local) = (&port.field, buffer(&port.field));
+        quote!(#field: #local)
+    });
+
+    let parameter_entries = parameters.iter().map(|parameter| {
+        let (field, name) = (&parameter.field, &parameter.name);
+        quote!((::std::string::String::from(#name), self.#field))
+    });
+    let parameter_arms = parameters.iter().map(|parameter| {
+        let (field, name) = (&parameter.field, &parameter.name);
+        let mut value = quote!(value);
+        if let Some(min) = &parameter.min {
+            value = quote!(#value.max((#min) as f32));
+        }
+        if let Some(max) = &parameter.max {
+            value = quote!(#value.min((#max) as f32));
+        }
+        quote!(#name => self.#field = #value)
+    });
+    let parameter_info = parameters.iter().map(|parameter| {
+        let name = &parameter.name;
+        let min = parameter
+            .min
+            .as_ref()
+            .map_or(quote!(f32::NEG_INFINITY), |min| quote!((#min) as f32));
+        let max = parameter
+            .max
+            .as_ref()
+            .map_or(quote!(f32::INFINITY), |max| quote!((#max) as f32));
+        quote!(::proto::ports::ParameterInfo { name: #name, min: #min, max: #max })
+    });
+
+    Ok(quote! {
+        #[doc = #ports_doc]
+        #vis struct #ports_name<'a> {
+            #(pub #input_fields: #input_types,)*
+            #(pub #output_fields: #output_types,)*
+        }
+
+        impl #impl_generics #name #type_generics #where_clause {
+            /// Names and types of the inputs
+            pub const INPUTS: &'static [(&'static str, ::proto::IOType)] = #input_constants;
+            /// Names and types of the outputs
+            pub const OUTPUTS: &'static [(&'static str, ::proto::IOType)] = #output_constants;
+            /// Names and ranges of the parameters
+            pub const PARAMETERS: &'static [::proto::ports::ParameterInfo] =
+                &[#(#parameter_info),*];
+        }
+
+        impl #impl_generics ::proto::ports::HasPorts for #name #type_generics #where_clause {
+            type Ports<'a> = #ports_name<'a>;
+        }
+
+        impl #impl_generics ::proto::Model for #name #type_generics #where_clause {
+            fn input_format(&self) -> ::std::collections::HashMap<::std::string::String, ::proto::IOType> {
+                #input_format
+            }
+
+            fn output_format(&self) -> ::std::collections::HashMap<::std::string::String, ::proto::IOType> {
+                #output_format
+            }
+
+            fn evaluate(
+                &mut self,
+                _buffer_size: usize,
+                inputs: ::proto::Input,
+                outputs: &mut ::proto::Output,
+                config: &::proto::Config,
+            ) {
+                #(#jack_checks)*
+                #(#borrow_inputs)*
+                #borrow_outputs
+                let ports = #ports_name {
+                    #(#read_inputs,)*
+                    #(#lend_outputs,)*
+                };
+                ::proto::ports::Process::process(self, ports, config);
+            }
+
+            fn parameters(&self) -> ::std::collections::HashMap<::std::string::String, f32> {
+                ::std::collections::HashMap::from([#(#parameter_entries),*])
+            }
+
+            #[allow(unreachable_code)]
+            fn set_parameter(&mut self, name: &str, value: f32) -> bool {
+                match name {
+                    #(#parameter_arms,)*
+                    _ => return false,
+                }
+                true
+            }
+
+            fn reset(&mut self) {
+                ::proto::ports::Process::reset(self)
+            }
+
+            fn next_event(&self, time: u64, frames: usize) -> ::std::option::Option<usize> {
+                ::proto::ports::Process::next_event(self, time, frames)
+            }
+        }
+    })
+}
+
+fn port(attribute: &syn::Attribute, field: &syn::Field) -> syn::Result<PortField> {
+    let ident = field.ident.as_ref().expect("named fields have names");
+    let mut io = None;
+    let mut name = None;
+    attribute.parse_nested_meta(|meta| {
+        if meta.path.is_ident("voltage") {
+            io = Some(Io::Voltage);
+        } else if meta.path.is_ident("midi") {
+            io = Some(Io::Midi);
+        } else if meta.path.is_ident("name") {
+            name = Some(meta.value()?.parse::<LitStr>()?.value());
+        } else {
+            return Err(meta.error("expected `voltage`, `midi` or `name = \"...\"`"));
+        }
+        Ok(())
+    })?;
+    let io = io.ok_or_else(|| {
+        syn::Error::new_spanned(attribute, "the port needs a type, `voltage` or `midi`")
+    })?;
+    Ok(PortField {
+        field: ident.clone(),
+        span: field.ty.span(),
+        name: name.unwrap_or_else(|| camel_case(ident)),
+        io,
+    })
+}
+
+fn parameter(attribute: &syn::Attribute, field: &Ident) -> syn::Result<ParameterField> {
+    let mut parameter = ParameterField {
+        field: field.clone(),
+        name: camel_case(field),
+        min: None,
+        max: None,
+    };
+    //A bare `#[parameter]` has nothing to parse
+    if matches!(attribute.meta, syn::Meta::Path(_)) {
+        return Ok(parameter);
+    }
+    attribute.parse_nested_meta(|meta| {
+        if meta.path.is_ident("name") {
+            parameter.name = meta.value()?.parse::<LitStr>()?.value();
+        } else if meta.path.is_ident("min") {
+            parameter.min = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("max") {
+            parameter.max = Some(meta.value()?.parse()?);
+        } else {
+            return Err(meta.error("expected `name`, `min` or `max`"));
+        }
+        Ok(())
+    })?;
+    Ok(parameter)
+}
+
+fn names(ports: &[PortField]) -> Vec<(&Ident, &String)> {
+    ports.iter().map(|port| (&port.field, &port.name)).collect()
+}
+
+//The local an output's buffer is kept in while the model writes to it, apart from the
+//arguments of `evaluate` so fields like `outputs` don't shadow them
+fn buffer(field: &Ident) -> Ident {
+    format_ident!("{}_buffer", field)
+}
+
+fn io_type(io: Io) -> TokenStream2 {
+    match io {
+        Io::Voltage => quote!(::proto::IOType::Voltage),
+        Io::Midi => quote!(::proto::IOType::Midi),
+    }
+}
+
+//`input_1_gain` becomes `Input1Gain`
+fn camel_case(field: &Ident) -> String {
+    let field = field.to_string();
+    let field = field.strip_prefix("r#").unwrap_or(&field);
+    field
+        .split('_')
+        .map(|part| {
+            let mut chars = part.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fields_become_camel_case() {
+        let camel = |field: &str| camel_case(&Ident::new(field, Span::call_site()));
+        assert_eq!(camel("gain"), "Gain");
+        assert_eq!(camel("input_1_gain"), "Input1Gain");
+        assert_eq!(camel("note__on_"), "NoteOn");
+        assert_eq!(camel("already_Camel"), "AlreadyCamel");
+        assert_eq!(
+            camel_case(&Ident::new_raw("type", Span::call_site())),
+            "Type"
+        );
+    }
+}
@@ -1,76 +1,181 @@
-use cpal::{
-    traits::{DeviceTrait, HostTrait, StreamTrait},
-    Stream,
-};
+#[cfg(feature = "cpal")]
+use cpal::Stream;
+#[cfg(feature = "cpal")]
 use parking_lot::Mutex;
 use rtrb::{Consumer, Producer, RingBuffer};
-use std::{collections::HashMap, sync::Arc};
+#[cfg(feature = "cpal")]
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
 
-use crate::{Config, IOType, Input, Model, ModelHolder, Output};
+#[cfg(feature = "cpal")]
+use crate::{
+    backend::{
+        cpal::{InputDevice, OutputDevice},
+        BackendError, StatusCallback,
+    },
+    ModelHolder,
+};
+use crate::{
+    ports::{Jack, Process},
+    Config, Model,
+};
+use midi_types::MidiMessage;
 
-pub struct AudioInput(Consumer<f32>, Stream);
+/// Records from an input device.
+///
+/// The input device runs on its own clock, so the recording is resampled by a tiny, varying
+/// amount to keep the ring buffer between the two at the same fill level instead of slowly
+/// running empty or overflowing. Devices that share the output device's clock can be opened
+/// with `AudioInput::duplex` which skips the resampling.
+///
+/// Errors of the input stream are reported through the `StatusCallback` it is opened with,
+/// pass `Engine::status_callback` to get them on the engine's `status_events`.
+#[cfg(feature = "cpal")]
+#[derive(Model)]
+pub struct AudioInput {
+    #[output(voltage)]
+    audio: Jack,
+    consumer: Consumer<f32>,
+    #[allow(dead_code)]
+    stream: Stream,
+    resampler: DriftResampler,
+    //Set by the input callback when the ring buffer was full
+    overflowed: Arc<AtomicBool>,
+}
 
+#[cfg(feature = "cpal")]
 impl AudioInput {
-    pub fn new(stream_config: &cpal::StreamConfig) -> ModelHolder {
-        let host = cpal::default_host();
-        let input_device = host.default_input_device().unwrap();
+    /// Records from the default input device.
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(
+        sample_rate: usize,
+        buffer_size: usize,
+        status: StatusCallback,
+    ) -> Result<ModelHolder, BackendError> {
+        let input_device = InputDevice::default().ok_or(BackendError::NoDevice)?;
+        AudioInput::from_device(&input_device, sample_rate, buffer_size, status)
+    }
+
+    /// Records from `input_device`, mixing all of its channels down to one. Devices that
+    /// don't support the engine's sample rate get resampled.
+    pub fn from_device(
+        input_device: &InputDevice,
+        sample_rate: usize,
+        buffer_size: usize,
+        status: StatusCallback,
+    ) -> Result<ModelHolder, BackendError> {
+        AudioInput::open(input_device, sample_rate, buffer_size, true, status)
+    }
+
+    /// Records from the input side of the device the engine plays on. Both sides run on the
+    /// same clock so the recording is passed through as is with as little latency as possible.
+    pub fn duplex(
+        input_device: &InputDevice,
+        output_device: &OutputDevice,
+        sample_rate: usize,
+        buffer_size: usize,
+        status: StatusCallback,
+    ) -> Result<ModelHolder, BackendError> {
+        if input_device.name != output_device.name {
+            return Err(BackendError::NotDuplex);
+        }
+        AudioInput::open(input_device, sample_rate, buffer_size, false, status)
+    }
+
+    fn open(
+        input_device: &InputDevice,
+        sample_rate: usize,
+        buffer_size: usize,
+        adaptive: bool,
+        status: StatusCallback,
+    ) -> Result<ModelHolder, BackendError> {
+        let settings = input_device.best_config(1, sample_rate, buffer_size)?;
+        if !adaptive && settings.sample_rate != sample_rate {
+            return Err(BackendError::UnsupportedConfig);
+        }
+        let channels = settings.channels as usize;
         let (mut producer, consumer) = RingBuffer::<f32>::new(16384);
+        let overflowed = Arc::new(AtomicBool::new(false));
+        let input_overflowed = Arc::clone(&overflowed);
 
-        let input_data_fn = move |data: &[f32], _: &cpal::InputCallbackInfo| {
-            let mut output_fell_behind = false;
-            for &sample in data {
+        let input_data_fn = move |data: &[f32]| {
+            for frame in data.chunks(channels) {
+                let sample = frame.iter().sum::<f32>() / channels as f32;
                 if producer.push(sample).is_err() {
-                    output_fell_behind = true;
+                    input_overflowed.store(true, Ordering::Relaxed);
                 }
             }
-            if output_fell_behind {
-                eprintln!("output stream fell behind: try increasing latency");
-            }
         };
-        let input_stream = input_device
-            .build_input_stream(stream_config, input_data_fn, err_fn, None)
-            .unwrap();
-        input_stream.play().unwrap();
-        Arc::new(Mutex::new(Box::new(AudioInput(consumer, input_stream))))
+        let stream = input_device.build_stream(&settings, input_data_fn, status)?;
+        //Keep enough buffered to ride out a callback from either device
+        let device_buffer = settings.buffer_size.unwrap_or(buffer_size);
+        let target = if adaptive {
+            2 * usize::max(buffer_size, device_buffer)
+        } else {
+            device_buffer
+        };
+        let resampler = DriftResampler::new(
+            settings.sample_rate as f64 / sample_rate as f64,
+            target,
+            adaptive,
+        );
+        Ok(Arc::new(Mutex::new(Box::new(AudioInput {
+            audio: Jack,
+            consumer,
+            stream,
+            resampler,
+            overflowed,
+        }))))
     }
 }
 
-impl Model for AudioInput {
-    fn input_format(&self) -> HashMap<String, IOType> {
-        HashMap::new()
-    }
-    fn output_format(&self) -> HashMap<String, IOType> {
-        let mut outputs = HashMap::new();
-        outputs.insert(String::from("Audio"), IOType::Voltage);
-        outputs
-    }
-    fn evaluate(
-        &mut self,
-        buffer_size: usize,
-        _inputs: Input,
-        outputs: &mut Output,
-        _config: &Config,
-    ) {
-        println!("doing stuff");
-        let mut audio: Vec<f32> = Vec::with_capacity(buffer_size);
-        for _ in 0..buffer_size {
-            match self.0.pop() {
-                Ok(value) => {
-                    audio.push(value);
-                }
-                Err(_e) => {
-                    audio.push(0.);
-                }
-            }
+#[cfg(feature = "cpal")]
+impl Process for AudioInput {
+    fn process(&mut self, ports: AudioInputPorts, config: &Config) {
+        let filled = self.resampler.process(&mut self.consumer, ports.audio);
+        if !filled || self.overflowed.swap(false, Ordering::Relaxed) {
+            config.report_xrun();
         }
-        outputs.voltages.insert(String::from("Audio"), audio);
     }
 }
 
+#[cfg(feature = "cpal")]
 unsafe impl Sync for AudioInput {}
+#[cfg(feature = "cpal")]
 unsafe impl Send for AudioInput {}
 
-pub struct AudioOutput(Producer<f32>);
+/// Marks where the graph is sent to the output device.
+///
+/// The engine reads whatever is connected to `Audio` right after evaluating the graph and
+/// copies it straight into the device buffer, so there is no extra latency.
+#[derive(Model, Default)]
+pub struct DeviceOutput {
+    #[input(voltage)]
+    audio: Jack,
+}
+
+impl DeviceOutput {
+    pub fn new() -> Self {
+        DeviceOutput::default()
+    }
+}
+
+impl Process for DeviceOutput {
+    fn process(&mut self, _ports: DeviceOutputPorts, _config: &Config) {}
+}
+
+/// Pushes its input into a ring buffer so another thread can consume it at its own pace.
+///
+/// This adds the latency of whatever is waiting in the ring buffer, use `DeviceOutput` to
+/// play the graph on the engine's own device.
+#[derive(Model)]
+pub struct AudioOutput {
+    #[input(voltage)]
+    audio: Jack,
+    producer: Producer<f32>,
+}
 
 impl AudioOutput {
     pub fn new() -> (Self, rtrb::Consumer<f32>) {
@@ -78,42 +183,206 @@ impl AudioOutput {
         for _ in 0..1024 {
             producer.push(0.).unwrap();
         }
-        (AudioOutput(producer), consumer)
+        (
+            AudioOutput {
+                audio: Jack,
+                producer,
+            },
+            consumer,
+        )
     }
 }
 
-impl Model for AudioOutput {
-    fn input_format(&self) -> HashMap<String, IOType> {
-        let mut inputs = HashMap::new();
-        inputs.insert(String::from("Audio"), IOType::Voltage);
-        inputs
-    }
-    fn output_format(&self) -> HashMap<String, IOType> {
-        HashMap::new()
-    }
-    fn evaluate(
-        &mut self,
-        _buffer_size: usize,
-        inputs: Input,
-        _outputs: &mut Output,
-        _config: &Config,
-    ) {
+impl Process for AudioOutput {
+    fn process(&mut self, ports: AudioOutputPorts, config: &Config) {
         let mut output_fell_behind = false;
-        for i in inputs.voltages.get("Audio").unwrap().iter() {
-            match self.0.push(*i) {
+        for i in ports.audio.iter() {
+            match self.producer.push(*i) {
                 Ok(_) => (),
                 Err(_) => output_fell_behind = true,
             };
         }
         if output_fell_behind {
-            println!("output device fell behind: try increasing latency");
+            config.report_xrun();
         }
-        println!("{}", inputs.voltages.get("Audio").unwrap().len());
     }
 }
 
 unsafe impl Sync for AudioOutput {}
 
-fn err_fn(err: cpal::StreamError) {
-    eprintln!("an error occurred on stream: {}", err);
+/// Plays MIDI messages sent from another thread at the sample they are timestamped with.
+///
+/// Timestamps are in samples on the engine's clock (see `Engine::sample_time`). Messages that
+/// arrive late are played at the start of the next block.
+#[derive(Model)]
+pub struct MidiInput {
+    #[output(midi)]
+    midi: Jack,
+    consumer: Consumer<(u64, MidiMessage)>,
+}
+
+impl MidiInput {
+    pub fn new() -> (Self, Producer<(u64, MidiMessage)>) {
+        let (producer, consumer) = RingBuffer::<(u64, MidiMessage)>::new(1024);
+        (
+            MidiInput {
+                midi: Jack,
+                consumer,
+            },
+            producer,
+        )
+    }
+}
+
+impl Process for MidiInput {
+    fn process(&mut self, ports: MidiInputPorts, config: &Config) {
+        let midi = ports.midi;
+        let buffer_size = midi.len();
+        while let Ok((time, _)) = self.consumer.peek() {
+            let index = time.saturating_sub(config.time()) as usize;
+            if index >= buffer_size {
+                break;
+            }
+            //Only one message fits in a sample, anything else is moved back a sample
+            let free = match (index..buffer_size).find(|i| midi[*i].is_none()) {
+                Some(i) => i,
+                None => break,
+            };
+            midi[free] = self.consumer.pop().ok().map(|e| e.1);
+        }
+    }
+
+    fn next_event(&self, time: u64, frames: usize) -> Option<usize> {
+        let (event, _) = self.consumer.peek().ok()?;
+        if *event > time && *event - time < frames as u64 {
+            Some((*event - time) as usize)
+        } else {
+            None
+        }
+    }
+}
+
+unsafe impl Sync for MidiInput {}
+
+/// Reads a ring buffer that is filled on another clock.
+///
+/// When `adaptive` the read speed is nudged so the fill level settles at `target`, which
+/// absorbs the drift between the clocks. Samples are linearly interpolated between reads.
+#[cfg(feature = "cpal")]
+struct DriftResampler {
+    //Input samples read per output sample when both clocks run at their nominal rate
+    nominal_ratio: f64,
+    ratio: f64,
+    target: usize,
+    adaptive: bool,
+    primed: bool,
+    phase: f64,
+    previous: f32,
+    current: f32,
+}
+
+#[cfg(feature = "cpal")]
+impl DriftResampler {
+    //How far the read speed may be pushed away from the nominal ratio
+    const MAX_CORRECTION: f64 = 0.005;
+    //Correction per unit of relative fill level error
+    const GAIN: f64 = 0.01;
+    //How quickly the ratio follows the correction each block
+    const SMOOTHING: f64 = 0.05;
+
+    fn new(nominal_ratio: f64, target: usize, adaptive: bool) -> Self {
+        DriftResampler {
+            nominal_ratio,
+            ratio: nominal_ratio,
+            target,
+            adaptive,
+            primed: false,
+            phase: 0.,
+            previous: 0.,
+            current: 0.,
+        }
+    }
+
+    /// Fills `output`. If the ring buffer runs empty the rest of it is silent and `false` is
+    /// returned so the underrun is counted as an xrun.
+    fn process(&mut self, consumer: &mut Consumer<f32>, output: &mut [f32]) -> bool {
+        let fill = consumer.slots();
+        //Wait for the buffer to fill up to the target before starting, and again after running empty
+        if !self.primed {
+            if fill < self.target {
+                output.fill(0.);
+                return true;
+            }
+            self.primed = true;
+        }
+        if self.adaptive {
+            let error = (fill as f64 - self.target as f64) / self.target as f64;
+            let correction =
+                (error * Self::GAIN).clamp(-Self::MAX_CORRECTION, Self::MAX_CORRECTION);
+            let wanted = self.nominal_ratio * (1. + correction);
+            self.ratio += (wanted - self.ratio) * Self::SMOOTHING;
+        } else if fill > self.target * 3 {
+            //Both sides share a clock so this only happens after a hiccup, jump back to the target
+            for _ in 0..fill - self.target {
+                let _ = consumer.pop();
+            }
+        }
+        for (i, sample) in output.iter_mut().enumerate() {
+            self.phase += self.ratio;
+            while self.phase >= 1. {
+                self.phase -= 1.;
+                self.previous = self.current;
+                match consumer.pop() {
+                    Ok(value) => self.current = value,
+                    Err(_) => {
+                        //Ran empty, play silence instead of holding the last sample as DC and
+                        //start again from silence once the buffer has filled up
+                        output[i..].fill(0.);
+                        self.primed = false;
+                        self.phase = 0.;
+                        self.previous = 0.;
+                        self.current = 0.;
+                        return false;
+                    }
+                }
+            }
+            *sample = self.previous + (self.current - self.previous) * self.phase as f32;
+        }
+        true
+    }
+}
+
+#[cfg(all(test, feature = "cpal"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resampler_passes_shared_clock_through() {
+        let (mut producer, mut consumer) = RingBuffer::<f32>::new(64);
+        for i in 0..16 {
+            producer.push(i as f32).unwrap();
+        }
+        let mut resampler = DriftResampler::new(1., 8, false);
+        let mut output = [0.; 8];
+        assert!(resampler.process(&mut consumer, &mut output));
+        //Interpolating between reads delays the signal by a sample
+        assert_eq!(output, [0., 0., 1., 2., 3., 4., 5., 6.]);
+    }
+
+    #[test]
+    fn resampler_underrun_is_silent() {
+        let (mut producer, mut consumer) = RingBuffer::<f32>::new(64);
+        for _ in 0..8 {
+            producer.push(0.5).unwrap();
+        }
+        let mut resampler = DriftResampler::new(1., 8, false);
+        let mut output = [1.; 16];
+        assert!(!resampler.process(&mut consumer, &mut output));
+        assert_eq!(output[4], 0.5);
+        assert!(output[9..].iter().all(|s| *s == 0.));
+        //Waits for the buffer to fill up again instead of holding the last sample
+        let mut output = [1.; 16];
+        assert!(resampler.process(&mut consumer, &mut output));
+        assert!(output.iter().all(|s| *s == 0.));
+    }
 }
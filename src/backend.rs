@@ -0,0 +1,59 @@
+#[cfg(feature = "cpal")]
+pub mod cpal;
+mod file;
+mod null;
+
+use std::sync::Arc;
+
+pub use file::FileBackend;
+pub use null::NullBackend;
+
+use crate::EngineEvent;
+
+/// Called by a backend whenever it needs more audio. The buffer is interleaved with
+/// `AudioBackend::channels` channels and has to be filled completely.
+pub type RenderCallback = Box<dyn FnMut(&mut [f32]) + Send>;
+
+/// Lets a backend report what happens to its stream, like errors or a lost device.
+pub type StatusCallback = Arc<dyn Fn(EngineEvent) + Send + Sync>;
+
+/// Something that pulls audio out of the engine, usually a sound card.
+pub trait AudioBackend: Send {
+    /// Starts calling `render` for audio until `stop` is called.
+    fn start(&mut self, render: RenderCallback, status: StatusCallback)
+        -> Result<(), BackendError>;
+
+    fn stop(&mut self);
+
+    /// Stops like `stop` and returns the error that made the backend stop on its own, if any.
+    fn finish(&mut self) -> Result<(), BackendError> {
+        self.stop();
+        Ok(())
+    }
+
+    fn sample_rate(&self) -> usize;
+
+    fn channels(&self) -> usize;
+
+    /// The buffer size the backend asks for. Backends may ask for more or less than this at
+    /// a time, the graph splits larger requests into blocks of this size.
+    fn buffer_size(&self) -> usize;
+
+    /// Whether the backend stopped on its own, like a file backend that wrote everything.
+    fn is_finished(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Debug)]
+pub enum BackendError {
+    HostUnavailable,
+    NoDevice,
+    Device(String),
+    UnsupportedConfig,
+    //The input and output device passed to a duplex stream are not the same device
+    NotDuplex,
+    AlreadyStarted,
+    Stream(String),
+    File(String),
+}
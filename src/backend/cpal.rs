@@ -0,0 +1,592 @@
+use std::{
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use cpal::{
+    traits::{DeviceTrait, HostTrait, StreamTrait},
+    FromSample, SampleRate, SizedSample, Stream, StreamConfig,
+};
+
+use parking_lot::Mutex;
+
+use super::{AudioBackend, BackendError, RenderCallback, StatusCallback};
+use crate::EngineEvent;
+
+/// One of the audio systems cpal can talk to, like ALSA or JACK.
+pub struct Host {
+    pub name: String,
+    id: cpal::HostId,
+}
+
+/// Lists every host that is available on this machine.
+pub fn hosts() -> Vec<Host> {
+    cpal::available_hosts()
+        .into_iter()
+        .map(|id| Host {
+            name: String::from(id.name()),
+            id,
+        })
+        .collect()
+}
+
+impl Host {
+    #[allow(clippy::should_implement_trait)]
+    pub fn default() -> Self {
+        Host::from_id(cpal::default_host().id())
+    }
+
+    fn from_id(id: cpal::HostId) -> Self {
+        Host {
+            name: String::from(id.name()),
+            id,
+        }
+    }
+
+    fn host(&self) -> Result<cpal::Host, BackendError> {
+        cpal::host_from_id(self.id).map_err(|_| BackendError::HostUnavailable)
+    }
+
+    pub fn output_devices(&self) -> Result<Vec<OutputDevice>, BackendError> {
+        let devices = self
+            .host()?
+            .output_devices()
+            .map_err(|e| BackendError::Device(e.to_string()))?;
+        Ok(devices
+            .map(|d| OutputDevice::from_cpal(d, self.id))
+            .collect())
+    }
+
+    pub fn input_devices(&self) -> Result<Vec<InputDevice>, BackendError> {
+        let devices = self
+            .host()?
+            .input_devices()
+            .map_err(|e| BackendError::Device(e.to_string()))?;
+        Ok(devices.map(InputDevice::from_cpal).collect())
+    }
+
+    pub fn default_output_device(&self) -> Option<OutputDevice> {
+        let device = self.host().ok()?.default_output_device()?;
+        Some(OutputDevice::from_cpal(device, self.id))
+    }
+
+    pub fn default_input_device(&self) -> Option<InputDevice> {
+        let device = self.host().ok()?.default_input_device()?;
+        Some(InputDevice::from_cpal(device))
+    }
+}
+
+#[derive(Clone)]
+pub struct OutputDevice {
+    pub name: String,
+    device: Arc<cpal::Device>,
+    host: cpal::HostId,
+}
+
+impl OutputDevice {
+    /// The default output device of the default host
+    #[allow(clippy::should_implement_trait)]
+    pub fn default() -> Option<Self> {
+        Host::default().default_output_device()
+    }
+
+    fn from_cpal(device: cpal::Device, host: cpal::HostId) -> Self {
+        OutputDevice {
+            name: device_name(&device),
+            device: Arc::new(device),
+            host,
+        }
+    }
+
+    pub fn supported_configs(&self) -> Result<Vec<SupportedConfig>, BackendError> {
+        let configs = self
+            .device
+            .supported_output_configs()
+            .map_err(|e| BackendError::Device(e.to_string()))?;
+        Ok(configs.filter_map(SupportedConfig::from_cpal).collect())
+    }
+
+    /// The supported config closest to what was asked for, see `best_config`.
+    pub fn best_config(
+        &self,
+        channels: u16,
+        sample_rate: usize,
+        buffer_size: usize,
+    ) -> Result<StreamSettings, BackendError> {
+        best_config(
+            &self.supported_configs()?,
+            channels,
+            sample_rate,
+            buffer_size,
+        )
+    }
+}
+
+#[derive(Clone)]
+pub struct InputDevice {
+    pub name: String,
+    device: Arc<cpal::Device>,
+}
+
+impl InputDevice {
+    /// The default input device of the default host
+    #[allow(clippy::should_implement_trait)]
+    pub fn default() -> Option<Self> {
+        Host::default().default_input_device()
+    }
+
+    fn from_cpal(device: cpal::Device) -> Self {
+        InputDevice {
+            name: device_name(&device),
+            device: Arc::new(device),
+        }
+    }
+
+    pub fn supported_configs(&self) -> Result<Vec<SupportedConfig>, BackendError> {
+        let configs = self
+            .device
+            .supported_input_configs()
+            .map_err(|e| BackendError::Device(e.to_string()))?;
+        Ok(configs.filter_map(SupportedConfig::from_cpal).collect())
+    }
+
+    /// The supported config closest to what was asked for, see `best_config`.
+    pub fn best_config(
+        &self,
+        channels: u16,
+        sample_rate: usize,
+        buffer_size: usize,
+    ) -> Result<StreamSettings, BackendError> {
+        best_config(
+            &self.supported_configs()?,
+            channels,
+            sample_rate,
+            buffer_size,
+        )
+    }
+
+    /// Opens an input stream that hands every buffer to `callback` converted to f32. Errors
+    /// of the stream are reported through `status`.
+    pub(crate) fn build_stream(
+        &self,
+        settings: &StreamSettings,
+        callback: impl FnMut(&[f32]) + Send + 'static,
+        status: StatusCallback,
+    ) -> Result<Stream, BackendError> {
+        let name = self.name.clone();
+        let error_fn = move |err: cpal::StreamError| match err {
+            cpal::StreamError::DeviceNotAvailable => {
+                status(EngineEvent::DeviceLost(name.clone()));
+            }
+            cpal::StreamError::BackendSpecific { err } => {
+                status(EngineEvent::StreamError(err.description));
+            }
+        };
+        let device = &self.device;
+        let stream = match settings.sample_format {
+            SampleFormat::F32 => build_input_stream::<f32>(device, settings, callback, error_fn),
+            SampleFormat::I16 => build_input_stream::<i16>(device, settings, callback, error_fn),
+            SampleFormat::U16 => build_input_stream::<u16>(device, settings, callback, error_fn),
+        }?;
+        stream
+            .play()
+            .map_err(|e| BackendError::Stream(e.to_string()))?;
+        Ok(stream)
+    }
+}
+
+/// The output devices of the default host
+pub fn list_devices() -> Result<Vec<OutputDevice>, BackendError> {
+    Host::default().output_devices()
+}
+
+fn device_name(device: &cpal::Device) -> String {
+    device
+        .name()
+        .unwrap_or_else(|_| String::from("unknown device"))
+}
+
+/// Sample formats the engine can convert to and from at the device boundary.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SampleFormat {
+    F32,
+    I16,
+    U16,
+}
+
+/// A range of stream configs a device supports.
+#[derive(Clone, Debug)]
+pub struct SupportedConfig {
+    pub channels: u16,
+    pub min_sample_rate: usize,
+    pub max_sample_rate: usize,
+    /// Smallest and largest fixed buffer size, `None` when the device doesn't say
+    pub buffer_sizes: Option<(usize, usize)>,
+    pub sample_format: SampleFormat,
+}
+
+impl SupportedConfig {
+    fn from_cpal(config: cpal::SupportedStreamConfigRange) -> Option<Self> {
+        let sample_format = match config.sample_format() {
+            cpal::SampleFormat::F32 => SampleFormat::F32,
+            cpal::SampleFormat::I16 => SampleFormat::I16,
+            cpal::SampleFormat::U16 => SampleFormat::U16,
+            _ => return None,
+        };
+        let buffer_sizes = match config.buffer_size() {
+            cpal::SupportedBufferSize::Range { min, max } => Some((*min as usize, *max as usize)),
+            cpal::SupportedBufferSize::Unknown => None,
+        };
+        Some(SupportedConfig {
+            channels: config.channels(),
+            min_sample_rate: config.min_sample_rate().0 as usize,
+            max_sample_rate: config.max_sample_rate().0 as usize,
+            buffer_sizes,
+            sample_format,
+        })
+    }
+}
+
+/// The settings a stream is opened with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StreamSettings {
+    pub channels: u16,
+    pub sample_rate: usize,
+    /// Fixed buffer size to ask for, `None` leaves it up to the device
+    pub buffer_size: Option<usize>,
+    pub sample_format: SampleFormat,
+}
+
+impl StreamSettings {
+    fn stream_config(&self) -> StreamConfig {
+        StreamConfig {
+            channels: self.channels,
+            sample_rate: SampleRate(self.sample_rate as u32),
+            buffer_size: match self.buffer_size {
+                Some(size) => cpal::BufferSize::Fixed(size as u32),
+                None => cpal::BufferSize::Default,
+            },
+        }
+    }
+}
+
+/// Picks the config closest to the requested one.
+///
+/// The sample rate matters most since the graph runs at whatever rate the device uses, then
+/// the channel count (more channels are better than fewer) and then the sample format, with
+/// f32 preferred because it needs no conversion. The buffer size is clamped to what the device
+/// supports.
+pub fn best_config(
+    supported: &[SupportedConfig],
+    channels: u16,
+    sample_rate: usize,
+    buffer_size: usize,
+) -> Result<StreamSettings, BackendError> {
+    let best = supported
+        .iter()
+        .min_by_key(|config| {
+            let rate_distance = if sample_rate < config.min_sample_rate {
+                config.min_sample_rate - sample_rate
+            } else {
+                sample_rate.saturating_sub(config.max_sample_rate)
+            };
+            let channel_distance = if config.channels >= channels {
+                (config.channels - channels) as usize
+            } else {
+                1000 + (channels - config.channels) as usize
+            };
+            let format_rank = match config.sample_format {
+                SampleFormat::F32 => 0,
+                SampleFormat::I16 => 1,
+                SampleFormat::U16 => 2,
+            };
+            (rate_distance, channel_distance, format_rank)
+        })
+        .ok_or(BackendError::UnsupportedConfig)?;
+    Ok(StreamSettings {
+        channels: best.channels,
+        sample_rate: sample_rate.clamp(best.min_sample_rate, best.max_sample_rate),
+        buffer_size: best
+            .buffer_sizes
+            .map(|(min, max)| buffer_size.clamp(min, max)),
+        sample_format: best.sample_format,
+    })
+}
+
+/// Plays the graph on a cpal output device.
+///
+/// The stream lives on its own thread. If the device disappears the thread keeps trying to
+/// open it again, falling back to the host's default output device, until the backend is
+/// stopped.
+pub struct CpalBackend {
+    device: OutputDevice,
+    settings: StreamSettings,
+    //Block size used when the device picks the buffer size
+    requested_buffer_size: usize,
+    thread: Option<(Sender<StreamCommand>, JoinHandle<()>)>,
+}
+
+enum StreamCommand {
+    Stop,
+    DeviceLost,
+}
+
+//How long to wait between attempts to reopen a lost device
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(500);
+
+impl CpalBackend {
+    /// Opens `output_device` with the config closest to the requested one.
+    pub fn new(
+        output_device: &OutputDevice,
+        buffer_size: usize,
+        sample_rate: usize,
+    ) -> Result<Self, BackendError> {
+        let settings = output_device.best_config(1, sample_rate, buffer_size)?;
+        Ok(CpalBackend::with_settings(
+            output_device,
+            settings,
+            buffer_size,
+        ))
+    }
+
+    /// Opens `output_device` with exactly `settings`.
+    pub fn with_settings(
+        output_device: &OutputDevice,
+        settings: StreamSettings,
+        buffer_size: usize,
+    ) -> Self {
+        CpalBackend {
+            device: output_device.clone(),
+            settings,
+            requested_buffer_size: buffer_size,
+            thread: None,
+        }
+    }
+
+    pub fn settings(&self) -> &StreamSettings {
+        &self.settings
+    }
+}
+
+impl AudioBackend for CpalBackend {
+    fn start(
+        &mut self,
+        render: RenderCallback,
+        status: StatusCallback,
+    ) -> Result<(), BackendError> {
+        if self.thread.is_some() {
+            return Err(BackendError::AlreadyStarted);
+        }
+        let (commands, receiver) = mpsc::channel();
+        let (started, started_receiver) = mpsc::channel();
+        let device = self.device.clone();
+        let settings = self.settings.clone();
+        let stream_commands = commands.clone();
+        let thread = thread::Builder::new()
+            .name(String::from("proto-cpal-stream"))
+            .spawn(move || {
+                stream_thread(
+                    device,
+                    settings,
+                    Arc::new(Mutex::new(render)),
+                    status,
+                    stream_commands,
+                    receiver,
+                    started,
+                )
+            })
+            .map_err(|e| BackendError::Stream(e.to_string()))?;
+        match started_receiver.recv() {
+            Ok(Ok(())) => {
+                self.thread = Some((commands, thread));
+                Ok(())
+            }
+            Ok(Err(e)) => {
+                let _ = thread.join();
+                Err(e)
+            }
+            Err(_) => {
+                let _ = thread.join();
+                Err(BackendError::Stream(String::from("stream thread exited")))
+            }
+        }
+    }
+
+    fn stop(&mut self) {
+        if let Some((commands, thread)) = self.thread.take() {
+            let _ = commands.send(StreamCommand::Stop);
+            let _ = thread.join();
+        }
+    }
+
+    fn sample_rate(&self) -> usize {
+        self.settings.sample_rate
+    }
+
+    fn channels(&self) -> usize {
+        self.settings.channels as usize
+    }
+
+    fn buffer_size(&self) -> usize {
+        self.settings
+            .buffer_size
+            .unwrap_or(self.requested_buffer_size)
+    }
+}
+
+impl Drop for CpalBackend {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+//Owns the stream so it never has to cross threads, and reopens it when the device goes away
+fn stream_thread(
+    mut device: OutputDevice,
+    settings: StreamSettings,
+    render: Arc<Mutex<RenderCallback>>,
+    status: StatusCallback,
+    commands: Sender<StreamCommand>,
+    receiver: Receiver<StreamCommand>,
+    started: Sender<Result<(), BackendError>>,
+) {
+    let open = |device: &OutputDevice| -> Result<Stream, BackendError> {
+        let commands = Mutex::new(commands.clone());
+        let status = Arc::clone(&status);
+        let error_fn = move |err: cpal::StreamError| match err {
+            cpal::StreamError::DeviceNotAvailable => {
+                let _ = commands.lock().send(StreamCommand::DeviceLost);
+            }
+            cpal::StreamError::BackendSpecific { err } => {
+                status(EngineEvent::StreamError(err.description));
+            }
+        };
+        let render = Arc::clone(&render);
+        let stream = match settings.sample_format {
+            SampleFormat::F32 => {
+                build_output_stream::<f32>(&device.device, &settings, render, error_fn)
+            }
+            SampleFormat::I16 => {
+                build_output_stream::<i16>(&device.device, &settings, render, error_fn)
+            }
+            SampleFormat::U16 => {
+                build_output_stream::<u16>(&device.device, &settings, render, error_fn)
+            }
+        }?;
+        stream
+            .play()
+            .map_err(|e| BackendError::Stream(e.to_string()))?;
+        Ok(stream)
+    };
+
+    let mut stream = match open(&device) {
+        Ok(stream) => {
+            let _ = started.send(Ok(()));
+            Some(stream)
+        }
+        Err(e) => {
+            let _ = started.send(Err(e));
+            return;
+        }
+    };
+    loop {
+        let command = if stream.is_some() {
+            receiver.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        } else {
+            receiver.recv_timeout(RECONNECT_INTERVAL)
+        };
+        match command {
+            Ok(StreamCommand::Stop) | Err(RecvTimeoutError::Disconnected) => return,
+            Ok(StreamCommand::DeviceLost) => {
+                if stream.take().is_some() {
+                    status(EngineEvent::DeviceLost(device.name.clone()));
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                //Prefer the device that was lost, otherwise whatever the host plays on now
+                let host = Host::from_id(device.host);
+                let candidate = host
+                    .output_devices()
+                    .ok()
+                    .and_then(|d| d.into_iter().find(|d| d.name == device.name))
+                    .or_else(|| host.default_output_device());
+                if let Some(candidate) = candidate {
+                    if let Ok(new_stream) = open(&candidate) {
+                        device = candidate;
+                        stream = Some(new_stream);
+                        status(EngineEvent::Reconnected(device.name.clone()));
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn build_output_stream<T>(
+    device: &cpal::Device,
+    settings: &StreamSettings,
+    render: Arc<Mutex<RenderCallback>>,
+    error_fn: impl FnMut(cpal::StreamError) + Send + 'static,
+) -> Result<Stream, BackendError>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let config = settings.stream_config();
+    let stream = if T::FORMAT == cpal::SampleFormat::F32 {
+        device.build_output_stream(
+            &config,
+            move |data: &mut [f32], _: &cpal::OutputCallbackInfo| (render.lock())(data),
+            error_fn,
+            None,
+        )
+    } else {
+        //Render into an f32 buffer and convert it into the device format. The buffer only
+        //allocates if the device asks for more than it ever did before
+        let mut buffer: Vec<f32> =
+            Vec::with_capacity(settings.buffer_size.unwrap_or(1024) * settings.channels as usize);
+        device.build_output_stream(
+            &config,
+            move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+                buffer.resize(data.len(), 0.);
+                (render.lock())(&mut buffer);
+                for (out, sample) in data.iter_mut().zip(buffer.iter()) {
+                    *out = T::from_sample(*sample);
+                }
+            },
+            error_fn,
+            None,
+        )
+    };
+    stream.map_err(|e| BackendError::Stream(e.to_string()))
+}
+
+fn build_input_stream<T>(
+    device: &cpal::Device,
+    settings: &StreamSettings,
+    mut callback: impl FnMut(&[f32]) + Send + 'static,
+    error_fn: impl FnMut(cpal::StreamError) + Send + 'static,
+) -> Result<Stream, BackendError>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    let config = settings.stream_config();
+    let mut buffer: Vec<f32> =
+        Vec::with_capacity(settings.buffer_size.unwrap_or(1024) * settings.channels as usize);
+    device
+        .build_input_stream(
+            &config,
+            move |data: &[T], _: &cpal::InputCallbackInfo| {
+                buffer.clear();
+                buffer.extend(data.iter().map(|s| s.to_sample::<f32>()));
+                callback(&buffer);
+            },
+            error_fn,
+            None,
+        )
+        .map_err(|e| BackendError::Stream(e.to_string()))
+}
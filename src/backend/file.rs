@@ -0,0 +1,176 @@
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+};
+
+use hound::{SampleFormat, WavSpec, WavWriter};
+
+use super::{AudioBackend, BackendError, RenderCallback, StatusCallback};
+use crate::EngineEvent;
+
+/// Renders the graph as fast as possible into a 32 bit float WAV file.
+///
+/// Rendering starts with the engine and stops once `frames` frames have been written.
+pub struct FileBackend {
+    path: PathBuf,
+    sample_rate: usize,
+    channels: usize,
+    buffer_size: usize,
+    frames: usize,
+    running: Arc<AtomicBool>,
+    finished: Arc<AtomicBool>,
+    thread: Option<JoinHandle<Result<(), BackendError>>>,
+}
+
+impl FileBackend {
+    pub fn new(
+        path: impl Into<PathBuf>,
+        sample_rate: usize,
+        channels: usize,
+        buffer_size: usize,
+        frames: usize,
+    ) -> Self {
+        FileBackend {
+            path: path.into(),
+            sample_rate,
+            channels,
+            buffer_size,
+            frames,
+            running: Arc::new(AtomicBool::new(false)),
+            finished: Arc::new(AtomicBool::new(false)),
+            thread: None,
+        }
+    }
+
+    fn wait(&mut self) -> Result<(), BackendError> {
+        match self.thread.take() {
+            Some(thread) => thread
+                .join()
+                .map_err(|_| BackendError::File(String::from("render thread panicked")))?,
+            None => Ok(()),
+        }
+    }
+}
+
+impl AudioBackend for FileBackend {
+    fn start(
+        &mut self,
+        mut render: RenderCallback,
+        status: StatusCallback,
+    ) -> Result<(), BackendError> {
+        if self.thread.is_some() {
+            return Err(BackendError::AlreadyStarted);
+        }
+        let spec = WavSpec {
+            channels: self.channels as u16,
+            sample_rate: self.sample_rate as u32,
+            bits_per_sample: 32,
+            sample_format: SampleFormat::Float,
+        };
+        let mut writer =
+            WavWriter::create(&self.path, spec).map_err(|e| BackendError::File(e.to_string()))?;
+        self.running.store(true, Ordering::Release);
+        self.finished.store(false, Ordering::Release);
+        let running = Arc::clone(&self.running);
+        let finished = Arc::clone(&self.finished);
+        let channels = self.channels;
+        let mut buffer = vec![0.; self.buffer_size * channels];
+        let mut remaining = self.frames;
+        let thread = thread::Builder::new()
+            .name(String::from("proto-file-backend"))
+            .spawn(move || {
+                let write = || -> Result<(), hound::Error> {
+                    while remaining > 0 && running.load(Ordering::Acquire) {
+                        let frames = usize::min(remaining, buffer.len() / channels);
+                        let buffer = &mut buffer[..frames * channels];
+                        render(buffer);
+                        for sample in buffer.iter() {
+                            writer.write_sample(*sample)?;
+                        }
+                        remaining -= frames;
+                    }
+                    writer.finalize()
+                };
+                let result = write().map_err(|e| BackendError::File(e.to_string()));
+                finished.store(true, Ordering::Release);
+                match &result {
+                    Ok(()) => status(EngineEvent::Finished),
+                    Err(BackendError::File(e)) => status(EngineEvent::StreamError(e.clone())),
+                    Err(_) => (),
+                }
+                result
+            })
+            .map_err(|e| BackendError::File(e.to_string()))?;
+        self.thread = Some(thread);
+        Ok(())
+    }
+
+    fn stop(&mut self) {
+        let _ = self.finish();
+    }
+
+    fn finish(&mut self) -> Result<(), BackendError> {
+        self.running.store(false, Ordering::Release);
+        self.wait()
+    }
+
+    fn sample_rate(&self) -> usize {
+        self.sample_rate
+    }
+
+    fn channels(&self) -> usize {
+        self.channels
+    }
+
+    fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::Acquire)
+    }
+}
+
+impl Drop for FileBackend {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use super::*;
+    use crate::Engine;
+
+    fn render(path: impl Into<PathBuf>) -> Result<(), BackendError> {
+        let backend = FileBackend::new(path, 48000, 2, 64, 48000);
+        let (mut engine, _) = Engine::with_backend(Box::new(backend));
+        engine.start()?;
+        while !engine.is_finished() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        engine.finish()
+    }
+
+    #[test]
+    fn finishing_reports_whether_everything_was_written() {
+        let path = std::env::temp_dir().join(format!("proto-render-{}.wav", std::process::id()));
+        let result = render(&path);
+        let length = std::fs::metadata(&path).map(|m| m.len());
+        let _ = std::fs::remove_file(&path);
+        assert!(result.is_ok());
+        assert!(length.unwrap() > 48000 * 2 * 4);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn finishing_reports_a_full_disk() {
+        assert!(matches!(render("/dev/full"), Err(BackendError::File(_))));
+    }
+}
@@ -0,0 +1,93 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use super::{AudioBackend, BackendError, RenderCallback, StatusCallback};
+
+/// Runs the graph in real time on a timer and throws the audio away.
+///
+/// Useful on machines without a sound card and for driving the engine in tests.
+pub struct NullBackend {
+    sample_rate: usize,
+    channels: usize,
+    buffer_size: usize,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl NullBackend {
+    pub fn new(sample_rate: usize, channels: usize, buffer_size: usize) -> Self {
+        NullBackend {
+            sample_rate,
+            channels,
+            buffer_size,
+            running: Arc::new(AtomicBool::new(false)),
+            thread: None,
+        }
+    }
+}
+
+impl AudioBackend for NullBackend {
+    fn start(
+        &mut self,
+        mut render: RenderCallback,
+        _status: StatusCallback,
+    ) -> Result<(), BackendError> {
+        if self.thread.is_some() {
+            return Err(BackendError::AlreadyStarted);
+        }
+        self.running.store(true, Ordering::Release);
+        let running = Arc::clone(&self.running);
+        let mut buffer = vec![0.; self.buffer_size * self.channels];
+        let period = Duration::from_secs_f64(self.buffer_size as f64 / self.sample_rate as f64);
+        let thread = thread::Builder::new()
+            .name(String::from("proto-null-backend"))
+            .spawn(move || {
+                //Sleep until a deadline instead of for a period so the timer doesn't drift
+                let mut deadline = Instant::now();
+                while running.load(Ordering::Acquire) {
+                    render(&mut buffer);
+                    deadline += period;
+                    let now = Instant::now();
+                    if deadline > now {
+                        thread::sleep(deadline - now);
+                    } else {
+                        deadline = now;
+                    }
+                }
+            })
+            .map_err(|e| BackendError::Stream(e.to_string()))?;
+        self.thread = Some(thread);
+        Ok(())
+    }
+
+    fn stop(&mut self) {
+        self.running.store(false, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+
+    fn sample_rate(&self) -> usize {
+        self.sample_rate
+    }
+
+    fn channels(&self) -> usize {
+        self.channels
+    }
+
+    fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+}
+
+impl Drop for NullBackend {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
@@ -0,0 +1,588 @@
+//! Building patches in Rust without spelling out every `Connection`.
+//!
+//! Components added to a `PatchBuilder` can be wired up as they are added, and give `Handle`s
+//! that know the ports and parameters of their model, so a misspelled port is caught when it
+//! is named instead of when the patch is committed:
+//!
+//! ```
+//! # use proto::{backend::NullBackend, builder::PatchBuilder, model_utils::*, Engine};
+//! # fn main() -> Result<(), proto::builder::BuildError> {
+//! # let (mut engine, _) = Engine::with_backend(Box::new(NullBackend::new(48000, 1, 64)));
+//! let mut patch = PatchBuilder::new(&mut engine);
+//! let out = patch.output();
+//! let mix = patch
+//!     .add(DuoSignalMixer::new(1., 1.))
+//!     .out("Output")?
+//!     .to(out.input("Audio")?)?
+//!     .handle();
+//! patch
+//!     .add(ConstantAmplifier::new(0.5))
+//!     .out("Output")?
+//!     .to(mix.input("Input1")?)?;
+//! let vca = patch.add(Vca::new()).handle();
+//! patch.connect(mix.output("Output")?, vca.input("Input")?)?;
+//! patch.commit()?;
+//! # assert_eq!(engine.connections().len(), 3);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The `patch!` macro writes the same with the patch language's syntax, see `patch`.
+
+use std::{collections::HashMap, ops::Deref, sync::Arc};
+
+use parking_lot::Mutex;
+
+use crate::{
+    export::short_type_name,
+    patch::suggest,
+    transaction::{Edit, Transaction, TransactionError},
+    Connection, ConnectionError, Engine, IOType, Model, ModelHolder, Port,
+};
+
+#[derive(Debug)]
+pub enum BuildError {
+    UnknownInput {
+        component: String,
+        input: String,
+        suggestion: Option<String>,
+    },
+    UnknownOutput {
+        component: String,
+        output: String,
+        suggestion: Option<String>,
+    },
+    UnknownParameter {
+        component: String,
+        parameter: String,
+        suggestion: Option<String>,
+    },
+    /// The ports can't be connected, like a MIDI output to a voltage input
+    Connection(ConnectionError),
+    Transaction(TransactionError),
+}
+
+impl From<TransactionError> for BuildError {
+    fn from(error: TransactionError) -> Self {
+        BuildError::Transaction(error)
+    }
+}
+
+/// A component of a patch, with the ports and parameters its model declared when it was
+/// added. Stays valid after the patch is committed so it can be used for later edits.
+#[derive(Clone, Debug)]
+pub struct Handle {
+    id: usize,
+    type_name: &'static str,
+    inputs: HashMap<String, IOType>,
+    outputs: HashMap<String, IOType>,
+    parameters: Vec<String>,
+}
+
+/// An input that exists on its component, made by `Handle::input`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InputPort(Port);
+
+/// An output that exists on its component, made by `Handle::output`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutputPort(Port);
+
+impl Handle {
+    fn new(id: usize, model: &ModelHolder) -> Self {
+        let model = model.lock();
+        Handle {
+            id,
+            type_name: model.type_name(),
+            inputs: model.input_format(),
+            outputs: model.output_format(),
+            parameters: model.parameters().into_keys().collect(),
+        }
+    }
+
+    /// ID of the component in the engine once the patch is committed
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn input(&self, name: &str) -> Result<InputPort, BuildError> {
+        match self.inputs.get(name) {
+            Some(io) => Ok(InputPort(Port {
+                id: self.id,
+                io: io.clone(),
+                name: name.to_string(),
+            })),
+            None => Err(BuildError::UnknownInput {
+                component: self.component(),
+                input: name.to_string(),
+                suggestion: suggest(name, self.inputs.keys()),
+            }),
+        }
+    }
+
+    pub fn output(&self, name: &str) -> Result<OutputPort, BuildError> {
+        match self.outputs.get(name) {
+            Some(io) => Ok(OutputPort(Port {
+                id: self.id,
+                io: io.clone(),
+                name: name.to_string(),
+            })),
+            None => Err(BuildError::UnknownOutput {
+                component: self.component(),
+                output: name.to_string(),
+                suggestion: suggest(name, self.outputs.keys()),
+            }),
+        }
+    }
+
+    //How errors refer to the component
+    fn component(&self) -> String {
+        format!("{} #{}", short_type_name(self.type_name), self.id)
+    }
+}
+
+/// A component just added to a `PatchBuilder`. Its outputs can be connected right away with
+/// `out`, `handle` gives the `Handle` to keep for later.
+pub struct Added<'p, 'a> {
+    builder: &'p mut PatchBuilder<'a>,
+    handle: Handle,
+}
+
+/// An output of a component just added, waiting for `to`
+pub struct Cable<'p, 'a> {
+    added: Added<'p, 'a>,
+    from: OutputPort,
+}
+
+impl<'p, 'a> Added<'p, 'a> {
+    pub fn out(self, name: &str) -> Result<Cable<'p, 'a>, BuildError> {
+        let from = self.handle.output(name)?;
+        Ok(Cable { added: self, from })
+    }
+
+    pub fn handle(self) -> Handle {
+        self.handle
+    }
+}
+
+impl Deref for Added<'_, '_> {
+    type Target = Handle;
+
+    fn deref(&self) -> &Handle {
+        &self.handle
+    }
+}
+
+impl<'p, 'a> Cable<'p, 'a> {
+    /// Connects the output to `input`, and gives the component back to connect more outputs
+    pub fn to(self, input: InputPort) -> Result<Added<'p, 'a>, BuildError> {
+        let Cable { added, from } = self;
+        added.builder.connect(from, input)?;
+        Ok(added)
+    }
+}
+
+impl InputPort {
+    pub fn port(&self) -> &Port {
+        &self.0
+    }
+}
+
+impl OutputPort {
+    pub fn port(&self) -> &Port {
+        &self.0
+    }
+}
+
+/// What `PatchBuilder::add` takes, a model or one that is already shared like the ones
+/// `Tone::new` and `AudioInput::new` return
+pub trait IntoHolder {
+    fn into_holder(self) -> ModelHolder;
+}
+
+impl<M: Model + Send + Sync + 'static> IntoHolder for M {
+    fn into_holder(self) -> ModelHolder {
+        Arc::new(Mutex::new(Box::new(self)))
+    }
+}
+
+impl IntoHolder for ModelHolder {
+    fn into_holder(self) -> ModelHolder {
+        self
+    }
+}
+
+/// Collects edits to an engine and applies them as one transaction with `commit`, see
+/// `Engine::commit`.
+pub struct PatchBuilder<'a> {
+    engine: &'a mut Engine,
+    transaction: Transaction,
+}
+
+impl<'a> PatchBuilder<'a> {
+    pub fn new(engine: &'a mut Engine) -> Self {
+        let transaction = engine.transaction();
+        PatchBuilder {
+            engine,
+            transaction,
+        }
+    }
+
+    pub fn add(&mut self, model: impl IntoHolder) -> Added<'_, 'a> {
+        let model = model.into_holder();
+        let id = self.transaction.add_model(model.clone());
+        Added {
+            handle: Handle::new(id, &model),
+            builder: self,
+        }
+    }
+
+    /// A handle to a component that is already in the engine
+    pub fn handle(&self, id: usize) -> Option<Handle> {
+        let info = self.engine.component_info(id)?;
+        let ports = |ports: Vec<Port>| ports.into_iter().map(|p| (p.name, p.io)).collect();
+        Some(Handle {
+            id,
+            type_name: info.type_name,
+            inputs: ports(info.inputs),
+            outputs: ports(info.outputs),
+            parameters: self
+                .engine
+                .parameters(id)
+                .unwrap_or_default()
+                .into_keys()
+                .collect(),
+        })
+    }
+
+    /// A handle to the component that is played on the output device
+    pub fn output(&self) -> Handle {
+        self.handle(self.engine.output_id())
+            .expect("the output is always in the graph")
+    }
+
+    /// Connects two checked ports. Outputs only go to inputs:
+    ///
+    /// ```compile_fail
+    /// # use proto::{backend::NullBackend, builder::PatchBuilder, model_utils::Vca, Engine};
+    /// # let (mut engine, _) = Engine::with_backend(Box::new(NullBackend::new(48000, 1, 64)));
+    /// let mut patch = PatchBuilder::new(&mut engine);
+    /// let out = patch.output();
+    /// let vca = patch.add(Vca::new()).handle();
+    /// patch.connect(out.input("Audio").unwrap(), vca.input("Input").unwrap());
+    /// ```
+    pub fn connect(&mut self, from: OutputPort, to: InputPort) -> Result<&mut Self, BuildError> {
+        if from.0.io != to.0.io {
+            return Err(BuildError::Connection(
+                ConnectionError::MismatchedConnectionTypes,
+            ));
+        }
+        self.transaction.add_connection(Connection {
+            from: from.0,
+            to: to.0,
+        });
+        Ok(self)
+    }
+
+    pub fn disconnect(&mut self, from: OutputPort, to: InputPort) -> &mut Self {
+        self.transaction.remove_connection(Connection {
+            from: from.0,
+            to: to.0,
+        });
+        self
+    }
+
+    pub fn remove(&mut self, component: &Handle) -> &mut Self {
+        //Parameters set on the component so far would have nothing to apply to
+        self.transaction
+            .edits
+            .retain(|e| !matches!(e, Edit::SetParameter(id, ..) if *id == component.id));
+        self.transaction.remove_model(component.id);
+        self
+    }
+
+    /// Names the component for introspection tools, and for patches and the REPL to refer
+    /// to it by
+    pub fn label(&mut self, component: &Handle, label: &str) -> &mut Self {
+        self.transaction
+            .edits
+            .push(Edit::SetLabel(component.id, Some(label.to_string())));
+        self
+    }
+
+    pub fn set_parameter(
+        &mut self,
+        component: &Handle,
+        name: &str,
+        value: f32,
+    ) -> Result<&mut Self, BuildError> {
+        if !component.parameters.iter().any(|p| p == name) {
+            return Err(BuildError::UnknownParameter {
+                component: component.component(),
+                parameter: name.to_string(),
+                suggestion: suggest(name, &component.parameters),
+            });
+        }
+        self.transaction
+            .edits
+            .push(Edit::SetParameter(component.id, name.to_string(), value));
+        Ok(self)
+    }
+
+    /// Applies everything at once. If any edit fails nothing is applied.
+    pub fn commit(self) -> Result<(), TransactionError> {
+        self.engine.commit(self.transaction)
+    }
+}
+
+/// Adds components, connections and parameters to a `PatchBuilder` with the syntax of the
+/// patch language. Components are bound to handles named like them, so they can be used
+/// after the macro and by handles made before it, like the output:
+///
+/// ```
+/// # use proto::{backend::NullBackend, builder::PatchBuilder, model_utils::*, patch, Engine};
+/// # fn main() -> Result<(), proto::builder::BuildError> {
+/// # let (mut engine, _) = Engine::with_backend(Box::new(NullBackend::new(48000, 1, 64)));
+/// let mut patch = PatchBuilder::new(&mut engine);
+/// let out = patch.output();
+/// patch!(patch {
+///     amp = ConstantAmplifier::new(0.5);
+///     mix = DuoSignalMixer::new(1., 1.);
+///     amp.Output -> mix.Input1;
+///     mix.Output -> out.Audio;
+///     mix.Input1Gain = 0.8;
+/// });
+/// patch.commit()?;
+/// engine.set_parameter(amp.id(), "Gain", 0.25);
+/// # assert_eq!(engine.upstream(mix.id()), Some(vec![amp.id()]));
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Components are labelled with their names. Misspelled ports and parameters return a `BuildError` with
+/// `?`, so the macro has to be used in a function returning a compatible `Result`.
+#[macro_export]
+macro_rules! patch {
+    ($builder:ident { $($body:tt)* }) => {
+        $crate::patch!(@statements $builder; $($body)*)
+    };
+    (@statements $builder:ident;) => {};
+    (@statements $builder:ident;
+        $from:ident . $output:ident -> $to:ident . $input:ident; $($rest:tt)*
+    ) => {
+        $builder.connect(
+            $from.output(stringify!($output))?,
+            $to.input(stringify!($input))?,
+        )?;
+        $crate::patch!(@statements $builder; $($rest)*);
+    };
+    (@statements $builder:ident;
+        $component:ident . $parameter:ident = $value:expr; $($rest:tt)*
+    ) => {
+        $builder.set_parameter(&$component, stringify!($parameter), $value)?;
+        $crate::patch!(@statements $builder; $($rest)*);
+    };
+    (@statements $builder:ident; $name:ident = $model:expr; $($rest:tt)*) => {
+        let $name = $builder.add($model).handle();
+        $builder.label(&$name, stringify!($name));
+        $crate::patch!(@statements $builder; $($rest)*);
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backend::NullBackend,
+        model_utils::{ConstantAmplifier, DuoSignalMixer},
+        ports::{Jack, Process},
+        Config,
+    };
+
+    //A model with nothing but a MIDI output
+    #[derive(Model)]
+    struct Keys {
+        #[output(midi)]
+        midi: Jack,
+    }
+
+    impl Process for Keys {
+        fn process(&mut self, _ports: KeysPorts, _config: &Config) {}
+    }
+
+    fn engine() -> Engine {
+        Engine::with_backend(Box::new(NullBackend::new(48000, 1, 64))).0
+    }
+
+    #[test]
+    fn outputs_are_connected_as_components_are_added() {
+        let mut engine = engine();
+        let build = |engine: &mut Engine| -> Result<(Handle, Handle), BuildError> {
+            let mut patch = PatchBuilder::new(engine);
+            let out = patch.output();
+            let mix = patch
+                .add(DuoSignalMixer::new(1., 1.))
+                .out("Output")?
+                .to(out.input("Audio")?)?
+                .handle();
+            let amp = patch
+                .add(ConstantAmplifier::new(0.5))
+                .out("Output")?
+                .to(mix.input("Input1")?)?
+                .handle();
+            patch.commit()?;
+            Ok((amp, mix))
+        };
+        let (amp, mix) = build(&mut engine).unwrap();
+        assert_eq!(engine.upstream(engine.output_id()).unwrap(), vec![mix.id()]);
+        assert_eq!(engine.upstream(mix.id()).unwrap(), vec![amp.id()]);
+        assert_eq!(engine.parameters(amp.id()).unwrap()["Gain"], 0.5);
+    }
+
+    #[test]
+    fn ports_are_checked_when_they_are_named() {
+        let mut engine = engine();
+        let mut patch = PatchBuilder::new(&mut engine);
+        let amp = patch.add(ConstantAmplifier::new(1.)).handle();
+        match amp.input("Inptu") {
+            Err(BuildError::UnknownInput {
+                component,
+                suggestion,
+                ..
+            }) => {
+                assert_eq!(component, format!("ConstantAmplifier #{}", amp.id()));
+                assert_eq!(suggestion.as_deref(), Some("Input"));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        //Inputs aren't outputs, and the other way round
+        assert!(matches!(
+            amp.output("Input"),
+            Err(BuildError::UnknownOutput { .. })
+        ));
+        assert!(matches!(
+            amp.input("Output"),
+            Err(BuildError::UnknownInput { .. })
+        ));
+        assert!(matches!(
+            patch.add(ConstantAmplifier::new(1.)).out("Input"),
+            Err(BuildError::UnknownOutput { .. })
+        ));
+        match patch.set_parameter(&amp, "Gian", 2.) {
+            Err(BuildError::UnknownParameter { suggestion, .. }) => {
+                assert_eq!(suggestion.as_deref(), Some("Gain"))
+            }
+            Err(error) => panic!("unexpected {:?}", error),
+            Ok(_) => panic!("set a parameter that doesn't exist"),
+        }
+    }
+
+    #[test]
+    fn midi_outputs_cannot_feed_voltage_inputs() {
+        let mut engine = engine();
+        let mut patch = PatchBuilder::new(&mut engine);
+        let out = patch.output();
+        let keys = patch.add(Keys { midi: Jack }).handle();
+        let from = keys.output("Midi").unwrap();
+        assert_eq!(from.port().io, IOType::Midi);
+        assert!(matches!(
+            patch.connect(from, out.input("Audio").unwrap()),
+            Err(BuildError::Connection(
+                ConnectionError::MismatchedConnectionTypes
+            ))
+        ));
+        assert!(matches!(
+            patch
+                .add(Keys { midi: Jack })
+                .out("Midi")
+                .and_then(|cable| cable.to(out.input("Audio")?)),
+            Err(BuildError::Connection(
+                ConnectionError::MismatchedConnectionTypes
+            ))
+        ));
+    }
+
+    #[test]
+    fn handles_outlive_the_patch() {
+        let mut engine = engine();
+        let mut patch = PatchBuilder::new(&mut engine);
+        let amp = patch.add(ConstantAmplifier::new(1.)).handle();
+        patch.label(&amp, "amp");
+        patch.commit().unwrap();
+        assert_eq!(
+            engine.component_info(amp.id()).unwrap().label.as_deref(),
+            Some("amp")
+        );
+
+        let mut patch = PatchBuilder::new(&mut engine);
+        let found = patch.handle(amp.id()).unwrap();
+        assert_eq!(found.input("Input").unwrap(), amp.input("Input").unwrap());
+        assert!(patch.handle(1000).is_none());
+        patch.remove(&amp);
+        patch.commit().unwrap();
+        assert!(!engine.components().contains(&amp.id()));
+    }
+
+    #[test]
+    fn removing_a_component_drops_its_parameters() {
+        let mut engine = engine();
+        let mut patch = PatchBuilder::new(&mut engine);
+        let amp = patch.add(ConstantAmplifier::new(1.)).handle();
+        patch.commit().unwrap();
+
+        let mut patch = PatchBuilder::new(&mut engine);
+        patch.set_parameter(&amp, "Gain", 2.).unwrap();
+        patch.remove(&amp);
+        patch.commit().unwrap();
+        assert!(!engine.components().contains(&amp.id()));
+        assert!(engine.undo().unwrap());
+        assert_eq!(engine.parameters(amp.id()).unwrap()["Gain"], 1.);
+    }
+
+    #[test]
+    fn patch_macro_adds_labels_connects_and_sets() {
+        let mut engine = engine();
+        let build = |engine: &mut Engine| -> Result<(Handle, Handle), BuildError> {
+            let mut patch = PatchBuilder::new(engine);
+            let out = patch.output();
+            patch!(patch {
+                amp = ConstantAmplifier::new(0.5);
+                mix = DuoSignalMixer::new(1., 1.);
+                amp.Output -> mix.Input1;
+                mix.Output -> out.Audio;
+                mix.Input1Gain = 0.8;
+            });
+            patch.commit()?;
+            Ok((amp, mix))
+        };
+        let (amp, mix) = build(&mut engine).unwrap();
+        assert_eq!(
+            engine.component_info(amp.id()).unwrap().label.as_deref(),
+            Some("amp")
+        );
+        assert_eq!(engine.upstream(mix.id()).unwrap(), vec![amp.id()]);
+        assert_eq!(engine.upstream(engine.output_id()).unwrap(), vec![mix.id()]);
+        assert_eq!(engine.parameters(mix.id()).unwrap()["Input1Gain"], 0.8);
+    }
+
+    #[test]
+    fn patch_macro_stops_at_a_misspelled_port() {
+        let mut engine = engine();
+        let build = |engine: &mut Engine| -> Result<(), BuildError> {
+            let mut patch = PatchBuilder::new(engine);
+            let out = patch.output();
+            patch!(patch {
+                amp = ConstantAmplifier::new(0.5);
+                amp.Outptu -> out.Audio;
+            });
+            patch.commit()?;
+            Ok(())
+        };
+        match build(&mut engine) {
+            Err(BuildError::UnknownOutput { suggestion, .. }) => {
+                assert_eq!(suggestion.as_deref(), Some("Output"))
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(engine.components(), vec![engine.output_id()]);
+    }
+}
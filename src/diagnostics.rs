@@ -0,0 +1,266 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use parking_lot::Mutex;
+
+//Callback durations are sorted into buckets by powers of two microseconds, the last bucket
+//catches everything longer
+const HISTOGRAM_BUCKETS: usize = 16;
+
+/// Counters the audio thread writes with atomics only and the control thread reads.
+pub(crate) struct Diagnostics {
+    callbacks: AtomicU64,
+    late_callbacks: AtomicU64,
+    model_xruns: AtomicU64,
+    faults: AtomicU64,
+    histogram: [AtomicU64; HISTOGRAM_BUCKETS],
+    busy_nanos: AtomicU64,
+    budget_nanos: AtomicU64,
+    //f32 bits, loads are never negative so comparing the bits compares the values
+    last_load: AtomicU32,
+    peak_load: AtomicU32,
+    //Only touched by the control thread when models are added or removed and when reading stats
+    components: Mutex<HashMap<usize, Arc<ComponentMonitor>>>,
+}
+
+impl Diagnostics {
+    pub fn new() -> Self {
+        Diagnostics {
+            callbacks: AtomicU64::new(0),
+            late_callbacks: AtomicU64::new(0),
+            model_xruns: AtomicU64::new(0),
+            faults: AtomicU64::new(0),
+            histogram: Default::default(),
+            busy_nanos: AtomicU64::new(0),
+            budget_nanos: AtomicU64::new(0),
+            last_load: AtomicU32::new(0),
+            peak_load: AtomicU32::new(0),
+            components: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records a callback that took `elapsed` to render audio lasting `budget`.
+    pub fn record_callback(&self, elapsed: Duration, budget: Duration) {
+        self.callbacks.fetch_add(1, Ordering::Relaxed);
+        if elapsed > budget {
+            self.late_callbacks.fetch_add(1, Ordering::Relaxed);
+        }
+        let micros = elapsed.as_micros() as u64;
+        let bucket = usize::min(
+            (u64::BITS - micros.leading_zeros()) as usize,
+            HISTOGRAM_BUCKETS - 1,
+        );
+        self.histogram[bucket].fetch_add(1, Ordering::Relaxed);
+        self.busy_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+        self.budget_nanos
+            .fetch_add(budget.as_nanos() as u64, Ordering::Relaxed);
+        let load = if budget.is_zero() {
+            0.
+        } else {
+            elapsed.as_secs_f32() / budget.as_secs_f32()
+        };
+        self.last_load.store(load.to_bits(), Ordering::Relaxed);
+        self.peak_load.fetch_max(load.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn report_xrun(&self) {
+        self.model_xruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn report_fault(&self) {
+        self.faults.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_component(&self, id: usize) -> Arc<ComponentMonitor> {
+        let monitor = Arc::new(ComponentMonitor::default());
+        self.components.lock().insert(id, Arc::clone(&monitor));
+        monitor
+    }
+
+    pub fn insert_component(&self, id: usize, monitor: Arc<ComponentMonitor>) {
+        self.components.lock().insert(id, monitor);
+    }
+
+    pub fn remove_component(&self, id: usize) {
+        self.components.lock().remove(&id);
+    }
+
+    /// The fault of every component, `None` for healthy ones
+    pub fn faults(&self) -> HashMap<usize, Option<Fault>> {
+        self.components
+            .lock()
+            .iter()
+            .map(|(id, monitor)| (*id, monitor.fault()))
+            .collect()
+    }
+
+    pub fn stats(&self) -> EngineStats {
+        let busy = self.busy_nanos.load(Ordering::Relaxed);
+        let budget = self.budget_nanos.load(Ordering::Relaxed);
+        EngineStats {
+            callbacks: self.callbacks.load(Ordering::Relaxed),
+            late_callbacks: self.late_callbacks.load(Ordering::Relaxed),
+            model_xruns: self.model_xruns.load(Ordering::Relaxed),
+            faults: self.faults.load(Ordering::Relaxed),
+            callback_histogram: self
+                .histogram
+                .iter()
+                .enumerate()
+                .map(|(i, count)| {
+                    let limit = if i == HISTOGRAM_BUCKETS - 1 {
+                        Duration::MAX
+                    } else {
+                        Duration::from_micros(1 << i)
+                    };
+                    (limit, count.load(Ordering::Relaxed))
+                })
+                .collect(),
+            dsp_load: f32::from_bits(self.last_load.load(Ordering::Relaxed)),
+            peak_dsp_load: f32::from_bits(self.peak_load.load(Ordering::Relaxed)),
+            average_dsp_load: if budget == 0 {
+                0.
+            } else {
+                busy as f32 / budget as f32
+            },
+            components: self
+                .components
+                .lock()
+                .iter()
+                .map(|(id, monitor)| (*id, monitor.stats()))
+                .collect(),
+        }
+    }
+
+    pub fn reset(&self) {
+        self.callbacks.store(0, Ordering::Relaxed);
+        self.late_callbacks.store(0, Ordering::Relaxed);
+        self.model_xruns.store(0, Ordering::Relaxed);
+        self.faults.store(0, Ordering::Relaxed);
+        for bucket in self.histogram.iter() {
+            bucket.store(0, Ordering::Relaxed);
+        }
+        self.busy_nanos.store(0, Ordering::Relaxed);
+        self.budget_nanos.store(0, Ordering::Relaxed);
+        self.last_load.store(0, Ordering::Relaxed);
+        self.peak_load.store(0, Ordering::Relaxed);
+        for monitor in self.components.lock().values() {
+            monitor.reset();
+        }
+    }
+}
+
+/// How long one component spends in `Model::evaluate` and whether it has faulted.
+#[derive(Default)]
+pub(crate) struct ComponentMonitor {
+    evaluations: AtomicU64,
+    total_nanos: AtomicU64,
+    last_nanos: AtomicU64,
+    max_nanos: AtomicU64,
+    //0 while healthy, otherwise the `Fault` as set by `set_fault`
+    fault: AtomicU8,
+}
+
+/// Why a component was taken out of the graph's signal flow.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// `Model::evaluate` panicked
+    Panicked,
+    /// The model output NaN or infinity
+    NonFinite,
+}
+
+impl ComponentMonitor {
+    pub fn fault(&self) -> Option<Fault> {
+        match self.fault.load(Ordering::Acquire) {
+            1 => Some(Fault::Panicked),
+            2 => Some(Fault::NonFinite),
+            _ => None,
+        }
+    }
+
+    pub fn set_fault(&self, fault: Option<Fault>) {
+        let value = match fault {
+            None => 0,
+            Some(Fault::Panicked) => 1,
+            Some(Fault::NonFinite) => 2,
+        };
+        self.fault.store(value, Ordering::Release);
+    }
+
+    pub fn record(&self, elapsed: Duration) {
+        let nanos = elapsed.as_nanos() as u64;
+        self.evaluations.fetch_add(1, Ordering::Relaxed);
+        self.total_nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.last_nanos.store(nanos, Ordering::Relaxed);
+        self.max_nanos.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    fn stats(&self) -> ComponentStats {
+        ComponentStats {
+            evaluations: self.evaluations.load(Ordering::Relaxed),
+            total: Duration::from_nanos(self.total_nanos.load(Ordering::Relaxed)),
+            last: Duration::from_nanos(self.last_nanos.load(Ordering::Relaxed)),
+            max: Duration::from_nanos(self.max_nanos.load(Ordering::Relaxed)),
+            fault: self.fault(),
+        }
+    }
+
+    fn reset(&self) {
+        self.evaluations.store(0, Ordering::Relaxed);
+        self.total_nanos.store(0, Ordering::Relaxed);
+        self.last_nanos.store(0, Ordering::Relaxed);
+        self.max_nanos.store(0, Ordering::Relaxed);
+    }
+}
+
+/// A snapshot of the engine's real-time statistics, see `Engine::stats`.
+#[derive(Clone, Debug)]
+pub struct EngineStats {
+    pub callbacks: u64,
+    /// Callbacks that took longer than the audio they rendered, which the device hears as a dropout
+    pub late_callbacks: u64,
+    /// Under- and overruns reported by models such as `AudioInput` through `Config::report_xrun`
+    pub model_xruns: u64,
+    /// Number of times a component panicked or output NaN or infinity
+    pub faults: u64,
+    /// Number of callbacks that finished within each duration, shortest first
+    pub callback_histogram: Vec<(Duration, u64)>,
+    /// Time spent rendering the last callback relative to the time it had, 1.0 is fully loaded
+    pub dsp_load: f32,
+    pub peak_dsp_load: f32,
+    pub average_dsp_load: f32,
+    pub components: HashMap<usize, ComponentStats>,
+}
+
+impl EngineStats {
+    pub fn xruns(&self) -> u64 {
+        self.late_callbacks + self.model_xruns
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ComponentStats {
+    pub evaluations: u64,
+    pub total: Duration,
+    pub last: Duration,
+    pub max: Duration,
+    /// Faulted components output silence until they are reset with `Engine::reset_component`
+    pub fault: Option<Fault>,
+}
+
+impl ComponentStats {
+    pub fn average(&self) -> Duration {
+        if self.evaluations == 0 {
+            Duration::ZERO
+        } else {
+            Duration::from_nanos((self.total.as_nanos() / self.evaluations as u128) as u64)
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{mpsc::Sender, Arc, Weak},
+    thread,
+    time::Duration,
+};
+
+use parking_lot::Mutex;
+
+use crate::{
+    broadcast,
+    diagnostics::{Diagnostics, Fault},
+    history::Command,
+    Connection,
+};
+
+//How often the watcher thread looks for components that faulted
+const FAULT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A change to the graph, see `Engine::graph_events`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum GraphEvent {
+    ModelAdded(usize),
+    /// Sent after the `ConnectionRemoved` events of the model's connections
+    ModelRemoved(usize),
+    ConnectionAdded(Connection),
+    ConnectionRemoved(Connection),
+    LabelChanged {
+        id: usize,
+        label: Option<String>,
+    },
+    ParameterChanged {
+        id: usize,
+        name: String,
+        value: f32,
+    },
+    /// The component panicked or output NaN or infinity and was taken out of the signal flow
+    ComponentFaulted {
+        id: usize,
+        fault: Fault,
+    },
+    /// A faulted component was reset and is evaluated again
+    FaultCleared(usize),
+}
+
+/// The events for commands that were just applied.
+pub(crate) fn applied(commands: &[Command]) -> Vec<GraphEvent> {
+    let mut events = Vec::new();
+    for command in commands.iter() {
+        match command {
+            Command::AddModel { id, .. } => events.push(GraphEvent::ModelAdded(*id)),
+            Command::RemoveModel {
+                id, connections, ..
+            } => {
+                events.extend(
+                    connections
+                        .iter()
+                        .map(|c| GraphEvent::ConnectionRemoved(c.clone())),
+                );
+                events.push(GraphEvent::ModelRemoved(*id));
+            }
+            Command::AddConnection(c) => events.push(GraphEvent::ConnectionAdded(c.clone())),
+            Command::RemoveConnection(c) => events.push(GraphEvent::ConnectionRemoved(c.clone())),
+            Command::SetLabel { id, new, .. } => events.push(GraphEvent::LabelChanged {
+                id: *id,
+                label: new.clone(),
+            }),
+            Command::SetParameter { id, name, new, .. } => {
+                events.push(GraphEvent::ParameterChanged {
+                    id: *id,
+                    name: name.clone(),
+                    value: *new,
+                })
+            }
+        }
+    }
+    events
+}
+
+/// The events for commands that were just undone.
+pub(crate) fn reverted(commands: &[Command]) -> Vec<GraphEvent> {
+    let mut events = Vec::new();
+    //Connections between two restored models are only reported once, after both exist
+    let mut restored: HashSet<&Connection> = HashSet::new();
+    let mut connections = Vec::new();
+    for command in commands.iter().rev() {
+        match command {
+            Command::AddModel { id, .. } => events.push(GraphEvent::ModelRemoved(*id)),
+            Command::RemoveModel {
+                id, connections: c, ..
+            } => {
+                events.push(GraphEvent::ModelAdded(*id));
+                connections.extend(c.iter().filter(|c| restored.insert(*c)));
+            }
+            Command::AddConnection(c) => events.push(GraphEvent::ConnectionRemoved(c.clone())),
+            Command::RemoveConnection(c) => {
+                if restored.insert(c) {
+                    connections.push(c);
+                }
+            }
+            Command::SetLabel { id, old, .. } => events.push(GraphEvent::LabelChanged {
+                id: *id,
+                label: old.clone(),
+            }),
+            Command::SetParameter { id, name, old, .. } => {
+                events.push(GraphEvent::ParameterChanged {
+                    id: *id,
+                    name: name.clone(),
+                    value: *old,
+                })
+            }
+        }
+    }
+    events.extend(
+        connections
+            .into_iter()
+            .map(|c| GraphEvent::ConnectionAdded(c.clone())),
+    );
+    events
+}
+
+/// Starts a thread that reports components faulting and being reset. It stops once the
+/// subscribers are dropped along with the engine.
+pub(crate) fn watch_faults(
+    diagnostics: Arc<Diagnostics>,
+    subscribers: Weak<Mutex<Vec<Sender<GraphEvent>>>>,
+) {
+    thread::Builder::new()
+        .name(String::from("proto-fault-watcher"))
+        .spawn(move || {
+            let mut known: HashMap<usize, Option<Fault>> = HashMap::new();
+            loop {
+                thread::sleep(FAULT_POLL_INTERVAL);
+                let subscribers = match subscribers.upgrade() {
+                    Some(subscribers) => subscribers,
+                    None => return,
+                };
+                let faults = diagnostics.faults();
+                for (id, fault) in faults.iter() {
+                    let before = known.get(id).copied().flatten();
+                    match fault {
+                        Some(fault) if before != Some(*fault) => broadcast(
+                            &subscribers,
+                            GraphEvent::ComponentFaulted {
+                                id: *id,
+                                fault: *fault,
+                            },
+                        ),
+                        None if before.is_some() => {
+                            broadcast(&subscribers, GraphEvent::FaultCleared(*id))
+                        }
+                        _ => {}
+                    }
+                }
+                known = faults;
+            }
+        })
+        .unwrap();
+}
@@ -0,0 +1,282 @@
+use std::fmt::Write;
+
+use crate::{ComponentInfo, Connection, IOType, Port};
+
+//Edges leaving a faulted component carry silence and are drawn as disabled. Edges going
+//against the evaluation order would only be read a block late, they are drawn as feedback.
+//The engine refuses connections that close a loop so its own patches never have any.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Edge {
+    Normal,
+    Feedback,
+    Disabled,
+}
+
+/// Renders components as a Graphviz digraph, ports become record fields. `order` is the
+/// evaluation order, edges going against it are drawn as feedback.
+pub(crate) fn dot(components: &[ComponentInfo], order: &[usize]) -> String {
+    let mut out = String::from("digraph patch {\n    rankdir=LR;\n    node [shape=record];\n");
+    for component in components.iter() {
+        let inputs = record_ports(&component.inputs, "i");
+        let outputs = record_ports(&component.outputs, "o");
+        let label = escape_record(&title(component));
+        let style = if component.fault.is_some() {
+            ", style=dashed, color=gray"
+        } else {
+            ""
+        };
+        writeln!(
+            out,
+            "    n{} [label=\"{{{{{}}}|{}|{{{}}}}}\"{}];",
+            component.id, inputs, label, outputs, style
+        )
+        .unwrap();
+    }
+    for (connection, edge) in edges(components, order) {
+        let from = port_index(components, &connection.from, false);
+        let to = port_index(components, &connection.to, true);
+        let style = match (edge, &connection.from.io) {
+            (Edge::Disabled, _) => "style=dotted, color=gray",
+            //Feedback edges don't pull their source to the left of the layout
+            (Edge::Feedback, _) => "style=dashed, color=red, constraint=false",
+            (Edge::Normal, IOType::Voltage) => "style=solid",
+            (Edge::Normal, IOType::Midi) => "style=dashed, color=blue",
+        };
+        writeln!(
+            out,
+            "    n{}:o{} -> n{}:i{} [{}];",
+            connection.from.id, from, connection.to.id, to, style
+        )
+        .unwrap();
+    }
+    out.push_str("}\n");
+    out
+}
+
+/// Renders components as a Mermaid flowchart. Voltage edges are solid, MIDI edges thick,
+/// disabled edges dotted and feedback edges red and dashed.
+pub(crate) fn mermaid(components: &[ComponentInfo], order: &[usize]) -> String {
+    let mut out = String::from("flowchart LR\n");
+    for component in components.iter() {
+        let mut label = escape_mermaid(&title(component));
+        let names = |ports: &[Port]| {
+            ports
+                .iter()
+                .map(|p| escape_mermaid(&p.name))
+                .collect::<Vec<String>>()
+                .join(", ")
+        };
+        if !component.inputs.is_empty() {
+            write!(label, "<br/>in: {}", names(&component.inputs)).unwrap();
+        }
+        if !component.outputs.is_empty() {
+            write!(label, "<br/>out: {}", names(&component.outputs)).unwrap();
+        }
+        writeln!(out, "    n{}[\"{}\"]", component.id, label).unwrap();
+    }
+    //Mermaid styles edges by their position in the chart
+    let mut feedback = Vec::new();
+    for (index, (connection, edge)) in edges(components, order).into_iter().enumerate() {
+        let arrow = match (edge, &connection.from.io) {
+            (Edge::Disabled, _) => "-.->",
+            (_, IOType::Voltage) => "-->",
+            (_, IOType::Midi) => "==>",
+        };
+        if edge == Edge::Feedback {
+            feedback.push(index.to_string());
+        }
+        writeln!(
+            out,
+            "    n{} {}|\"{} → {}\"| n{}",
+            connection.from.id,
+            arrow,
+            escape_mermaid(&connection.from.name),
+            escape_mermaid(&connection.to.name),
+            connection.to.id
+        )
+        .unwrap();
+    }
+    if !feedback.is_empty() {
+        writeln!(
+            out,
+            "    linkStyle {} stroke:red,stroke-dasharray:4",
+            feedback.join(",")
+        )
+        .unwrap();
+    }
+    out
+}
+
+//Label or ID of a component, then its type without the module path and whether it faulted
+fn title(component: &ComponentInfo) -> String {
+    let name = match &component.label {
+        Some(label) => label.clone(),
+        None => format!("#{}", component.id),
+    };
+    let mut title = format!("{}\n{}", name, short_type_name(component.type_name));
+    if component.fault.is_some() {
+        title.push_str("\n(faulted)");
+    }
+    title
+}
+
+pub(crate) fn short_type_name(type_name: &str) -> &str {
+    let base = type_name.split('<').next().unwrap_or(type_name);
+    let start = base.rfind("::").map_or(0, |i| i + 2);
+    &type_name[start..]
+}
+
+//Every connection once, ordered by component, along with how it is drawn
+fn edges<'a>(components: &'a [ComponentInfo], order: &[usize]) -> Vec<(&'a Connection, Edge)> {
+    let position = |id: usize| order.iter().position(|o| *o == id);
+    components
+        .iter()
+        .flat_map(|component| {
+            component.out_connections.iter().map(move |c| {
+                let edge = if component.fault.is_some() {
+                    Edge::Disabled
+                } else {
+                    match (position(c.from.id), position(c.to.id)) {
+                        (Some(from), Some(to)) if from < to => Edge::Normal,
+                        _ => Edge::Feedback,
+                    }
+                };
+                (c, edge)
+            })
+        })
+        .collect()
+}
+
+//Ports are referred to by position since their names may contain anything
+fn record_ports(ports: &[Port], prefix: &str) -> String {
+    ports
+        .iter()
+        .enumerate()
+        .map(|(i, p)| format!("<{}{}> {}", prefix, i, escape_record(&p.name)))
+        .collect::<Vec<String>>()
+        .join("|")
+}
+
+fn port_index(components: &[ComponentInfo], port: &Port, input: bool) -> usize {
+    components
+        .iter()
+        .find(|c| c.id == port.id)
+        .and_then(|c| {
+            let ports = if input { &c.inputs } else { &c.outputs };
+            ports.iter().position(|p| p.name == port.name)
+        })
+        .unwrap_or(0)
+}
+
+fn escape_record(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\n' => escaped.push_str("\\n"),
+            '{' | '}' | '|' | '<' | '>' | '"' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn escape_mermaid(text: &str) -> String {
+    text.replace('#', "#35;")
+        .replace('"', "#quot;")
+        .replace('<', "#lt;")
+        .replace('>', "#gt;")
+        .replace('\n', "<br/>")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostics::Fault;
+
+    fn port(id: usize, io: IOType, name: &str) -> Port {
+        Port {
+            id,
+            io,
+            name: name.to_string(),
+        }
+    }
+
+    fn component(id: usize, out_connections: Vec<Connection>) -> ComponentInfo {
+        ComponentInfo {
+            id,
+            type_name: "proto::model_utils::Vca",
+            label: None,
+            fault: None,
+            inputs: vec![port(id, IOType::Voltage, "Input")],
+            outputs: vec![port(id, IOType::Voltage, "Output")],
+            in_connections: Vec::new(),
+            out_connections,
+        }
+    }
+
+    fn cable(from: usize, to: usize) -> Connection {
+        Connection {
+            from: port(from, IOType::Voltage, "Output"),
+            to: port(to, IOType::Voltage, "Input"),
+        }
+    }
+
+    #[test]
+    fn dot_styles_edges() {
+        let mut midi = component(2, Vec::new());
+        midi.outputs = vec![port(2, IOType::Midi, "Midi")];
+        midi.out_connections = vec![Connection {
+            from: port(2, IOType::Midi, "Midi"),
+            to: port(0, IOType::Midi, "Input"),
+        }];
+        let mut faulted = component(3, vec![cable(3, 0)]);
+        faulted.fault = Some(Fault::Panicked);
+        let components = vec![
+            component(0, vec![cable(0, 1)]),
+            component(1, Vec::new()),
+            midi,
+            faulted,
+        ];
+        let dot = dot(&components, &[2, 3, 0, 1]);
+        assert!(dot.contains("n0:o0 -> n1:i0 [style=solid];"));
+        assert!(dot.contains("n2:o0 -> n0:i0 [style=dashed, color=blue];"));
+        assert!(dot.contains("n3:o0 -> n0:i0 [style=dotted, color=gray];"));
+        assert!(dot.contains("n3 [label=\"{{<i0> Input}|#3\\nVca\\n(faulted)|{<o0> Output}}\", style=dashed, color=gray];"));
+    }
+
+    #[test]
+    fn feedback_edges_are_distinct() {
+        let components = vec![
+            component(0, vec![cable(0, 1)]),
+            component(1, vec![cable(1, 0)]),
+        ];
+        let dot = dot(&components, &[0, 1]);
+        assert!(dot.contains("n0:o0 -> n1:i0 [style=solid];"));
+        assert!(dot.contains("n1:o0 -> n0:i0 [style=dashed, color=red, constraint=false];"));
+        let mermaid = mermaid(&components, &[0, 1]);
+        assert!(mermaid.contains("n1 -->|\"Output → Input\"| n0"));
+        assert!(mermaid.ends_with("    linkStyle 1 stroke:red,stroke-dasharray:4\n"));
+    }
+
+    #[test]
+    fn mermaid_without_feedback_has_no_link_styles() {
+        let components = vec![component(0, vec![cable(0, 1)]), component(1, Vec::new())];
+        let mermaid = mermaid(&components, &[0, 1]);
+        assert_eq!(
+            mermaid,
+            "flowchart LR\n    n0[\"#35;0<br/>Vca<br/>in: Input<br/>out: Output\"]\n    \
+             n1[\"#35;1<br/>Vca<br/>in: Input<br/>out: Output\"]\n    \
+             n0 -->|\"Output → Input\"| n1\n"
+        );
+    }
+
+    #[test]
+    fn short_type_names_keep_generics() {
+        assert_eq!(short_type_name("proto::model_utils::Vca"), "Vca");
+        assert_eq!(short_type_name("a::B<c::D>"), "B<c::D>");
+    }
+}
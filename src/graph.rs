@@ -1,80 +1,424 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    mem,
+    sync::{Arc, Weak},
+    thread,
+    time::Duration,
+};
 
-use crate::{Config, Connection, ConnectionError, IOType, Input, ModelHolder, Output};
+use parking_lot::Mutex;
 
-use midi_types::MidiMessage;
+use crate::{
+    diagnostics::{ComponentMonitor, Diagnostics},
+    export,
+    scheduler::{Ramp, Schedule, ScheduledComponent, ScheduledInput, WorkerPool},
+    transaction::{Edit, Transaction, TransactionError},
+    ComponentInfo, Config, Connection, ConnectionError, IOType, ModelHolder, Port,
+};
+
+//How often the engine looks for fades that have finished
+const FADE_POLL_INTERVAL: Duration = Duration::from_millis(50);
 
 pub struct Graph {
     buffer_size: usize,
     components: HashMap<usize, Component>,
     evaluation_order: Vec<usize>,
     next_free_id: usize,
+    renderer: Arc<Mutex<Renderer>>,
+    diagnostics: Arc<Diagnostics>,
+    crossfade: Duration,
+    //Connections that were added recently and are still fading in
+    fade_ins: HashMap<Connection, Arc<Ramp>>,
+    //Connections that were removed but are still fading out
+    fade_outs: Vec<(Connection, Arc<Ramp>)>,
+    //Removed components that keep running until their connections have faded out
+    retired: HashMap<usize, Component>,
 }
 
 impl Graph {
     pub fn new(buffer_size: usize) -> Self {
+        let diagnostics = Arc::new(Diagnostics::new());
+        let renderer = Renderer {
+            buffer_size,
+            split_at_events: false,
+            sample_time: 0,
+            schedule: Arc::new(Schedule::build(Vec::new(), buffer_size)),
+            workers: WorkerPool::new(0),
+            diagnostics: Arc::clone(&diagnostics),
+        };
         Graph {
             buffer_size,
             components: HashMap::new(),
             evaluation_order: Vec::new(),
             next_free_id: 0,
+            renderer: Arc::new(Mutex::new(renderer)),
+            diagnostics,
+            crossfade: Duration::from_millis(10),
+            fade_ins: HashMap::new(),
+            fade_outs: Vec::new(),
+            retired: HashMap::new(),
         }
     }
 
-    pub fn evaluate(&mut self, sample_rate: usize, buffer_size: usize) {
-        let default_voltage = vec![0.; buffer_size];
-        let default_midi: Vec<Option<MidiMessage>> = vec![None; buffer_size];
-        let mut outputs: HashMap<usize, Output> = HashMap::new();
-        let config = Config {
-            buffer_size,
-            delta: 1.0 / sample_rate as f32,
+    /// What the audio thread evaluates. Edits to the graph only lock it to swap in the new
+    /// schedule.
+    pub fn renderer(&self) -> &Arc<Mutex<Renderer>> {
+        &self.renderer
+    }
+
+    pub(crate) fn diagnostics(&self) -> &Arc<Diagnostics> {
+        &self.diagnostics
+    }
+
+    pub fn components(&self) -> Vec<usize> {
+        let mut ids: Vec<usize> = self.components.keys().copied().collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    pub fn component_info(&self, id: usize) -> Option<ComponentInfo> {
+        let component = self.components.get(&id)?;
+        let model = component.model.lock();
+        let ports = |format: HashMap<String, IOType>| {
+            let mut ports: Vec<Port> = format
+                .into_iter()
+                .map(|(name, io)| Port { id, io, name })
+                .collect();
+            ports.sort_by(|a, b| a.name.cmp(&b.name));
+            ports
         };
-        for id in self.evaluation_order.iter() {
-            let component = self.components.get_mut(&id).unwrap();
-            let mut output = component.construct_outputs(buffer_size);
-            let mut input = Input::default();
-            //Get references to all the inputs with a connection
-            for i in component.in_connections.iter() {
-                let output = outputs.get(&i.from.id).unwrap();
-                match i.from.io {
-                    IOType::Voltage => {
-                        let value = output.voltages.get(&i.from.name).unwrap();
-                        input.voltages.insert(i.to.name.clone(), value);
-                    }
-                    IOType::Midi => {
-                        let value = output.midi_events.get(&i.from.name).unwrap();
-                        input.midi_events.insert(i.to.name.clone(), value);
-                    }
-                }
+        let mut in_connections: Vec<Connection> =
+            component.in_connections.iter().cloned().collect();
+        in_connections.sort_by(|a, b| a.to.name.cmp(&b.to.name));
+        let mut out_connections: Vec<Connection> =
+            component.out_connections.iter().cloned().collect();
+        out_connections.sort_by(|a, b| {
+            (a.to.id, &a.to.name, &a.from.name).cmp(&(b.to.id, &b.to.name, &b.from.name))
+        });
+        Some(ComponentInfo {
+            id,
+            type_name: model.type_name(),
+            label: component.label.clone(),
+            fault: component.monitor.fault(),
+            inputs: ports(model.input_format()),
+            outputs: ports(model.output_format()),
+            in_connections,
+            out_connections,
+        })
+    }
+
+    pub fn upstream(&self, id: usize) -> Option<Vec<usize>> {
+        let component = self.components.get(&id)?;
+        let mut ids: Vec<usize> = component.in_connections.iter().map(|c| c.from.id).collect();
+        ids.sort_unstable();
+        ids.dedup();
+        Some(ids)
+    }
+
+    pub fn downstream(&self, id: usize) -> Option<Vec<usize>> {
+        let component = self.components.get(&id)?;
+        let mut ids: Vec<usize> = component.out_connections.iter().map(|c| c.to.id).collect();
+        ids.sort_unstable();
+        ids.dedup();
+        Some(ids)
+    }
+
+    pub fn evaluation_order(&self) -> &[usize] {
+        &self.evaluation_order
+    }
+
+    pub fn to_dot(&self) -> String {
+        export::dot(&self.infos(), &self.evaluation_order)
+    }
+
+    pub fn to_mermaid(&self) -> String {
+        export::mermaid(&self.infos(), &self.evaluation_order)
+    }
+
+    fn infos(&self) -> Vec<ComponentInfo> {
+        self.components()
+            .into_iter()
+            .filter_map(|id| self.component_info(id))
+            .collect()
+    }
+
+    /// Which connections of component `id` would survive swapping its model for `model`, and
+    /// which would be dropped because the new model has no port with that name and type.
+    pub fn reattach(
+        &self,
+        id: usize,
+        model: &ModelHolder,
+    ) -> Option<(Vec<Connection>, Vec<Connection>)> {
+        let component = self.components.get(&id)?;
+        let (input_format, output_format) = {
+            let model = model.lock();
+            (model.input_format(), model.output_format())
+        };
+        let (mut kept, mut dropped) = (Vec::new(), Vec::new());
+        for c in component.in_connections.iter() {
+            match input_format.get(&c.to.name) {
+                Some(io) if *io == c.to.io => kept.push(c.clone()),
+                _ => dropped.push(c.clone()),
             }
-            //Fill in defaults for all the inputs without a connection
-            for i in component.model.lock().input_format() {
-                match i.1 {
-                    IOType::Voltage => {
-                        if !input.voltages.contains_key(&i.0) {
-                            input.voltages.insert(i.0.clone(), &default_voltage);
-                        }
-                    }
-                    IOType::Midi => {
-                        if !input.midi_events.contains_key(&i.0) {
-                            input.midi_events.insert(i.0.clone(), &default_midi);
-                        }
-                    }
+        }
+        for c in component.out_connections.iter() {
+            match output_format.get(&c.from.name) {
+                Some(io) if *io == c.from.io => kept.push(c.clone()),
+                _ => dropped.push(c.clone()),
+            }
+        }
+        Some((kept, dropped))
+    }
+
+    pub fn model(&self, id: usize) -> Option<ModelHolder> {
+        self.components.get(&id).map(|c| c.model.clone())
+    }
+
+    /// Every component's model and label, for working out what a commit changed
+    pub fn models(&self) -> HashMap<usize, (ModelHolder, Option<String>)> {
+        self.components
+            .iter()
+            .map(|(id, c)| (*id, (c.model.clone(), c.label.clone())))
+            .collect()
+    }
+
+    pub fn parameters(&self, id: usize) -> Option<HashMap<String, f32>> {
+        Some(self.components.get(&id)?.model.lock().parameters())
+    }
+
+    /// Sets a parameter of component `id` and returns the value it had, or `None` if there is
+    /// no such component or parameter.
+    pub fn set_parameter(&mut self, id: usize, name: &str, value: f32) -> Option<f32> {
+        let mut model = self.components.get(&id)?.model.lock();
+        let old = *model.parameters().get(name)?;
+        model.set_parameter(name, value).then_some(old)
+    }
+
+    pub fn set_label(&mut self, id: usize, label: Option<String>) -> bool {
+        match self.components.get_mut(&id) {
+            Some(component) => {
+                component.label = label;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Resets the model of component `id` and puts it back into the signal flow if it faulted.
+    pub fn reset_component(&mut self, id: usize) -> bool {
+        match self.components.get(&id) {
+            Some(component) => {
+                component.model.lock().reset();
+                component.monitor.set_fault(None);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Sets how many extra threads help evaluate independent branches of the graph.
+    /// With 0 every component is evaluated on the audio thread.
+    pub fn set_worker_threads(&mut self, threads: usize) {
+        if threads != self.worker_threads() {
+            //Spawn and join the threads without holding up the audio thread
+            let workers = WorkerPool::new(threads);
+            let old = mem::replace(&mut self.renderer.lock().workers, workers);
+            drop(old);
+        }
+    }
+
+    pub fn worker_threads(&self) -> usize {
+        self.renderer.lock().worker_threads()
+    }
+
+    /// Whether blocks are split at the events models announce through `Model::next_event`
+    pub fn set_split_at_events(&mut self, split: bool) {
+        self.renderer.lock().set_split_at_events(split)
+    }
+
+    /// How long added and removed connections take to fade in and out. Zero switches instantly.
+    pub fn set_crossfade(&mut self, crossfade: Duration) {
+        self.crossfade = crossfade;
+    }
+
+    pub fn crossfade(&self) -> Duration {
+        self.crossfade
+    }
+
+    fn contains(&self, id: usize) -> bool {
+        self.components.contains_key(&id) || self.retired.contains_key(&id)
+    }
+
+    //Voltage connections fade, MIDI is switched straight away
+    fn fades(&self, connection: &Connection) -> bool {
+        !self.crossfade.is_zero() && connection.from.io == IOType::Voltage
+    }
+
+    fn start_fade_in(&mut self, connection: &Connection) {
+        if !self.fades(connection) {
+            return;
+        }
+        //Pick up where a fade out of the same connection got to
+        let from = match self.fade_outs.iter().position(|(c, _)| c == connection) {
+            Some(i) => self.fade_outs.remove(i).1.gain(),
+            None => 0.,
+        };
+        let length = self.crossfade.as_secs_f32() * (1. - from);
+        self.fade_ins
+            .insert(connection.clone(), Arc::new(Ramp::new(from, 1., length)));
+    }
+
+    fn start_fade_out(&mut self, connection: Connection) -> bool {
+        if !self.fades(&connection) {
+            return false;
+        }
+        let from = match self.fade_ins.remove(&connection) {
+            Some(ramp) => ramp.gain(),
+            None => 1.,
+        };
+        let length = self.crossfade.as_secs_f32() * from;
+        self.fade_outs
+            .push((connection, Arc::new(Ramp::new(from, 0., length))));
+        true
+    }
+
+    //Forgets fades that have finished along with the components that were waiting for them
+    fn collect_fades(&mut self) {
+        self.fade_outs.retain(|(_, ramp)| !ramp.finished());
+        let fade_outs = &self.fade_outs;
+        self.retired
+            .retain(|id, _| fade_outs.iter().any(|(c, _)| c.from.id == *id));
+        let mut fade_outs = std::mem::take(&mut self.fade_outs);
+        fade_outs.retain(|(c, _)| self.contains(c.from.id) && self.contains(c.to.id));
+        self.fade_outs = fade_outs;
+        let mut fade_ins = std::mem::take(&mut self.fade_ins);
+        fade_ins.retain(|c, ramp| {
+            !ramp.finished() && self.contains(c.from.id) && self.contains(c.to.id)
+        });
+        self.fade_ins = fade_ins;
+    }
+
+    //The evaluation order including the components and connections that are fading out
+    fn fading_order(&self) -> Option<Vec<usize>> {
+        if self.retired.is_empty() && self.fade_outs.is_empty() {
+            return Some(self.evaluation_order.clone());
+        }
+        let mut edges: Vec<(usize, usize)> = self
+            .fade_outs
+            .iter()
+            .map(|(c, _)| (c.from.id, c.to.id))
+            .collect();
+        for component in self.components.values().chain(self.retired.values()) {
+            edges.extend(
+                component
+                    .in_connections
+                    .iter()
+                    .filter(|c| self.contains(c.from.id))
+                    .map(|c| (c.from.id, c.to.id)),
+            );
+        }
+        let mut ready: Vec<usize> = self
+            .components
+            .keys()
+            .chain(self.retired.keys())
+            .filter(|id| !edges.iter().any(|e| e.1 == **id))
+            .copied()
+            .collect();
+        let mut order = Vec::new();
+        while let Some(n) = ready.pop() {
+            order.push(n);
+            let targets: Vec<usize> = edges.iter().filter(|e| e.0 == n).map(|e| e.1).collect();
+            edges.retain(|e| e.0 != n);
+            for m in targets {
+                if !edges.iter().any(|e| e.1 == m) && !ready.contains(&m) {
+                    ready.push(m);
                 }
             }
-            component
-                .model
-                .lock()
-                .evaluate(buffer_size, input, &mut output, &config);
-            outputs.insert(*id, output);
+        }
+        if edges.is_empty() {
+            Some(order)
+        } else {
+            None
+        }
+    }
+
+    /// Rebuilds the schedule without the fades that have finished and the removed components
+    /// that were waiting for them. Does nothing if no fade has finished.
+    pub fn collect_finished_fades(&mut self) {
+        let finished = self.fade_ins.values().any(|r| r.finished())
+            || self.fade_outs.iter().any(|(_, r)| r.finished());
+        if finished {
+            self.update_schedule();
         }
     }
 
+    //Builds a new schedule and swaps it in, the audio thread only waits for the swap
+    fn update_schedule(&mut self) {
+        self.collect_fades();
+        let order = match self.fading_order() {
+            Some(order) => order,
+            None => {
+                //A new connection closed a loop through one that is fading out, cut the fades short
+                self.fade_outs.clear();
+                self.retired.clear();
+                self.evaluation_order.clone()
+            }
+        };
+        let components = order
+            .iter()
+            .map(|id| {
+                let component = self.components.get(id).unwrap_or_else(|| &self.retired[id]);
+                let mut inputs: Vec<ScheduledInput> = component
+                    .in_connections
+                    .iter()
+                    .filter(|c| self.contains(c.from.id))
+                    .map(|c| ScheduledInput {
+                        name: c.to.name.clone(),
+                        from: c.from.id,
+                        from_name: c.from.name.clone(),
+                        io: c.from.io.clone(),
+                        ramp: self.fade_ins.get(c).cloned(),
+                    })
+                    .collect();
+                inputs.extend(self.fade_outs.iter().filter(|(c, _)| c.to.id == *id).map(
+                    |(c, ramp)| ScheduledInput {
+                        name: c.to.name.clone(),
+                        from: c.from.id,
+                        from_name: c.from.name.clone(),
+                        io: c.from.io.clone(),
+                        ramp: Some(Arc::clone(ramp)),
+                    },
+                ));
+                let fading_out = if self.retired.contains_key(id) {
+                    self.fade_outs
+                        .iter()
+                        .filter(|(c, _)| c.from.id == *id)
+                        .map(|(_, ramp)| Arc::clone(ramp))
+                        .collect()
+                } else {
+                    Vec::new()
+                };
+                ScheduledComponent {
+                    id: *id,
+                    model: &component.model,
+                    monitor: &component.monitor,
+                    inputs,
+                    fading_out,
+                }
+            })
+            .collect();
+        let schedule = Arc::new(Schedule::build(components, self.buffer_size));
+        let old = mem::replace(&mut self.renderer.lock().schedule, schedule);
+        drop(old);
+    }
+
     pub fn add_model(&mut self, model: ModelHolder) -> usize {
         self.components.insert(
             self.next_free_id,
             Component {
-                model: model,
+                monitor: self.diagnostics.add_component(self.next_free_id),
+                model,
+                label: None,
                 in_connections: HashSet::new(),
                 out_connections: HashSet::new(),
             },
@@ -82,25 +426,26 @@ impl Graph {
         self.next_free_id += 1;
         //There is no situation in which adding a new unconnected model will cause the topo sort to return an error
         self.evaluation_order = self.sort().unwrap();
+        self.update_schedule();
         self.next_free_id - 1
     }
 
     pub fn connections(&self) -> HashSet<Connection> {
         let mut connections: HashSet<Connection> = HashSet::new();
-        for (_, component) in &self.components {
+        for component in self.components.values() {
             connections.extend(component.out_connections.clone());
         }
-        return connections;
+        connections
     }
 
-    pub fn add_connection(&mut self, new_connection: Connection) -> Result<(), ConnectionError> {
+    fn check_connection(&self, new_connection: &Connection) -> Result<(), ConnectionError> {
         //Check for self connection
         if new_connection.from.id == new_connection.to.id {
             return Result::Err(ConnectionError::LoopingConnection);
         }
         //Get node outputing to and verify that nothing is already connected to it
-        let to = self.components.get_mut(&new_connection.to.id);
-        let to: &mut Component = match to {
+        let to = self.components.get(&new_connection.to.id);
+        let to: &Component = match to {
             Some(c) => c,
             None => return Result::Err(ConnectionError::ControllerNotInGraph),
         };
@@ -121,8 +466,8 @@ impl Graph {
         }
 
         //Run checks on where its coming from
-        let from = self.components.get_mut(&new_connection.from.id);
-        let from: &mut Component = match from {
+        let from = self.components.get(&new_connection.from.id);
+        let from: &Component = match from {
             Some(c) => c,
             None => return Result::Err(ConnectionError::ControllerNotInGraph),
         };
@@ -139,6 +484,14 @@ impl Graph {
                 }
             }
         }
+        if new_connection.from.io != new_connection.to.io {
+            return Err(ConnectionError::MismatchedConnectionTypes);
+        }
+        Ok(())
+    }
+
+    pub fn add_connection(&mut self, new_connection: Connection) -> Result<(), ConnectionError> {
+        self.check_connection(&new_connection)?;
 
         //Add the connections now that the guard statements have been passed
         let from = self.components.get_mut(&new_connection.from.id).unwrap();
@@ -151,11 +504,16 @@ impl Graph {
         let order = self.sort();
         match order {
             Err(e) => {
-                self.remove_connection(new_connection).unwrap();
+                let from = self.components.get_mut(&new_connection.from.id).unwrap();
+                from.out_connections.remove(&new_connection);
+                let to = self.components.get_mut(&new_connection.to.id).unwrap();
+                to.in_connections.remove(&new_connection);
                 Err(e)
             }
             Ok(l) => {
                 self.evaluation_order = l;
+                self.start_fade_in(&new_connection);
+                self.update_schedule();
                 Ok(())
             }
         }
@@ -171,12 +529,17 @@ impl Graph {
             None => return Result::Err(ConnectionError::ControllerNotInGraph),
         };
         from.out_connections.remove(&old_connection);
-        let to = self.components.get_mut(&old_connection.from.id);
+        let to = self.components.get_mut(&old_connection.to.id);
         let to = match to {
             Some(c) => c,
             None => return Result::Err(ConnectionError::ControllerNotInGraph),
         };
-        Ok(to.in_connections.remove(&old_connection))
+        let removed = to.in_connections.remove(&old_connection);
+        if removed {
+            self.start_fade_out(old_connection);
+        }
+        self.update_schedule();
+        Ok(removed)
     }
 
     fn sort(&mut self) -> Result<Vec<usize>, ConnectionError> {
@@ -190,14 +553,13 @@ impl Graph {
         let mut s: Vec<usize> = self
             .components
             .iter()
-            .filter(|x| x.1.in_connections.len() == 0)
-            .map(|x| x.0.clone())
+            .filter(|x| x.1.in_connections.is_empty())
+            .map(|x| *x.0)
             .collect();
         //L ← Empty list that will contain the sorted elements
         let mut l: Vec<usize> = Vec::new();
-        while s.len() > 0 {
-            //remove a node n from S
-            let n = s.pop().unwrap();
+        //remove a node n from S
+        while let Some(n) = s.pop() {
             //add n to L
             l.push(n);
             //for each node m with an edge e from n to m do
@@ -220,6 +582,161 @@ impl Graph {
         Ok(l)
     }
 
+    /// ID the next added model gets
+    pub fn next_id(&self) -> usize {
+        self.next_free_id
+    }
+
+    /// Applies every edit of the transaction and sorts the graph once, or changes nothing if
+    /// any of them fails.
+    pub fn commit(&mut self, transaction: Transaction) -> Result<(), TransactionError> {
+        if transaction.base != self.next_free_id {
+            return Err(TransactionError::Stale);
+        }
+        let backup = (self.components.clone(), self.next_free_id);
+        let before = self.connections();
+        let mut parameters = Vec::new();
+        let result = self
+            .apply(transaction.edits, &mut parameters)
+            .and_then(|_| self.sort().map_err(|_| TransactionError::LoopingConnection));
+        let order = match result {
+            Ok(order) => order,
+            Err(e) => {
+                (self.components, self.next_free_id) = backup;
+                return Err(e);
+            }
+        };
+        //Models can't be rolled back so parameters are only set once nothing else can fail
+        for (id, name, value) in parameters {
+            if let Some(component) = self.components.get(&id) {
+                component.model.lock().set_parameter(&name, value);
+            }
+        }
+        //Everything checked out, let diagnostics and the fades know what changed
+        let (old_components, _) = backup;
+        //Components that kept their ID but got a different model
+        let mut replaced: HashSet<usize> = HashSet::new();
+        for (id, component) in self.components.iter() {
+            match old_components.get(id) {
+                None => {
+                    self.diagnostics
+                        .insert_component(*id, Arc::clone(&component.monitor));
+                    //A model put back while it was still fading out takes over from its old self
+                    self.retired.remove(id);
+                }
+                Some(old) if !Arc::ptr_eq(&old.model, &component.model) => {
+                    self.diagnostics
+                        .insert_component(*id, Arc::clone(&component.monitor));
+                    replaced.insert(*id);
+                }
+                Some(_) => {}
+            }
+        }
+        //The old model is gone so nothing can keep reading its outputs while they fade
+        self.fade_outs
+            .retain(|(c, _)| !replaced.contains(&c.from.id) && !replaced.contains(&c.to.id));
+        let after = self.connections();
+        let mut fading: HashSet<usize> = HashSet::new();
+        for connection in before.difference(&after) {
+            if replaced.contains(&connection.from.id) || replaced.contains(&connection.to.id) {
+                continue;
+            }
+            if self.start_fade_out(connection.clone()) {
+                fading.insert(connection.from.id);
+            }
+        }
+        for connection in after.difference(&before) {
+            self.start_fade_in(connection);
+        }
+        for (id, component) in old_components {
+            if !self.components.contains_key(&id) {
+                self.diagnostics.remove_component(id);
+                if fading.contains(&id) {
+                    self.retired.insert(id, component);
+                }
+            }
+        }
+        self.evaluation_order = order;
+        self.update_schedule();
+        Ok(())
+    }
+
+    //Makes the structural changes of a transaction without touching the schedule
+    fn apply(
+        &mut self,
+        edits: Vec<Edit>,
+        parameters: &mut Vec<(usize, String, f32)>,
+    ) -> Result<(), TransactionError> {
+        for (index, edit) in edits.into_iter().enumerate() {
+            let failed = |error| TransactionError::Edit { index, error };
+            match edit {
+                Edit::AddModel(model) => {
+                    self.components
+                        .insert(self.next_free_id, Component::new(model));
+                    self.next_free_id += 1;
+                }
+                Edit::InsertModel(id, model) => {
+                    if self.components.contains_key(&id) {
+                        return Err(TransactionError::Stale);
+                    }
+                    self.components.insert(id, Component::new(model));
+                    self.next_free_id = usize::max(self.next_free_id, id + 1);
+                }
+                Edit::SetLabel(id, label) => match self.components.get_mut(&id) {
+                    Some(component) => component.label = label,
+                    None => return Err(failed(ConnectionError::ControllerNotInGraph)),
+                },
+                Edit::SetParameter(id, name, value) => {
+                    let component = self
+                        .components
+                        .get(&id)
+                        .ok_or(failed(ConnectionError::ControllerNotInGraph))?;
+                    if !component.model.lock().parameters().contains_key(&name) {
+                        return Err(TransactionError::UnknownParameter { index, name });
+                    }
+                    parameters.push((id, name, value));
+                }
+                Edit::RemoveModel(id) => {
+                    let component = self
+                        .components
+                        .remove(&id)
+                        .ok_or(failed(ConnectionError::ControllerNotInGraph))?;
+                    //Parameters set before the model went would land on nothing
+                    parameters.retain(|(p, _, _)| *p != id);
+                    for c in component.in_connections {
+                        if let Some(from) = self.components.get_mut(&c.from.id) {
+                            from.out_connections.remove(&c);
+                        }
+                    }
+                    for c in component.out_connections {
+                        if let Some(to) = self.components.get_mut(&c.to.id) {
+                            to.in_connections.remove(&c);
+                        }
+                    }
+                }
+                Edit::AddConnection(connection) => {
+                    self.check_connection(&connection).map_err(failed)?;
+                    let from = self.components.get_mut(&connection.from.id).unwrap();
+                    from.out_connections.insert(connection.clone());
+                    let to = self.components.get_mut(&connection.to.id).unwrap();
+                    to.in_connections.insert(connection);
+                }
+                Edit::RemoveConnection(connection) => {
+                    if !self.components.contains_key(&connection.from.id)
+                        || !self.components.contains_key(&connection.to.id)
+                    {
+                        return Err(failed(ConnectionError::ControllerNotInGraph));
+                    }
+                    let from = self.components.get_mut(&connection.from.id).unwrap();
+                    from.out_connections.remove(&connection);
+                    let to = self.components.get_mut(&connection.to.id).unwrap();
+                    to.in_connections.remove(&connection);
+                }
+            }
+        }
+        Ok(())
+    }
+
     pub fn remove_model(&mut self, id: usize) -> bool {
         let c = match self.components.get(&id) {
             Some(c) => c,
@@ -227,7 +744,8 @@ impl Graph {
         };
         let in_connections = c.in_connections.clone().into_iter();
         let out_connections = c.out_connections.clone().into_iter();
-        self.components.remove(&id);
+        let component = self.components.remove(&id).unwrap();
+        self.diagnostics.remove_component(id);
         for i in in_connections {
             self.components
                 .get_mut(&i.from.id)
@@ -235,42 +753,225 @@ impl Graph {
                 .out_connections
                 .remove(&i);
         }
+        let mut fading = false;
         for i in out_connections {
             self.components
                 .get_mut(&i.to.id)
                 .unwrap()
                 .in_connections
                 .remove(&i);
+            fading |= self.start_fade_out(i);
+        }
+        //Keep the model running until the components it was feeding have faded it out
+        if fading {
+            self.retired.insert(id, component);
         }
+        //Removing a model can't create a loop
+        self.evaluation_order = self.sort().unwrap();
+        self.update_schedule();
         true
     }
 }
 
+/// The part of the graph the audio thread needs, behind its own lock so the schedule can be
+/// rebuilt while the audio thread keeps evaluating the old one.
+pub struct Renderer {
+    //Largest block the graph is evaluated in, callbacks longer than this get split up
+    buffer_size: usize,
+    split_at_events: bool,
+    sample_time: u64,
+    schedule: Arc<Schedule>,
+    workers: WorkerPool,
+    diagnostics: Arc<Diagnostics>,
+}
+
+impl Renderer {
+    /// Evaluates `frames` samples, however many the device asked for.
+    ///
+    /// The frames are split into blocks no longer than the graph's buffer size and, if
+    /// enabled, at the next event a model announces so every event starts a new block.
+    /// `block` is called after each block with its offset and length.
+    pub fn process(
+        &mut self,
+        sample_rate: usize,
+        frames: usize,
+        mut block: impl FnMut(&Renderer, usize, usize),
+    ) {
+        let mut offset = 0;
+        while offset < frames {
+            let mut length = usize::min(frames - offset, self.buffer_size);
+            if self.split_at_events {
+                if let Some(event) = self.schedule.next_event(self.sample_time, length) {
+                    length = event;
+                }
+            }
+            self.evaluate(sample_rate, length);
+            block(self, offset, length);
+            offset += length;
+        }
+    }
+
+    /// Evaluates a single block. `buffer_size` must not be larger than the graph's buffer size.
+    pub fn evaluate(&mut self, sample_rate: usize, buffer_size: usize) {
+        let config = Config {
+            buffer_size,
+            delta: 1.0 / sample_rate as f32,
+            time: self.sample_time,
+            diagnostics: Arc::clone(&self.diagnostics),
+        };
+        self.workers.run(&self.schedule, &config);
+        self.sample_time += buffer_size as u64;
+    }
+
+    /// Number of samples evaluated since the graph was created
+    pub fn sample_time(&self) -> u64 {
+        self.sample_time
+    }
+
+    /// Whether blocks are split at the events models announce through `Model::next_event`
+    pub fn set_split_at_events(&mut self, split: bool) {
+        self.split_at_events = split;
+    }
+
+    /// The voltages fed into input `name` of component `id` during the last call to `evaluate`.
+    /// Returns `None` when the input has no connection.
+    pub fn input_buffer(&self, id: usize, name: &str) -> Option<&Vec<f32>> {
+        self.schedule.input(id, name)
+    }
+
+    pub fn worker_threads(&self) -> usize {
+        self.workers.threads()
+    }
+}
+
+/// Collects finished fades every `FADE_POLL_INTERVAL` until the graph is dropped, so removed
+/// components and finished ramps don't stay in the schedule until the next edit.
+pub(crate) fn collect_fades_periodically(graph: Weak<Mutex<Graph>>) {
+    thread::Builder::new()
+        .name(String::from("proto-fade-collector"))
+        .spawn(move || loop {
+            thread::sleep(FADE_POLL_INTERVAL);
+            match graph.upgrade() {
+                Some(graph) => graph.lock().collect_finished_fades(),
+                None => return,
+            }
+        })
+        .unwrap();
+}
+
+#[derive(Clone)]
 struct Component {
     pub model: ModelHolder,
+    label: Option<String>,
+    monitor: Arc<ComponentMonitor>,
     in_connections: HashSet<Connection>,
     out_connections: HashSet<Connection>,
 }
 
 impl Component {
-    fn construct_outputs(&self, buffer_size: usize) -> Output {
-        let request = self.model.lock().output_format();
+    //Diagnostics only learn about the monitor once the component is committed
+    fn new(model: ModelHolder) -> Self {
+        Component {
+            monitor: Arc::new(ComponentMonitor::default()),
+            model,
+            label: None,
+            in_connections: HashSet::new(),
+            out_connections: HashSet::new(),
+        }
+    }
+}
 
-        let mut new_outputs = Output::default();
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
 
-        for (name, io_type) in request {
-            match io_type {
-                IOType::Voltage => {
-                    new_outputs.voltages.insert(name, vec![0.; buffer_size]);
-                }
-                IOType::Midi => {
-                    new_outputs
-                        .midi_events
-                        .insert(name, vec![None; buffer_size]);
-                }
-            }
+    use super::*;
+    use crate::{
+        backend::NullBackend,
+        builder::IntoHolder,
+        model_utils::{ConstantAmplifier, DuoSignalMixer},
+        Engine,
+    };
+
+    fn port(id: usize, name: &str) -> Port {
+        Port {
+            id,
+            io: IOType::Voltage,
+            name: name.to_string(),
+        }
+    }
+
+    fn connection(from: usize, to: usize, input: &str) -> Connection {
+        Connection {
+            from: port(from, "Output"),
+            to: port(to, input),
+        }
+    }
+
+    fn render(graph: &Graph, blocks: usize) {
+        let mut renderer = graph.renderer().lock();
+        for _ in 0..blocks {
+            renderer.evaluate(48000, 64);
         }
+    }
+
+    #[test]
+    fn finished_fade_outs_leave_the_schedule() {
+        let mut graph = Graph::new(64);
+        let amp = graph.add_model(ConstantAmplifier::new(1.).into_holder());
+        let mixer = graph.add_model(DuoSignalMixer::new(1., 1.).into_holder());
+        graph
+            .add_connection(connection(amp, mixer, "Input1"))
+            .unwrap();
+        graph.remove_model(amp);
+        assert!(graph.retired.contains_key(&amp));
+        //The crossfade is 10ms, 480 samples
+        render(&graph, 10);
+        assert!(graph
+            .renderer()
+            .lock()
+            .input_buffer(mixer, "Input1")
+            .is_some());
+        graph.collect_finished_fades();
+        assert!(graph.retired.is_empty());
+        assert!(graph.fade_outs.is_empty());
+        assert!(graph
+            .renderer()
+            .lock()
+            .input_buffer(mixer, "Input1")
+            .is_none());
+    }
 
-        new_outputs
+    #[test]
+    fn unfinished_fades_are_kept() {
+        let mut graph = Graph::new(64);
+        let amp = graph.add_model(ConstantAmplifier::new(1.).into_holder());
+        let mixer = graph.add_model(DuoSignalMixer::new(1., 1.).into_holder());
+        let cable = connection(amp, mixer, "Input1");
+        graph.add_connection(cable.clone()).unwrap();
+        render(&graph, 2);
+        graph.collect_finished_fades();
+        assert!(graph.fade_ins.contains_key(&cable));
+        render(&graph, 8);
+        graph.collect_finished_fades();
+        assert!(graph.fade_ins.is_empty());
+    }
+
+    #[test]
+    fn engine_collects_fades_on_its_own() {
+        let (mut engine, _) = Engine::with_backend(Box::new(NullBackend::new(48000, 1, 64)));
+        let amp = engine.add_model(ConstantAmplifier::new(1.).into_holder());
+        let mixer = engine.add_model(DuoSignalMixer::new(1., 1.).into_holder());
+        engine
+            .add_connection(connection(amp, mixer, "Input1"))
+            .unwrap();
+        engine.start().unwrap();
+        engine.remove_model(amp);
+        let start = Instant::now();
+        while engine.probe(mixer, "Input1").is_some() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+        engine.stop();
     }
 }
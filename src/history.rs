@@ -0,0 +1,452 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
+};
+
+use parking_lot::Mutex;
+
+use crate::{
+    broadcast,
+    transaction::{Edit, Transaction},
+    Connection, ModelHolder,
+};
+
+/// Something that happened to the undo history, see `Engine::history_events`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HistoryEvent {
+    /// A new edit or group of edits was recorded, which clears everything that could be redone
+    Recorded,
+    Undone,
+    Redone,
+    Cleared,
+}
+
+/// One reversible graph edit
+#[derive(Clone)]
+pub(crate) enum Command {
+    AddModel {
+        id: usize,
+        model: ModelHolder,
+        label: Option<String>,
+    },
+    RemoveModel {
+        id: usize,
+        model: ModelHolder,
+        label: Option<String>,
+        //Every connection to and from the model when it was removed
+        connections: Vec<Connection>,
+    },
+    AddConnection(Connection),
+    RemoveConnection(Connection),
+    SetLabel {
+        id: usize,
+        old: Option<String>,
+        new: Option<String>,
+    },
+    SetParameter {
+        id: usize,
+        name: String,
+        old: f32,
+        new: f32,
+    },
+}
+
+/// Bounded undo and redo stacks of command groups.
+pub(crate) struct History {
+    undo: VecDeque<Vec<Command>>,
+    redo: Vec<Vec<Command>>,
+    limit: usize,
+    //Commands recorded since `begin_group`, and how many groups are open
+    group: Vec<Command>,
+    depth: usize,
+    subscribers: Mutex<Vec<Sender<HistoryEvent>>>,
+}
+
+impl History {
+    pub fn new(limit: usize) -> Self {
+        History {
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            limit,
+            group: Vec::new(),
+            depth: 0,
+            subscribers: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn subscribe(&self) -> Receiver<HistoryEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.lock().push(sender);
+        receiver
+    }
+
+    pub fn record_all(&mut self, commands: Vec<Command>) {
+        self.group.extend(commands);
+        if self.depth == 0 {
+            self.push_group();
+        }
+    }
+
+    pub fn begin_group(&mut self) {
+        self.depth += 1;
+    }
+
+    pub fn end_group(&mut self) {
+        self.depth = self.depth.saturating_sub(1);
+        if self.depth == 0 {
+            self.push_group();
+        }
+    }
+
+    //Closes groups left open, undoing in the middle of a group undoes what it has so far
+    fn close_groups(&mut self) {
+        if self.depth > 0 {
+            self.depth = 0;
+            self.push_group();
+        }
+    }
+
+    fn push_group(&mut self) {
+        if self.group.is_empty() {
+            return;
+        }
+        self.undo.push_back(std::mem::take(&mut self.group));
+        while self.undo.len() > self.limit {
+            self.undo.pop_front();
+        }
+        self.redo.clear();
+        broadcast(&self.subscribers, HistoryEvent::Recorded);
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty() || !self.group.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+        while self.undo.len() > self.limit {
+            self.undo.pop_front();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.group.clear();
+        self.depth = 0;
+        broadcast(&self.subscribers, HistoryEvent::Cleared);
+    }
+
+    /// A transaction reverting the last group, or `None` if there is nothing to undo.
+    /// The group only moves to the redo stack once `undone` confirms the transaction committed.
+    pub fn undo(&mut self, base: usize) -> Option<Transaction> {
+        self.close_groups();
+        self.undo.back().map(|group| inverse(group, base))
+    }
+
+    /// Moves the last group to the redo stack and returns it
+    pub fn undone(&mut self) -> Vec<Command> {
+        match self.undo.pop_back() {
+            Some(group) => {
+                self.redo.push(group.clone());
+                broadcast(&self.subscribers, HistoryEvent::Undone);
+                group
+            }
+            None => Vec::new(),
+        }
+    }
+
+    pub fn redo(&mut self, base: usize) -> Option<Transaction> {
+        self.close_groups();
+        self.redo.last().map(|group| forward(group, base))
+    }
+
+    pub fn redone(&mut self) -> Vec<Command> {
+        match self.redo.pop() {
+            Some(group) => {
+                self.undo.push_back(group.clone());
+                broadcast(&self.subscribers, HistoryEvent::Redone);
+                group
+            }
+            None => Vec::new(),
+        }
+    }
+}
+
+fn forward(group: &[Command], base: usize) -> Transaction {
+    let mut transaction = Transaction::new(base);
+    for command in group.iter() {
+        let edits = &mut transaction.edits;
+        match command {
+            Command::AddModel { id, model, label } => {
+                edits.push(Edit::InsertModel(*id, model.clone()));
+                edits.push(Edit::SetLabel(*id, label.clone()));
+            }
+            Command::RemoveModel { id, .. } => edits.push(Edit::RemoveModel(*id)),
+            Command::AddConnection(c) => edits.push(Edit::AddConnection(c.clone())),
+            Command::RemoveConnection(c) => edits.push(Edit::RemoveConnection(c.clone())),
+            Command::SetLabel { id, new, .. } => edits.push(Edit::SetLabel(*id, new.clone())),
+            Command::SetParameter { id, name, new, .. } => {
+                edits.push(Edit::SetParameter(*id, name.clone(), *new))
+            }
+        }
+    }
+    transaction
+}
+
+//Undoes the commands in reverse order, so every edit finds the graph as it was right after
+//the command it reverts
+fn inverse(group: &[Command], base: usize) -> Transaction {
+    let mut transaction = Transaction::new(base);
+    //How many times each model still comes back further down. Connections between two
+    //removed models are restored with whichever comes back second.
+    let mut removed: HashMap<usize, usize> = HashMap::new();
+    for command in group.iter() {
+        if let Command::RemoveModel { id, .. } = command {
+            *removed.entry(*id).or_default() += 1;
+        }
+    }
+    for command in group.iter().rev() {
+        let edits = &mut transaction.edits;
+        match command {
+            Command::AddModel { id, .. } => edits.push(Edit::RemoveModel(*id)),
+            Command::RemoveModel {
+                id,
+                model,
+                label,
+                connections,
+            } => {
+                edits.push(Edit::InsertModel(*id, model.clone()));
+                edits.push(Edit::SetLabel(*id, label.clone()));
+                if let Some(count) = removed.get_mut(id) {
+                    *count -= 1;
+                }
+                let missing = |id: usize| removed.get(&id).is_some_and(|count| *count > 0);
+                for c in connections.iter() {
+                    if !missing(c.from.id) && !missing(c.to.id) {
+                        edits.push(Edit::AddConnection(c.clone()));
+                    }
+                }
+            }
+            Command::AddConnection(c) => edits.push(Edit::RemoveConnection(c.clone())),
+            Command::RemoveConnection(c) => edits.push(Edit::AddConnection(c.clone())),
+            Command::SetLabel { id, old, .. } => edits.push(Edit::SetLabel(*id, old.clone())),
+            Command::SetParameter { id, name, old, .. } => {
+                edits.push(Edit::SetParameter(*id, name.clone(), *old))
+            }
+        }
+    }
+    transaction
+}
+
+/// The commands that redo what a transaction changed, worked out from the graph before and
+/// after it was committed.
+pub(crate) fn diff(
+    before_models: HashMap<usize, (ModelHolder, Option<String>)>,
+    before_connections: &HashSet<Connection>,
+    after: HashMap<usize, (ModelHolder, Option<String>)>,
+    after_connections: &HashSet<Connection>,
+) -> Vec<Command> {
+    let after_labels: HashMap<usize, Option<String>> = after
+        .iter()
+        .map(|(id, (_, label))| (*id, label.clone()))
+        .collect();
+    let after_models: HashMap<usize, ModelHolder> = after
+        .into_iter()
+        .map(|(id, (model, _))| (id, model))
+        .collect();
+    //A model swapped for another under the same ID counts as removed and added again
+    let changed = |id: &usize, model: &ModelHolder| match after_models.get(id) {
+        Some(after) => !Arc::ptr_eq(after, model),
+        None => true,
+    };
+    let removed: HashSet<usize> = before_models
+        .iter()
+        .filter(|(id, (model, _))| changed(id, model))
+        .map(|(id, _)| *id)
+        .collect();
+    //Connections of removed models come back with the model
+    let mut commands: Vec<Command> = before_connections
+        .difference(after_connections)
+        .filter(|c| !removed.contains(&c.from.id) && !removed.contains(&c.to.id))
+        .map(|c| Command::RemoveConnection(c.clone()))
+        .collect();
+    let mut added: Vec<(usize, ModelHolder, Option<String>)> = after_models
+        .into_iter()
+        .filter(|(id, model)| match before_models.get(id) {
+            Some((before, _)) => !Arc::ptr_eq(before, model),
+            None => true,
+        })
+        .map(|(id, model)| (id, model, after_labels.get(&id).cloned().flatten()))
+        .collect();
+    added.sort_by_key(|(id, _, _)| *id);
+    for (id, (model, label)) in before_models.into_iter() {
+        if removed.contains(&id) {
+            commands.push(Command::RemoveModel {
+                id,
+                model,
+                label,
+                connections: before_connections
+                    .iter()
+                    .filter(|c| c.from.id == id || c.to.id == id)
+                    .cloned()
+                    .collect(),
+            });
+        }
+    }
+    commands.extend(
+        added
+            .into_iter()
+            .map(|(id, model, label)| Command::AddModel { id, model, label }),
+    );
+    //Connections a replaced model kept have to be made again after it is added back
+    commands.extend(
+        after_connections
+            .iter()
+            .filter(|c| {
+                !before_connections.contains(c)
+                    || removed.contains(&c.from.id)
+                    || removed.contains(&c.to.id)
+            })
+            .map(|c| Command::AddConnection(c.clone())),
+    );
+    commands
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        backend::NullBackend,
+        builder::IntoHolder,
+        model_utils::{ConstantAmplifier, DuoSignalMixer, Tone},
+        Connection, Engine, IOType, Port,
+    };
+
+    fn port(id: usize, name: &str) -> Port {
+        Port {
+            id,
+            io: IOType::Voltage,
+            name: name.to_string(),
+        }
+    }
+
+    fn connection(from: usize, to: usize, input: &str) -> Connection {
+        Connection {
+            from: port(from, "Output"),
+            to: port(to, input),
+        }
+    }
+
+    fn engine() -> Engine {
+        Engine::with_backend(Box::new(NullBackend::new(48000, 1, 64))).0
+    }
+
+    #[test]
+    fn undoing_a_connect_and_disconnect_leaves_the_cable_out() {
+        let mut engine = engine();
+        let amp = engine.add_model(ConstantAmplifier::new(1.).into_holder());
+        let mixer = engine.add_model(DuoSignalMixer::new(1., 1.).into_holder());
+        let cable = connection(amp, mixer, "Input1");
+        engine.begin_group();
+        engine.add_connection(cable.clone()).unwrap();
+        engine.remove_connection(cable.clone()).unwrap();
+        engine.end_group();
+        assert!(engine.undo().unwrap());
+        assert!(!engine.connections().contains(&cable));
+        assert!(engine.redo().unwrap());
+        assert!(!engine.connections().contains(&cable));
+    }
+
+    #[test]
+    fn undoing_a_model_connected_and_removed_in_one_group() {
+        let mut engine = engine();
+        let mixer = engine.add_model(DuoSignalMixer::new(1., 1.).into_holder());
+        engine.begin_group();
+        let amp = engine.add_model(ConstantAmplifier::new(1.).into_holder());
+        engine
+            .add_connection(connection(amp, mixer, "Input1"))
+            .unwrap();
+        assert!(engine.remove_model(amp));
+        engine.end_group();
+        assert!(engine.undo().unwrap());
+        assert!(!engine.components().contains(&amp));
+        assert!(engine.connections().is_empty());
+        //The next group back is undone as well instead of failing on the last one again
+        assert!(engine.undo().unwrap());
+        assert!(!engine.components().contains(&mixer));
+    }
+
+    #[test]
+    fn undoing_a_model_added_and_set_in_one_group() {
+        let mut engine = engine();
+        engine.begin_group();
+        let amp = engine.add_model(ConstantAmplifier::new(1.).into_holder());
+        assert!(engine.set_parameter(amp, "Gain", 2.));
+        engine.end_group();
+        assert!(engine.undo().unwrap());
+        assert!(!engine.components().contains(&amp));
+        assert!(engine.redo().unwrap());
+        assert_eq!(engine.parameters(amp).unwrap()["Gain"], 2.);
+    }
+
+    #[test]
+    fn undoing_a_commit_that_removed_two_connected_models() {
+        let mut engine = engine();
+        let amp = engine.add_model(ConstantAmplifier::new(1.).into_holder());
+        let mixer = engine.add_model(DuoSignalMixer::new(1., 1.).into_holder());
+        let cable = connection(amp, mixer, "Input1");
+        engine.add_connection(cable.clone()).unwrap();
+        let mut transaction = engine.transaction();
+        transaction.remove_model(amp);
+        transaction.remove_model(mixer);
+        engine.commit(transaction).unwrap();
+        assert!(engine.undo().unwrap());
+        assert!(engine.components().contains(&amp));
+        assert!(engine.components().contains(&mixer));
+        assert!(engine.connections().contains(&cable));
+    }
+
+    #[test]
+    fn undo_and_redo_round_trip() {
+        let mut engine = engine();
+        let before = engine.components();
+        let tone = engine.add_model(Tone::new(1000.));
+        let mixer = engine.add_model(DuoSignalMixer::new(1., 1.).into_holder());
+        let cable = connection(tone, mixer, "Input2");
+        engine.add_connection(cable.clone()).unwrap();
+        assert!(engine.set_parameter(tone, "Resistance", 2000.));
+        assert!(engine.set_label(tone, Some("tone".to_string())));
+        let after = engine.components();
+
+        for _ in 0..5 {
+            assert!(engine.undo().unwrap());
+        }
+        assert!(!engine.undo().unwrap());
+        assert_eq!(engine.components(), before);
+        assert!(engine.connections().is_empty());
+
+        for _ in 0..5 {
+            assert!(engine.redo().unwrap());
+        }
+        assert!(!engine.redo().unwrap());
+        assert_eq!(engine.components(), after);
+        assert!(engine.connections().contains(&cable));
+        assert_eq!(engine.parameters(tone).unwrap()["Resistance"], 2000.);
+        assert_eq!(
+            engine.component_info(tone).unwrap().label.as_deref(),
+            Some("tone")
+        );
+
+        assert!(engine.undo().unwrap());
+        assert!(engine.undo().unwrap());
+        assert_eq!(engine.parameters(tone).unwrap()["Resistance"], 1000.);
+        assert_eq!(engine.component_info(tone).unwrap().label, None);
+    }
+}
@@ -1,106 +1,343 @@
 pub mod audio_io;
+pub mod backend;
+pub mod builder;
+pub mod diagnostics;
 pub mod model_utils;
+#[cfg(feature = "osc")]
+pub mod osc;
+pub mod patch;
+pub mod ports;
+pub mod registry;
+#[cfg(feature = "repl")]
+pub mod repl;
+pub mod safety;
+pub mod testing;
+#[cfg(feature = "tui")]
+pub mod tui;
 
+//Lets `#[derive(Model)]` refer to this crate as `proto` from inside it too
+extern crate self as proto;
+
+mod events;
+mod export;
 mod graph;
+mod history;
+mod scheduler;
+mod transaction;
 
-use audio_io::AudioOutput;
-use cpal::{
-    traits::{DeviceTrait, HostTrait, StreamTrait},
-    SampleRate, Stream, StreamConfig,
-};
-use graph::Graph;
+use audio_io::DeviceOutput;
+use backend::{AudioBackend, BackendError, RenderCallback, StatusCallback};
+use diagnostics::{Diagnostics, EngineStats, Fault};
+use graph::{Graph, Renderer};
+use history::{Command, History};
 use midi_types::MidiMessage;
 use parking_lot::Mutex;
+use safety::{OutputSafety, SafetyConfig};
 use std::{
     collections::{HashMap, HashSet},
-    ops::DerefMut,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
+#[cfg(feature = "cpal")]
+pub use backend::cpal::{list_devices, InputDevice, OutputDevice};
+pub use events::GraphEvent;
+pub use history::HistoryEvent;
 pub use midi_types;
+pub use proto_derive::Model;
+use transaction::Edit;
+pub use transaction::{Transaction, TransactionError};
 
 type ModelHolder = Arc<Mutex<Box<dyn Model + Send + Sync>>>;
 
 pub struct Engine {
-    pub stream_config: StreamConfig,
     graph: Arc<Mutex<Graph>>,
-    #[allow(dead_code)]
-    output_stream: Stream,
+    //The audio thread only locks this, never the graph
+    renderer: Arc<Mutex<Renderer>>,
+    output_id: usize,
+    backend: Box<dyn AudioBackend>,
+    state: EngineState,
+    paused: Arc<AtomicBool>,
+    subscribers: Arc<Mutex<Vec<Sender<EngineEvent>>>>,
+    diagnostics: Arc<Diagnostics>,
+    safety: Arc<Mutex<OutputSafety>>,
+    history: History,
+    graph_subscribers: Arc<Mutex<Vec<Sender<GraphEvent>>>>,
+    watching_faults: AtomicBool,
 }
 
-pub struct OutputDevice {
-    pub name: String,
-    device: cpal::Device,
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EngineState {
+    Stopped,
+    Running,
+    //The backend keeps running but plays silence and the graph isn't evaluated
+    Paused,
 }
 
-impl OutputDevice {
-    pub fn default() -> Option<Self> {
-        let host = cpal::default_host();
-        let device = host.default_output_device()?;
-        Some(OutputDevice {
-            name: device.name().unwrap(),
-            device,
-        })
-    }
+/// Something that happened to the engine or its stream, see `Engine::status_events`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum EngineEvent {
+    Started,
+    Stopped,
+    Paused,
+    Resumed,
+    DeviceSwitched,
+    StreamError(String),
+    //The device with this name went away, the backend keeps trying to reconnect
+    DeviceLost(String),
+    //The stream is playing again on the device with this name
+    Reconnected(String),
+    //The backend stopped on its own, like a file backend that rendered everything
+    Finished,
 }
 
 impl Engine {
+    /// Creates an engine playing on `output_device` and starts it straight away.
+    ///
+    /// The device is opened with the supported config closest to `buffer_size` and
+    /// `sample_rate`, check `sample_rate` and `buffer_size` for what was picked.
+    #[cfg(feature = "cpal")]
     pub fn new(
         output_device: &OutputDevice,
         buffer_size: usize,
         sample_rate: usize,
-    ) -> (Self, ModelHolder) {
-        let output_device = &output_device.device;
-        let config = cpal::StreamConfig {
-            channels: 1,
-            sample_rate: SampleRate(sample_rate as u32),
-            buffer_size: cpal::BufferSize::Fixed(buffer_size as u32),
-        };
-        let mut graph = Graph::new(buffer_size);
-        let (output, mut consumer) = AudioOutput::new();
-        let output_model: ModelHolder = Arc::new(Mutex::new(Box::new(output)));
-        graph.add_model(output_model.clone());
-        let graph = Arc::new(Mutex::new(graph));
-        let output_reference = Arc::clone(&graph);
-        //The actual code that outputs and runs the graph. This function runs once for every buffer the device requests.
-        let output_data_fn = move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-            let mut input_fell_behind = false;
-            let buffer_size = data.len();
-            println!("output len: {}", data.len());
-            for sample in data {
-                *sample = match consumer.pop() {
-                    Ok(s) => s,
-                    Err(_e) => {
-                        input_fell_behind = true;
-                        0.0
-                    }
-                };
-            }
-            //Evaluate the graph here so its synced with the
-            let mut output_reference = output_reference.lock();
-            let graph = output_reference.deref_mut();
-            graph.evaluate(sample_rate, buffer_size);
+    ) -> Result<(Self, ModelHolder), BackendError> {
+        let backend = backend::cpal::CpalBackend::new(output_device, buffer_size, sample_rate)?;
+        let (mut engine, output_model) = Engine::with_backend(Box::new(backend));
+        engine.start()?;
+        Ok((engine, output_model))
+    }
 
-            if input_fell_behind {
-                eprintln!("input stream fell behind: try increasing latency");
-            }
-        };
-        let output_stream = output_device
-            .build_output_stream(&config, output_data_fn, err_fn, None)
-            .unwrap();
-        output_stream.play().unwrap();
+    /// Creates an engine that is driven by `backend`. Nothing is rendered until `start` is called.
+    pub fn with_backend(backend: Box<dyn AudioBackend>) -> (Self, ModelHolder) {
+        let mut graph = Graph::new(backend.buffer_size());
+        let output_model: ModelHolder = Arc::new(Mutex::new(Box::new(DeviceOutput::new())));
+        let output_id = graph.add_model(output_model.clone());
+        let diagnostics = Arc::clone(graph.diagnostics());
+        let renderer = Arc::clone(graph.renderer());
+        let safety = OutputSafety::new(backend.sample_rate());
+        let graph = Arc::new(Mutex::new(graph));
+        graph::collect_fades_periodically(Arc::downgrade(&graph));
         (
             Engine {
                 graph,
-                stream_config: config,
-                output_stream,
+                renderer,
+                output_id,
+                backend,
+                state: EngineState::Stopped,
+                paused: Arc::new(AtomicBool::new(false)),
+                subscribers: Arc::new(Mutex::new(Vec::new())),
+                diagnostics,
+                safety: Arc::new(Mutex::new(safety)),
+                history: History::new(100),
+                graph_subscribers: Arc::new(Mutex::new(Vec::new())),
+                watching_faults: AtomicBool::new(false),
             },
             output_model,
         )
     }
 
+    /// Starts rendering, or resumes if the engine is paused.
+    pub fn start(&mut self) -> Result<(), BackendError> {
+        match self.state {
+            EngineState::Running => Err(BackendError::AlreadyStarted),
+            EngineState::Paused => {
+                self.resume();
+                Ok(())
+            }
+            EngineState::Stopped => {
+                self.safety
+                    .lock()
+                    .restart(self.backend.sample_rate(), self.backend.channels());
+                let render = self.render_callback();
+                self.backend.start(render, self.status_callback())?;
+                self.state = EngineState::Running;
+                self.broadcast(EngineEvent::Started);
+                Ok(())
+            }
+        }
+    }
+
+    /// Closes the stream. The graph keeps its state and carries on where it left off on `start`.
+    pub fn stop(&mut self) {
+        let _ = self.finish();
+    }
+
+    /// Stops like `stop` and returns the error the backend ran into while it was running,
+    /// e.g. a `FileBackend` that couldn't write the whole file.
+    pub fn finish(&mut self) -> Result<(), BackendError> {
+        if self.state == EngineState::Stopped {
+            return Ok(());
+        }
+        let result = self.backend.finish();
+        self.paused.store(false, Ordering::Release);
+        self.state = EngineState::Stopped;
+        self.broadcast(EngineEvent::Stopped);
+        result
+    }
+
+    /// Outputs silence without evaluating the graph while keeping the stream open.
+    pub fn pause(&mut self) {
+        if self.state == EngineState::Running {
+            self.paused.store(true, Ordering::Release);
+            self.state = EngineState::Paused;
+            self.broadcast(EngineEvent::Paused);
+        }
+    }
+
+    pub fn resume(&mut self) {
+        if self.state == EngineState::Paused {
+            self.safety
+                .lock()
+                .restart(self.backend.sample_rate(), self.backend.channels());
+            self.paused.store(false, Ordering::Release);
+            self.state = EngineState::Running;
+            self.broadcast(EngineEvent::Resumed);
+        }
+    }
+
+    pub fn state(&self) -> EngineState {
+        self.state
+    }
+
+    /// Moves the engine to another backend, keeping the graph as it is. The engine ends up in
+    /// the same state it was in before. If the new backend fails to start the engine is left
+    /// stopped on the new backend.
+    pub fn switch_device(&mut self, backend: Box<dyn AudioBackend>) -> Result<(), BackendError> {
+        let state = self.state;
+        self.stop();
+        self.backend = backend;
+        self.broadcast(EngineEvent::DeviceSwitched);
+        if state != EngineState::Stopped {
+            self.start()?;
+        }
+        if state == EngineState::Paused {
+            self.pause();
+        }
+        Ok(())
+    }
+
+    /// Moves the engine to `output_device`, opened with the config closest to the current one.
+    #[cfg(feature = "cpal")]
+    pub fn switch_output_device(
+        &mut self,
+        output_device: &OutputDevice,
+    ) -> Result<(), BackendError> {
+        let backend =
+            backend::cpal::CpalBackend::new(output_device, self.buffer_size(), self.sample_rate())?;
+        self.switch_device(Box::new(backend))
+    }
+
+    /// Sets the safety stage of every output channel. Pass `SafetyConfig::disabled()` to send
+    /// the graph to the device untouched.
+    pub fn set_output_safety(&mut self, config: SafetyConfig) {
+        self.safety.lock().set_all(config)
+    }
+
+    pub fn set_channel_safety(&mut self, channel: usize, config: SafetyConfig) {
+        self.safety.lock().set_config(channel, config)
+    }
+
+    pub fn channel_safety(&self, channel: usize) -> Option<SafetyConfig> {
+        self.safety.lock().config(channel).cloned()
+    }
+
+    /// Xrun counters, DSP load and how long each component takes to evaluate.
+    /// Reading these never blocks the audio thread.
+    pub fn stats(&self) -> EngineStats {
+        self.diagnostics.stats()
+    }
+
+    pub fn reset_stats(&self) {
+        self.diagnostics.reset()
+    }
+
+    /// A new channel receiving every `EngineEvent` from now on.
+    pub fn status_events(&self) -> Receiver<EngineEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.lock().push(sender);
+        receiver
+    }
+
+    fn broadcast(&self, event: EngineEvent) {
+        broadcast(&self.subscribers, event)
+    }
+
+    /// Sends an event to every `status_events` receiver. Streams the engine doesn't own, like
+    /// the one of an `AudioInput`, report their errors through this.
+    pub fn status_callback(&self) -> StatusCallback {
+        let subscribers = Arc::clone(&self.subscribers);
+        Arc::new(move |event| broadcast(&subscribers, event))
+    }
+
+    /// Whether the backend stopped on its own, e.g. a `FileBackend` that rendered everything
+    pub fn is_finished(&self) -> bool {
+        self.backend.is_finished()
+    }
+
+    pub fn sample_rate(&self) -> usize {
+        self.backend.sample_rate()
+    }
+
+    pub fn buffer_size(&self) -> usize {
+        self.backend.buffer_size()
+    }
+
+    //The actual code that outputs and runs the graph. This function runs once for every buffer the backend requests.
+    fn render_callback(&self) -> RenderCallback {
+        let renderer = Arc::clone(&self.renderer);
+        let output_id = self.output_id;
+        let sample_rate = self.backend.sample_rate();
+        let channels = self.backend.channels();
+        let paused = Arc::clone(&self.paused);
+        let diagnostics = Arc::clone(&self.diagnostics);
+        let safety = Arc::clone(&self.safety);
+        Box::new(move |data: &mut [f32]| {
+            if paused.load(Ordering::Acquire) {
+                data.fill(0.);
+                return;
+            }
+            let start = Instant::now();
+            let frames = data.len() / channels;
+            let mut renderer = renderer.lock();
+            //Render the graph first and copy it straight into the device buffer. Devices don't
+            //always hand out the buffer size that was asked for, so the graph splits it up
+            renderer.process(sample_rate, frames, |renderer, offset, length| {
+                let data = &mut data[offset * channels..(offset + length) * channels];
+                match renderer.input_buffer(output_id, "Audio") {
+                    Some(audio) => {
+                        for (frame, sample) in data.chunks_mut(channels).zip(audio.iter()) {
+                            frame.fill(*sample);
+                        }
+                    }
+                    None => data.fill(0.),
+                }
+            });
+            drop(renderer);
+            //Keep DC, huge values and NaNs away from the speakers
+            safety.lock().process(data, channels);
+            let budget = Duration::from_secs_f64(frames as f64 / sample_rate as f64);
+            diagnostics.record_callback(start.elapsed(), budget);
+        })
+    }
+
+    /// ID of the `DeviceOutput` component that is played on the output device
+    pub fn output_id(&self) -> usize {
+        self.output_id
+    }
+
     pub fn add_model(&mut self, model: ModelHolder) -> usize {
-        self.graph.lock().add_model(model)
+        let id = self.graph.lock().add_model(model.clone());
+        self.record(Command::AddModel {
+            id,
+            model,
+            label: None,
+        });
+        id
     }
 
     pub fn connections(&self) -> HashSet<Connection> {
@@ -108,30 +345,345 @@ impl Engine {
     }
 
     pub fn add_connection(&mut self, new_connection: Connection) -> Result<(), ConnectionError> {
-        self.graph.lock().add_connection(new_connection)
+        self.graph.lock().add_connection(new_connection.clone())?;
+        self.record(Command::AddConnection(new_connection));
+        Ok(())
     }
 
     pub fn remove_connection(
         &mut self,
         old_connection: Connection,
     ) -> Result<bool, ConnectionError> {
-        self.graph.lock().remove_connection(old_connection)
+        let removed = self
+            .graph
+            .lock()
+            .remove_connection(old_connection.clone())?;
+        if removed {
+            self.record(Command::RemoveConnection(old_connection));
+        }
+        Ok(removed)
     }
 
+    /// Starts a batch of edits that is applied with `commit`
+    pub fn transaction(&self) -> Transaction {
+        Transaction::new(self.graph.lock().next_id())
+    }
+
+    /// Applies all edits of a transaction at once. If any of them fails, or together they
+    /// would create a loop, the graph is left exactly as it was.
+    pub fn commit(&mut self, transaction: Transaction) -> Result<(), TransactionError> {
+        let mut graph = self.graph.lock();
+        let before_models = graph.models();
+        let before_connections = graph.connections();
+        graph.commit(transaction)?;
+        let commands = history::diff(
+            before_models,
+            &before_connections,
+            graph.models(),
+            &graph.connections(),
+        );
+        drop(graph);
+        self.record_all(commands);
+        Ok(())
+    }
+
+    /// Removes a model. Its outputs fade out over the crossfade time before it stops being
+    /// evaluated.
     pub fn remove_model(&mut self, id: usize) -> bool {
-        self.graph.lock().remove_model(id)
+        let mut graph = self.graph.lock();
+        let (model, info) = match (graph.model(id), graph.component_info(id)) {
+            (Some(model), Some(info)) => (model, info),
+            _ => return false,
+        };
+        graph.remove_model(id);
+        drop(graph);
+        let mut connections = info.in_connections;
+        connections.extend(info.out_connections);
+        self.record(Command::RemoveModel {
+            id,
+            model,
+            label: info.label,
+            connections,
+        });
+        true
+    }
+
+    /// Swaps the model of component `id` for `model`, keeping its ID and label.
+    ///
+    /// Connections are re-attached wherever the new model has a port with the same name and
+    /// type, the ones that couldn't be are returned. With `transfer_parameters` the new model
+    /// takes over the values of the parameters both models have. The swap happens at once,
+    /// without a crossfade.
+    pub fn replace_model(
+        &mut self,
+        id: usize,
+        model: ModelHolder,
+        transfer_parameters: bool,
+    ) -> Result<Vec<Connection>, ConnectionError> {
+        let (transaction, dropped) = {
+            let graph = self.graph.lock();
+            let (old, info) = match (graph.model(id), graph.component_info(id)) {
+                (Some(old), Some(info)) => (old, info),
+                _ => return Err(ConnectionError::ControllerNotInGraph),
+            };
+            let (kept, dropped) = graph.reattach(id, &model).unwrap();
+            if transfer_parameters && !Arc::ptr_eq(&old, &model) {
+                let parameters = old.lock().parameters();
+                let mut new = model.lock();
+                for (name, value) in parameters {
+                    if new.parameters().contains_key(&name) {
+                        new.set_parameter(&name, value);
+                    }
+                }
+            }
+            let mut transaction = Transaction::new(graph.next_id());
+            transaction.edits.push(Edit::RemoveModel(id));
+            transaction.edits.push(Edit::InsertModel(id, model));
+            transaction.edits.push(Edit::SetLabel(id, info.label));
+            for connection in kept {
+                transaction.edits.push(Edit::AddConnection(connection));
+            }
+            (transaction, dropped)
+        };
+        //The connections that are kept were valid before and can't form a loop now
+        self.commit(transaction).unwrap();
+        Ok(dropped)
+    }
+
+    pub fn parameters(&self, id: usize) -> Option<HashMap<String, f32>> {
+        self.graph.lock().parameters(id)
+    }
+
+    /// Sets a parameter through `Model::set_parameter`. Returns false if there is no component
+    /// with that ID or it has no such parameter.
+    pub fn set_parameter(&mut self, id: usize, name: &str, value: f32) -> bool {
+        let old = self.graph.lock().set_parameter(id, name, value);
+        match old {
+            Some(old) => {
+                self.record(Command::SetParameter {
+                    id,
+                    name: name.to_string(),
+                    old,
+                    new: value,
+                });
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The last sample the input of a component received, `None` if there is no such input
+    pub fn probe(&self, id: usize, input: &str) -> Option<f32> {
+        let renderer = self.renderer.lock();
+        renderer
+            .input_buffer(id, input)
+            .map(|buffer| buffer.last().copied().unwrap_or(0.0))
+    }
+
+    /// The largest absolute sample in the last block an input received, for level meters
+    pub fn peak(&self, id: usize, input: &str) -> Option<f32> {
+        let renderer = self.renderer.lock();
+        renderer
+            .input_buffer(id, input)
+            .map(|buffer| buffer.iter().fold(0.0, |peak: f32, s| peak.max(s.abs())))
+    }
+
+    /// A copy of the last block an input received, for drawing scopes
+    pub fn scope(&self, id: usize, input: &str) -> Option<Vec<f32>> {
+        let renderer = self.renderer.lock();
+        renderer.input_buffer(id, input).cloned()
+    }
+
+    /// Reverts the last recorded edit or group of edits. Returns false if there was nothing
+    /// to undo.
+    pub fn undo(&mut self) -> Result<bool, TransactionError> {
+        let mut graph = self.graph.lock();
+        match self.history.undo(graph.next_id()) {
+            Some(transaction) => {
+                graph.commit(transaction)?;
+                let group = self.history.undone();
+                broadcast_all(&self.graph_subscribers, events::reverted(&group));
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    pub fn redo(&mut self) -> Result<bool, TransactionError> {
+        let mut graph = self.graph.lock();
+        match self.history.redo(graph.next_id()) {
+            Some(transaction) => {
+                graph.commit(transaction)?;
+                let group = self.history.redone();
+                broadcast_all(&self.graph_subscribers, events::applied(&group));
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    pub fn can_undo(&self) -> bool {
+        self.history.can_undo()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        self.history.can_redo()
+    }
+
+    /// Edits made until the matching `end_group` are undone and redone together. Groups nest.
+    pub fn begin_group(&mut self) {
+        self.history.begin_group()
+    }
+
+    pub fn end_group(&mut self) {
+        self.history.end_group()
+    }
+
+    /// How many edits or groups of edits can be undone, 100 by default
+    pub fn set_history_limit(&mut self, limit: usize) {
+        self.history.set_limit(limit)
+    }
+
+    pub fn clear_history(&mut self) {
+        self.history.clear()
+    }
+
+    /// Returns a receiver that gets an event for every change made to the graph, including
+    /// undo and redo, and whenever a component faults or is reset.
+    pub fn graph_events(&self) -> Receiver<GraphEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.graph_subscribers.lock().push(sender);
+        if !self.watching_faults.swap(true, Ordering::AcqRel) {
+            events::watch_faults(
+                Arc::clone(&self.diagnostics),
+                Arc::downgrade(&self.graph_subscribers),
+            );
+        }
+        receiver
+    }
+
+    //Every edit goes through here so it can be undone and observers hear about it
+    fn record(&mut self, command: Command) {
+        self.record_all(vec![command])
+    }
+
+    fn record_all(&mut self, commands: Vec<Command>) {
+        broadcast_all(&self.graph_subscribers, events::applied(&commands));
+        self.history.record_all(commands);
+    }
+
+    /// Returns a receiver that gets an event whenever the undo history changes
+    pub fn history_events(&self) -> Receiver<HistoryEvent> {
+        self.history.subscribe()
+    }
+
+    /// How long connections take to fade in when added and out when removed, 10ms by default.
+    /// Zero switches connections instantly.
+    pub fn set_crossfade(&mut self, crossfade: Duration) {
+        self.graph.lock().set_crossfade(crossfade)
+    }
+
+    pub fn crossfade(&self) -> Duration {
+        self.graph.lock().crossfade()
+    }
+
+    /// IDs of every component in the graph, in ascending order
+    pub fn components(&self) -> Vec<usize> {
+        self.graph.lock().components()
+    }
+
+    pub fn component_info(&self, id: usize) -> Option<ComponentInfo> {
+        self.graph.lock().component_info(id)
+    }
+
+    /// IDs of the components feeding component `id`
+    pub fn upstream(&self, id: usize) -> Option<Vec<usize>> {
+        self.graph.lock().upstream(id)
+    }
+
+    /// IDs of the components component `id` feeds
+    pub fn downstream(&self, id: usize) -> Option<Vec<usize>> {
+        self.graph.lock().downstream(id)
+    }
+
+    /// The order components are evaluated in, every component comes after the ones feeding it
+    pub fn evaluation_order(&self) -> Vec<usize> {
+        self.graph.lock().evaluation_order().to_vec()
+    }
+
+    /// The patch as a Graphviz digraph. Ports are record fields, MIDI edges are blue and dashed
+    /// and edges from faulted components are gray and dotted. Edges going against the
+    /// evaluation order would be red and dashed, but the engine never lets a patch loop.
+    pub fn to_dot(&self) -> String {
+        self.graph.lock().to_dot()
+    }
+
+    /// The patch as a Mermaid flowchart. MIDI edges are thick and edges from faulted components
+    /// are dotted, edges against the evaluation order would be red.
+    pub fn to_mermaid(&self) -> String {
+        self.graph.lock().to_mermaid()
+    }
+
+    /// Gives a component a name for patch editors and debugging. Returns false if there is
+    /// no component with that ID.
+    pub fn set_label(&mut self, id: usize, label: Option<String>) -> bool {
+        let mut graph = self.graph.lock();
+        let old = match graph.component_info(id) {
+            Some(info) => info.label,
+            None => return false,
+        };
+        graph.set_label(id, label.clone());
+        drop(graph);
+        self.record(Command::SetLabel {
+            id,
+            old,
+            new: label,
+        });
+        true
+    }
+
+    /// Resets a component's model and clears its fault so it is evaluated again.
+    /// Returns false if there is no component with that ID.
+    pub fn reset_component(&mut self, id: usize) -> bool {
+        self.graph.lock().reset_component(id)
+    }
+
+    /// Sets how many worker threads evaluate independent branches of the graph alongside
+    /// the audio thread. 0 evaluates everything on the audio thread.
+    pub fn set_worker_threads(&mut self, threads: usize) {
+        self.graph.lock().set_worker_threads(threads)
+    }
+
+    pub fn worker_threads(&self) -> usize {
+        self.graph.lock().worker_threads()
+    }
+
+    /// Splits blocks at the events models announce so models can treat everything as
+    /// constant for the length of a block. Off by default.
+    pub fn set_split_at_events(&mut self, split: bool) {
+        self.graph.lock().set_split_at_events(split)
+    }
+
+    /// Number of samples the engine has rendered, the clock `MidiInput` timestamps are in
+    pub fn sample_time(&self) -> u64 {
+        self.renderer.lock().sample_time()
     }
 }
 
-pub fn list_devices() -> Vec<OutputDevice> {
-    let host = cpal::default_host();
-    host.output_devices()
-        .unwrap()
-        .map(|device| OutputDevice {
-            name: device.name().unwrap(),
-            device,
-        })
-        .collect()
+//Sends `event` to every subscriber that is still listening
+fn broadcast_all<T: Clone>(subscribers: &Mutex<Vec<Sender<T>>>, events: Vec<T>) {
+    if events.is_empty() {
+        return;
+    }
+    subscribers
+        .lock()
+        .retain(|subscriber| events.iter().all(|e| subscriber.send(e.clone()).is_ok()));
+}
+
+fn broadcast<T: Clone>(subscribers: &Mutex<Vec<Sender<T>>>, event: T) {
+    subscribers
+        .lock()
+        .retain(|subscriber| subscriber.send(event.clone()).is_ok());
 }
 
 pub trait Model {
@@ -146,26 +698,131 @@ pub trait Model {
         outputs: &mut Output,
         config: &Config,
     );
+
+    /// Current values of the model's parameters by name
+    fn parameters(&self) -> HashMap<String, f32> {
+        HashMap::new()
+    }
+
+    /// Sets one of the parameters listed by `parameters`. Returns false if there is no
+    /// parameter called `name`.
+    fn set_parameter(&mut self, _name: &str, _value: f32) -> bool {
+        false
+    }
+
+    /// Name of the model's type, shown by introspection tools.
+    fn type_name(&self) -> &'static str {
+        std::any::type_name::<Self>()
+    }
+
+    /// Puts the model back into its initial state. Called when a faulted component is reset.
+    fn reset(&mut self) {}
+
+    /// Offset into the next `frames` samples starting at sample `time` where this model will
+    /// produce an event. Used to split blocks at events, models without events return `None`.
+    fn next_event(&self, _time: u64, _frames: usize) -> Option<usize> {
+        None
+    }
 }
 
+#[derive(Clone)]
 pub struct Config {
     buffer_size: usize,
     delta: f32,
+    time: u64,
+    diagnostics: Arc<Diagnostics>,
 }
 
-#[derive(Default)]
+impl Config {
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    /// Time between two samples in seconds
+    pub fn delta(&self) -> f32 {
+        self.delta
+    }
+
+    /// Number of samples evaluated before this block
+    pub fn time(&self) -> u64 {
+        self.time
+    }
+
+    /// Counts an under- or overrun in the engine's stats. Safe to call from `Model::evaluate`.
+    pub fn report_xrun(&self) {
+        self.diagnostics.report_xrun()
+    }
+
+    fn report_fault(&self) {
+        self.diagnostics.report_fault()
+    }
+}
+
+/// The buffers feeding a model's inputs. The maps are built once per graph change and lent
+/// to every evaluation, so evaluating a model doesn't allocate.
 pub struct Input<'a> {
-    pub voltages: HashMap<String, &'a Vec<f32>>,
-    pub midi_events: HashMap<String, &'a Vec<Option<MidiMessage>>>,
+    pub voltages: &'a HashMap<String, &'a Vec<f32>>,
+    pub midi_events: &'a HashMap<String, &'a Vec<Option<MidiMessage>>>,
 }
 
+/// The buffers a model writes its outputs to, one for every output in `Model::output_format`.
+/// The engine points the inputs downstream straight at them, so models can write into the
+/// buffers but not add, remove or resize any.
 #[derive(Default)]
 pub struct Output {
-    pub voltages: HashMap<String, Vec<f32>>,
-    pub midi_events: HashMap<String, Vec<Option<MidiMessage>>>,
+    pub(crate) voltages: HashMap<String, Vec<f32>>,
+    pub(crate) midi_events: HashMap<String, Vec<Option<MidiMessage>>>,
+}
+
+impl Output {
+    /// Silent buffers of `length` samples for the outputs in `format`
+    pub fn new(format: HashMap<String, IOType>, length: usize) -> Self {
+        let mut output = Output::default();
+        for (name, io) in format {
+            match io {
+                IOType::Voltage => {
+                    output.voltages.insert(name, vec![0.; length]);
+                }
+                IOType::Midi => {
+                    output.midi_events.insert(name, vec![None; length]);
+                }
+            }
+        }
+        output
+    }
+
+    /// Borrows the voltage and MIDI outputs with the given names all at once. Names that
+    /// aren't outputs of that type, or that were asked for earlier, are `None`.
+    #[allow(clippy::type_complexity)]
+    pub fn buffers_mut<const V: usize, const M: usize>(
+        &mut self,
+        voltages: [&str; V],
+        midi_events: [&str; M],
+    ) -> (
+        [Option<&mut [f32]>; V],
+        [Option<&mut [Option<MidiMessage>]>; M],
+    ) {
+        (
+            borrow_buffers(&mut self.voltages, voltages),
+            borrow_buffers(&mut self.midi_events, midi_events),
+        )
+    }
 }
 
-#[derive(Clone, Hash, PartialEq, Eq)]
+fn borrow_buffers<'a, T, const N: usize>(
+    buffers: &'a mut HashMap<String, Vec<T>>,
+    names: [&str; N],
+) -> [Option<&'a mut [T]>; N] {
+    let mut borrowed = names.map(|_| None);
+    for (name, buffer) in buffers.iter_mut() {
+        if let Some(i) = names.iter().position(|n| n == name) {
+            borrowed[i] = Some(buffer.as_mut_slice());
+        }
+    }
+    borrowed
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
 pub enum IOType {
     Voltage,
     Midi,
@@ -181,19 +838,33 @@ pub enum ConnectionError {
     OutputNotInComponent,
 }
 
-#[derive(Clone, Hash, Eq, PartialEq)]
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
 pub struct Connection {
     pub from: Port,
     pub to: Port,
 }
 
-#[derive(Clone, Hash, Eq, PartialEq)]
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
 pub struct Port {
     pub id: usize,
     pub io: IOType,
     pub name: String,
 }
 
-fn err_fn(err: cpal::StreamError) {
-    eprintln!("an error occurred on stream: {}", err);
+/// A read-only description of one component, see `Engine::component_info`.
+#[derive(Clone, Debug)]
+pub struct ComponentInfo {
+    pub id: usize,
+    /// `Model::type_name` of the component's model
+    pub type_name: &'static str,
+    pub label: Option<String>,
+    pub fault: Option<Fault>,
+    /// Sorted by name
+    pub inputs: Vec<Port>,
+    /// Sorted by name
+    pub outputs: Vec<Port>,
+    /// Sorted by input name
+    pub in_connections: Vec<Connection>,
+    /// Sorted by the component and input they go to
+    pub out_connections: Vec<Connection>,
 }
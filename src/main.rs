@@ -0,0 +1,464 @@
+use std::{
+    collections::HashMap,
+    env, fs,
+    process::ExitCode,
+    thread,
+    time::{Duration, Instant},
+};
+#[cfg(feature = "cpal")]
+use std::{
+    io,
+    sync::mpsc::{self, RecvTimeoutError},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+#[cfg(feature = "cpal")]
+use proto::{
+    audio_io::AudioInput,
+    backend::cpal::{hosts, CpalBackend, Host},
+    GraphEvent, InputDevice, OutputDevice,
+};
+use proto::{
+    backend::FileBackend,
+    patch::{Patch, PatchError, OUTPUT_NAME},
+    registry::ModelRegistry,
+    safety::SafetyConfig,
+    Engine,
+};
+
+#[cfg(all(feature = "repl", feature = "cpal"))]
+use proto::repl::Repl;
+#[cfg(all(feature = "tui", feature = "cpal"))]
+use proto::tui::Tui;
+
+const USAGE: &str = "\
+Usage:
+  proto play <patch> [options]           Play a patch on an audio device
+  proto render <patch> <file.wav> [options]
+                                         Render a patch offline to a WAV file
+  proto repl [patch] [options]           Patch live at a prompt, needs the repl feature
+  proto tui [patch] [options]            Patch live in a terminal editor, needs the tui feature
+  proto devices                          List audio devices
+  proto models                           List the models patches can use
+
+play, repl, tui and devices need the cpal feature, which is on by default.
+
+Options:
+  --output <name>      Output device, by name or part of it (play, repl, tui)
+  --input <name>       Input device used by AudioInput (play, repl, tui)
+  --duration <secs>    How long to play, or how much to render (required for render)
+  --rate <hz>          Sample rate, 48000 by default
+  --buffer <frames>    Buffer size, 256 by default
+  --channels <n>       Channels of the rendered file, 2 by default (render)
+  --limit              Pass the render through the output limiter like play does, it is
+                       written untouched otherwise (render)
+  --stats              Print engine statistics every second while playing";
+
+#[cfg(feature = "cpal")]
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Options {
+    positional: Vec<String>,
+    output: Option<String>,
+    input: Option<String>,
+    duration: Option<f32>,
+    sample_rate: usize,
+    buffer_size: usize,
+    channels: usize,
+    limit: bool,
+    stats: bool,
+}
+
+fn main() -> ExitCode {
+    match run(env::args().skip(1).collect()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {:#}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: Vec<String>) -> Result<()> {
+    let options = parse_options(args)?;
+    let positional: Vec<&str> = options.positional.iter().map(|s| s.as_str()).collect();
+    match positional.as_slice() {
+        #[cfg(feature = "cpal")]
+        ["play", patch] => play(patch, &options),
+        ["render", patch, file] => render(patch, file, &options),
+        #[cfg(all(feature = "repl", feature = "cpal"))]
+        ["repl"] => repl(None, &options),
+        #[cfg(all(feature = "repl", feature = "cpal"))]
+        ["repl", patch] => repl(Some(patch), &options),
+        #[cfg(all(feature = "tui", feature = "cpal"))]
+        ["tui"] => tui(None, &options),
+        #[cfg(all(feature = "tui", feature = "cpal"))]
+        ["tui", patch] => tui(Some(patch), &options),
+        #[cfg(feature = "cpal")]
+        ["devices"] => devices(),
+        #[cfg(not(feature = "cpal"))]
+        [command @ ("play" | "repl" | "tui" | "devices"), ..] => {
+            bail!("{} needs the cpal feature", command)
+        }
+        ["models"] => {
+            models();
+            Ok(())
+        }
+        [] | ["help"] => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        _ => bail!("unexpected arguments\n\n{}", USAGE),
+    }
+}
+
+fn parse_options(args: Vec<String>) -> Result<Options> {
+    let mut options = Options {
+        positional: Vec::new(),
+        output: None,
+        input: None,
+        duration: None,
+        sample_rate: 48000,
+        buffer_size: 256,
+        channels: 2,
+        limit: false,
+        stats: false,
+    };
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| anyhow!("{} needs a value", arg));
+        match arg.as_str() {
+            "--output" => options.output = Some(value()?),
+            "--input" => options.input = Some(value()?),
+            "--duration" => options.duration = Some(number(&value()?, "--duration")?),
+            "--rate" => options.sample_rate = number(&value()?, "--rate")?,
+            "--buffer" => options.buffer_size = number(&value()?, "--buffer")?,
+            "--channels" => options.channels = number(&value()?, "--channels")?,
+            "--limit" => options.limit = true,
+            "--stats" => options.stats = true,
+            "-h" | "--help" => options.positional = vec![String::from("help")],
+            _ if arg.starts_with("--") => bail!("unknown option {}\n\n{}", arg, USAGE),
+            _ => options.positional.push(arg),
+        }
+    }
+    Ok(options)
+}
+
+fn number<T: std::str::FromStr>(value: &str, option: &str) -> Result<T> {
+    value
+        .parse()
+        .map_err(|_| anyhow!("{} expects a number, got {}", option, value))
+}
+
+//The output device and a registry whose AudioInput records from the chosen input device
+#[cfg(feature = "cpal")]
+fn open_devices(options: &Options) -> Result<(OutputDevice, ModelRegistry)> {
+    let output = match &options.output {
+        Some(name) => find_device(
+            Host::default().output_devices(),
+            name,
+            |d: &OutputDevice| &d.name,
+        )?,
+        None => OutputDevice::default().ok_or_else(|| anyhow!("there is no output device"))?,
+    };
+    let mut registry = ModelRegistry::with_builtins();
+    if let Some(name) = &options.input {
+        let input = find_device(Host::default().input_devices(), name, |d: &InputDevice| {
+            &d.name
+        })?;
+        println!("recording from {}", input.name);
+        registry.register("AudioInput", &[], move |_, context| {
+            AudioInput::from_device(
+                &input,
+                context.sample_rate,
+                context.buffer_size,
+                context.status.clone(),
+            )
+            .map_err(|e| format!("{:?}", e))
+        });
+    }
+    Ok((output, registry))
+}
+
+#[cfg(feature = "cpal")]
+fn live_engine(output: &OutputDevice, options: &Options) -> Result<Engine> {
+    let backend = CpalBackend::new(output, options.buffer_size, options.sample_rate)
+        .map_err(|e| anyhow!("can't open {}: {:?}", output.name, e))?;
+    Ok(Engine::with_backend(Box::new(backend)).0)
+}
+
+#[cfg(feature = "cpal")]
+fn play(path: &str, options: &Options) -> Result<()> {
+    let (patch, source) = load_patch(path)?;
+    let (output, registry) = open_devices(options)?;
+    let mut engine = live_engine(&output, options)?;
+    let names = patch
+        .apply(&mut engine, &registry)
+        .map_err(|e| patch_error(path, &e, &source))?;
+    let graph_events = engine.graph_events();
+    let status_events = engine.status_events();
+    engine
+        .start()
+        .map_err(|e| anyhow!("can't start {}: {:?}", output.name, e))?;
+    println!(
+        "playing {} on {} at {} Hz, press enter to stop",
+        path,
+        output.name,
+        engine.sample_rate()
+    );
+
+    //Without a terminal stdin is closed straight away, then only --duration stops playback
+    let (enter, entered) = mpsc::channel();
+    thread::spawn(move || {
+        if let Ok(1..) = io::stdin().read_line(&mut String::new()) {
+            let _ = enter.send(());
+        }
+    });
+    let started = Instant::now();
+    let duration = options.duration.map(Duration::from_secs_f32);
+    loop {
+        let wait = match duration {
+            Some(duration) => match duration.checked_sub(started.elapsed()) {
+                Some(left) => left.min(STATS_INTERVAL),
+                None => break,
+            },
+            None => STATS_INTERVAL,
+        };
+        match entered.recv_timeout(wait) {
+            Ok(()) => break,
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => thread::sleep(wait),
+        }
+        for event in graph_events.try_iter() {
+            if let GraphEvent::ComponentFaulted { id, fault } = event {
+                println!("{} faulted: {:?}", name(&names, &engine, id), fault);
+            }
+        }
+        for event in status_events.try_iter() {
+            println!("{:?}", event);
+        }
+        if options.stats {
+            let stats = engine.stats();
+            println!(
+                "dsp load {:.1}% (peak {:.1}%), xruns {}, faults {}",
+                stats.dsp_load * 100.,
+                stats.peak_dsp_load * 100.,
+                stats.xruns(),
+                stats.faults
+            );
+        }
+    }
+    engine.stop();
+    print_diagnostics(&engine, &names);
+    Ok(())
+}
+
+#[cfg(all(feature = "repl", feature = "cpal"))]
+fn repl(path: Option<&str>, options: &Options) -> Result<()> {
+    let (output, registry) = open_devices(options)?;
+    let mut engine = live_engine(&output, options)?;
+    if let Some(path) = path {
+        let (patch, source) = load_patch(path)?;
+        patch
+            .apply(&mut engine, &registry)
+            .map_err(|e| patch_error(path, &e, &source))?;
+    }
+    engine
+        .start()
+        .map_err(|e| anyhow!("can't start {}: {:?}", output.name, e))?;
+    println!(
+        "playing on {} at {} Hz, type help for commands",
+        output.name,
+        engine.sample_rate()
+    );
+    Repl::new(&mut engine, registry).run()?;
+    engine.stop();
+    Ok(())
+}
+
+#[cfg(all(feature = "tui", feature = "cpal"))]
+fn tui(path: Option<&str>, options: &Options) -> Result<()> {
+    let (output, registry) = open_devices(options)?;
+    let mut engine = live_engine(&output, options)?;
+    if let Some(path) = path {
+        let (patch, source) = load_patch(path)?;
+        patch
+            .apply(&mut engine, &registry)
+            .map_err(|e| patch_error(path, &e, &source))?;
+    }
+    engine
+        .start()
+        .map_err(|e| anyhow!("can't start {}: {:?}", output.name, e))?;
+    Tui::new(&mut engine, registry).run()?;
+    engine.stop();
+    Ok(())
+}
+
+fn render(path: &str, file: &str, options: &Options) -> Result<()> {
+    let (patch, source) = load_patch(path)?;
+    let duration = options
+        .duration
+        .ok_or_else(|| anyhow!("render needs --duration"))?;
+    let frames = (duration * options.sample_rate as f32) as usize;
+    let backend = FileBackend::new(
+        file,
+        options.sample_rate,
+        options.channels,
+        options.buffer_size,
+        frames,
+    );
+    let (mut engine, _) = Engine::with_backend(Box::new(backend));
+    //A file can hold peaks over full scale, so the limiter meant to protect ears and speakers
+    //is only used when asked for
+    if !options.limit {
+        engine.set_output_safety(SafetyConfig::disabled());
+    }
+    let names = patch
+        .apply(&mut engine, &ModelRegistry::with_builtins())
+        .map_err(|e| patch_error(path, &e, &source))?;
+    let started = Instant::now();
+    engine
+        .start()
+        .map_err(|e| anyhow!("can't write {}: {:?}", file, e))?;
+    while !engine.is_finished() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    engine
+        .finish()
+        .map_err(|e| anyhow!("can't write {}: {:?}", file, e))?;
+    println!(
+        "rendered {:.2}s of {} to {} in {:.2}s",
+        duration,
+        path,
+        file,
+        started.elapsed().as_secs_f32()
+    );
+    print_diagnostics(&engine, &names);
+    Ok(())
+}
+
+#[cfg(feature = "cpal")]
+fn devices() -> Result<()> {
+    for host in hosts() {
+        println!("{}", host.name);
+        let default_output = host.default_output_device().map(|d| d.name);
+        let default_input = host.default_input_device().map(|d| d.name);
+        let outputs = host.output_devices().unwrap_or_default();
+        let inputs = host.input_devices().unwrap_or_default();
+        println!("  outputs:");
+        for device in outputs {
+            let marker = if Some(&device.name) == default_output.as_ref() {
+                " (default)"
+            } else {
+                ""
+            };
+            println!("    {}{}", device.name, marker);
+        }
+        println!("  inputs:");
+        for device in inputs {
+            let marker = if Some(&device.name) == default_input.as_ref() {
+                " (default)"
+            } else {
+                ""
+            };
+            println!("    {}{}", device.name, marker);
+        }
+    }
+    Ok(())
+}
+
+fn models() {
+    let registry = ModelRegistry::with_builtins();
+    for name in registry.names() {
+        let arguments: Vec<String> = registry
+            .arguments(name)
+            .unwrap()
+            .iter()
+            .map(|(argument, default)| format!("{} = {}", argument, default))
+            .collect();
+        println!("{}({})", name, arguments.join(", "));
+    }
+}
+
+//The patch and its source, to point at errors found when it is applied
+fn load_patch(path: &str) -> Result<(Patch, String)> {
+    let source = fs::read_to_string(path).with_context(|| format!("can't read {}", path))?;
+    let patch = Patch::parse(&source).map_err(|e| patch_error(path, &e, &source))?;
+    Ok((patch, source))
+}
+
+//Picks the device called `name`, or the only one whose name contains it
+#[cfg(feature = "cpal")]
+fn find_device<D, E: std::fmt::Debug>(
+    devices: Result<Vec<D>, E>,
+    name: &str,
+    device_name: impl Fn(&D) -> &String,
+) -> Result<D> {
+    let mut devices = devices.map_err(|e| anyhow!("can't list devices: {:?}", e))?;
+    if let Some(index) = devices.iter().position(|d| device_name(d) == name) {
+        return Ok(devices.swap_remove(index));
+    }
+    let lowercase = name.to_lowercase();
+    let mut matching: Vec<D> = devices
+        .into_iter()
+        .filter(|d| device_name(d).to_lowercase().contains(&lowercase))
+        .collect();
+    match matching.len() {
+        0 => bail!("no device matches {}, see `proto devices`", name),
+        1 => Ok(matching.remove(0)),
+        _ => {
+            let names: Vec<&String> = matching.iter().map(&device_name).collect();
+            bail!("{} matches several devices: {:?}", name, names)
+        }
+    }
+}
+
+fn patch_error(path: &str, error: &PatchError, source: &str) -> anyhow::Error {
+    match error.position() {
+        Some(_) => anyhow!("{}:{}", path, error.report(source)),
+        None => anyhow!("{}: {}", path, error),
+    }
+}
+
+fn name(names: &HashMap<String, usize>, engine: &Engine, id: usize) -> String {
+    if id == engine.output_id() {
+        return String::from(OUTPUT_NAME);
+    }
+    names
+        .iter()
+        .find(|(_, i)| **i == id)
+        .map(|(name, _)| name.clone())
+        .unwrap_or_else(|| format!("#{}", id))
+}
+
+fn print_diagnostics(engine: &Engine, names: &HashMap<String, usize>) {
+    let stats = engine.stats();
+    println!(
+        "callbacks {}, late {}, model xruns {}, faults {}",
+        stats.callbacks, stats.late_callbacks, stats.model_xruns, stats.faults
+    );
+    println!(
+        "dsp load average {:.1}%, peak {:.1}%",
+        stats.average_dsp_load * 100.,
+        stats.peak_dsp_load * 100.
+    );
+    let mut components: Vec<_> = stats.components.iter().collect();
+    components.sort_by_key(|(id, _)| **id);
+    println!(
+        "{:<20} {:>12} {:>12} {:>12}  fault",
+        "component", "evaluations", "average", "max"
+    );
+    for (id, component) in components {
+        println!(
+            "{:<20} {:>12} {:>12?} {:>12?}  {}",
+            name(names, engine, *id),
+            component.evaluations,
+            component.average(),
+            component.max,
+            component
+                .fault
+                .map(|f| format!("{:?}", f))
+                .unwrap_or_default()
+        );
+    }
+}
@@ -1,111 +1,106 @@
-use std::{collections::HashMap, iter::zip, sync::Arc};
+use std::{iter::zip, sync::Arc};
 
 use parking_lot::Mutex;
 
-use crate::{Config, IOType, Model, ModelHolder};
+use crate::{
+    ports::{Jack, Process},
+    Config, Model, ModelHolder,
+};
 
-pub struct ConstantAmplifier(f32);
+#[derive(Model)]
+pub struct ConstantAmplifier {
+    #[input(voltage)]
+    input: Jack,
+    #[output(voltage)]
+    output: Jack,
+    #[parameter]
+    gain: f32,
+}
 
 impl ConstantAmplifier {
     pub fn new(value: f32) -> Self {
-        ConstantAmplifier(value)
+        ConstantAmplifier {
+            input: Jack,
+            output: Jack,
+            gain: value,
+        }
     }
 }
 
-impl Model for ConstantAmplifier {
-    fn input_format(&self) -> std::collections::HashMap<String, crate::IOType> {
-        let mut input_format = HashMap::new();
-        input_format.insert(String::from("Input"), IOType::Voltage);
-        input_format
-    }
-    fn output_format(&self) -> std::collections::HashMap<String, crate::IOType> {
-        let mut output_format = HashMap::new();
-        output_format.insert(String::from("Output"), IOType::Voltage);
-        output_format
-    }
-    fn evaluate(
-        &mut self,
-        _buffer_size: usize,
-        inputs: crate::Input,
-        outputs: &mut crate::Output,
-        _config: &Config,
-    ) {
-        let input = inputs.voltages.get("Input").unwrap();
-        let output = outputs.voltages.get_mut("Output").unwrap();
-        for (index, sample) in input.iter().enumerate() {
-            output[index] = sample * self.0;
+impl Process for ConstantAmplifier {
+    fn process(&mut self, ports: ConstantAmplifierPorts, _config: &Config) {
+        for (output, sample) in zip(ports.output.iter_mut(), ports.input) {
+            *output = sample * self.gain;
         }
     }
 }
 
-pub struct DuoSignalMixer(f32, f32);
+#[derive(Model)]
+pub struct DuoSignalMixer {
+    #[input(voltage)]
+    input1: Jack,
+    #[input(voltage)]
+    input2: Jack,
+    #[output(voltage)]
+    output: Jack,
+    #[parameter]
+    input1_gain: f32,
+    #[parameter]
+    input2_gain: f32,
+}
 
 impl DuoSignalMixer {
     pub fn new(input_1_mult: f32, input_2_mult: f32) -> Self {
-        DuoSignalMixer(input_1_mult, input_2_mult)
+        DuoSignalMixer {
+            input1: Jack,
+            input2: Jack,
+            output: Jack,
+            input1_gain: input_1_mult,
+            input2_gain: input_2_mult,
+        }
     }
 }
 
-impl Model for DuoSignalMixer {
-    fn input_format(&self) -> std::collections::HashMap<String, crate::IOType> {
-        let mut input_format = HashMap::new();
-        input_format.insert(String::from("Input1"), IOType::Voltage);
-        input_format.insert(String::from("Input2"), IOType::Voltage);
-        input_format
-    }
-    fn output_format(&self) -> std::collections::HashMap<String, crate::IOType> {
-        let mut output_format = HashMap::new();
-        output_format.insert(String::from("Output"), IOType::Voltage);
-        output_format
-    }
-    fn evaluate(
-        &mut self,
-        _buffer_size: usize,
-        inputs: crate::Input,
-        outputs: &mut crate::Output,
-        _config: &Config,
-    ) {
-        let input_1 = inputs.voltages.get("Input1").unwrap();
-        let input_2 = inputs.voltages.get("Input2").unwrap();
-        let output = outputs.voltages.get_mut("Output").unwrap();
-        for (index, sample) in zip(input_1.iter(), input_2.iter()).enumerate() {
-            output[index] = (sample.0 * self.0) + (sample.1 * self.1);
+impl Process for DuoSignalMixer {
+    fn process(&mut self, ports: DuoSignalMixerPorts, _config: &Config) {
+        for (index, sample) in zip(ports.input1, ports.input2).enumerate() {
+            ports.output[index] = (sample.0 * self.input1_gain) + (sample.1 * self.input2_gain);
         }
     }
 }
 
-pub struct Vca;
-
-impl Model for Vca {
-    fn input_format(&self) -> std::collections::HashMap<String, crate::IOType> {
-        let mut input_format = HashMap::new();
-        input_format.insert(String::from("Input"), IOType::Voltage);
-        input_format.insert(String::from("Control"), IOType::Voltage);
-        input_format
-    }
-    fn output_format(&self) -> std::collections::HashMap<String, crate::IOType> {
-        let mut output_format = HashMap::new();
-        output_format.insert(String::from("Output"), IOType::Voltage);
-        output_format
-    }
-    fn evaluate(
-        &mut self,
-        _buffer_size: usize,
-        inputs: crate::Input,
-        outputs: &mut crate::Output,
-        _config: &Config,
-    ) {
-        let input = inputs.voltages.get("Input").unwrap();
-        let control = inputs.voltages.get("Control").unwrap();
-        let output = outputs.voltages.get_mut("Output").unwrap();
-        for (index, sample) in zip(input.iter(), control.iter()).enumerate() {
-            output[index] = sample.0 * sample.1;
+#[derive(Model, Default)]
+pub struct Vca {
+    #[input(voltage)]
+    input: Jack,
+    #[input(voltage)]
+    control: Jack,
+    #[output(voltage)]
+    output: Jack,
+}
+
+impl Vca {
+    pub fn new() -> Self {
+        Vca::default()
+    }
+}
+
+impl Process for Vca {
+    fn process(&mut self, ports: VcaPorts, _config: &Config) {
+        for (index, sample) in zip(ports.input, ports.control).enumerate() {
+            ports.output[index] = sample.0 * sample.1;
         }
     }
 }
 
+#[derive(Model)]
 pub struct Tone {
+    #[input(voltage)]
+    input: Jack,
+    #[output(voltage)]
+    output: Jack,
     resistor_one_value: f32,
+    #[parameter(name = "Resistance")]
     resistor_two_value: f32,
     inductor_value: f32,
     capicitor_value: f32,
@@ -114,8 +109,11 @@ pub struct Tone {
 }
 
 impl Tone {
+    #[allow(clippy::new_ret_no_self)]
     pub fn new(resistor_value: f32) -> ModelHolder {
         let tone = Tone {
+            input: Jack,
+            output: Jack,
             capicitor_value: 1.5e-8,
             resistor_one_value: 100_000.,
             resistor_two_value: resistor_value,
@@ -127,38 +125,117 @@ impl Tone {
     }
 }
 
-impl Model for Tone {
-    fn input_format(&self) -> std::collections::HashMap<String, crate::IOType> {
-        let mut input_format = HashMap::new();
-        input_format.insert(String::from("Input"), IOType::Voltage);
-        input_format
-    }
-
-    fn output_format(&self) -> std::collections::HashMap<String, crate::IOType> {
-        let mut output_format = HashMap::new();
-        output_format.insert(String::from("Output"), IOType::Voltage);
-        output_format
-    }
-
-    fn evaluate(
-        &mut self,
-        _buffer_size: usize,
-        inputs: crate::Input,
-        outputs: &mut crate::Output,
-        config: &Config,
-    ) {
-        let inputs = *inputs.voltages.get("Input").unwrap();
-        let outputs = outputs.voltages.get_mut("Output").unwrap();
-        for (index, sample) in inputs.iter().enumerate() {
+impl Process for Tone {
+    fn process(&mut self, ports: TonePorts, config: &Config) {
+        for (index, sample) in ports.input.iter().enumerate() {
             //This is silly circuit simulation.
             //It's an eulerian approximation of a voltage source to a resistor to an inductor to another resistor to a capacitor to ground
-            //The voltage out is between the inductor and second resistor
-            self.current += (((sample - self.current * self.resistor_one_value)
-                - (self.current * self.resistor_two_value + self.capicitor_voltage))
-                / self.inductor_value)
-                * config.delta;
-            self.capicitor_value += (self.current / self.capicitor_voltage) * config.delta;
-            outputs[index] = self.current * self.resistor_two_value + self.capicitor_voltage;
+            //The voltage out is across the second resistor, so the capacitor blocks DC and the
+            //inductor the highs
+            //The current is stepped backwards, a forward step blows up once the resistors are
+            //large compared to the inductor, like at the default resistance
+            let resistance = self.resistor_one_value + self.resistor_two_value;
+            self.current = (self.current
+                + (sample - self.capicitor_voltage) * config.delta / self.inductor_value)
+                / (1. + resistance * config.delta / self.inductor_value);
+            self.capicitor_voltage += self.current / self.capicitor_value * config.delta;
+            ports.output[index] = self.current * self.resistor_two_value;
         }
     }
+
+    fn reset(&mut self) {
+        self.capicitor_value = 1.5e-8;
+        self.current = 0.;
+        self.capicitor_voltage = 0.;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::TAU;
+
+    use super::*;
+    use crate::testing::ModelTester;
+
+    fn sine(frequency: f32) -> impl FnMut(f32) -> f32 {
+        move |t| (TAU * frequency * t).sin()
+    }
+
+    #[test]
+    fn constant_amplifier_scales_its_input() {
+        let mut tester = ModelTester::new(ConstantAmplifier::new(0.5), 48000, 64);
+        tester.input_fn("Input", sine(440.)).run_seconds(0.1);
+        let output = tester.output("Output");
+        output.assert_peak(0.5, 0.01);
+        output.assert_frequency(440., 1.);
+
+        tester
+            .set_parameter("Gain", -2.)
+            .clear_outputs()
+            .run_seconds(0.1);
+        tester.output("Output").assert_peak(2., 0.01);
+    }
+
+    #[test]
+    fn vca_multiplies_its_input_by_the_control() {
+        let mut tester = ModelTester::new(Vca::new(), 48000, 64);
+        tester
+            .input_fn("Input", sine(440.))
+            .constant("Control", 0.25)
+            .run_seconds(0.1);
+        tester.output("Output").assert_peak(0.25, 0.01);
+
+        tester
+            .constant("Control", 0.)
+            .clear_outputs()
+            .run_seconds(0.1);
+        tester.output("Output").assert_silent();
+    }
+
+    #[test]
+    fn vca_follows_the_control_sample_by_sample() {
+        let mut tester = ModelTester::new(Vca::new(), 48000, 4);
+        tester
+            .input("Input", [1., 2., 3., 4., 5., 6.])
+            .input("Control", [1., 0., -1., 0.5, 0., 1.])
+            .run(6);
+        assert_eq!(tester.output("Output").samples(), [1., 0., -3., 2., 0., 6.]);
+    }
+
+    #[test]
+    fn tone_blocks_dc() {
+        let mut tester = ModelTester::new(Tone::new(100_000.), 48000, 64);
+        tester.constant("Input", 1.).run_seconds(0.1);
+        let output = tester.output("Output");
+        output.assert_finite();
+        //The capacitor charges up through both resistors
+        assert!(output.peak() > 0.4);
+        output.skip(0.05).assert_silent();
+    }
+
+    #[test]
+    fn tone_gain_depends_on_frequency() {
+        let gain = |frequency: f32| {
+            let mut tester = ModelTester::new(Tone::new(100_000.), 48000, 64);
+            tester.input_fn("Input", sine(frequency)).run_seconds(0.5);
+            tester.output("Output").skip(0.3).rms() / std::f32::consts::FRAC_1_SQRT_2
+        };
+        let (low, middle, high) = (gain(10.), gain(1000.), gain(20_000.));
+        //Around the resonance only the resistors divide the signal
+        assert!((middle - 0.5).abs() < 0.02);
+        assert!(low < 0.5 * middle);
+        assert!(high < 0.8 * middle);
+    }
+
+    #[test]
+    fn tone_reset_forgets_the_current() {
+        let mut tester = ModelTester::new(Tone::new(100_000.), 48000, 64);
+        tester.constant("Input", 1.).run_seconds(0.01);
+        tester
+            .constant("Input", 0.)
+            .reset()
+            .clear_outputs()
+            .run_blocks(1);
+        tester.output("Output").assert_silent();
+    }
 }
@@ -0,0 +1,695 @@
+//! A small OSC 1.0 server for driving an `Engine` over UDP.
+//!
+//! Incoming messages:
+//! - `/proto/<id>/<parameter> f` sets a parameter. Both parts may be OSC address patterns,
+//!   so `/proto/*/Gain 0.5` sets the gain of every component that has one.
+//! - `/proto/connect i s i s` and `/proto/disconnect i s i s` take the component and output
+//!   a connection comes from, then the component and input it goes to.
+//! - `/proto/probe i s` and `/proto/unprobe i s` start and stop sending the sender the level
+//!   at an input as `/proto/probe/<id>/<input> f`.
+//!
+//! Anything that fails is answered with `/proto/error s`.
+
+use std::{
+    io,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+
+use crate::{Connection, Engine, Port};
+
+//How long the server blocks on the socket, which bounds how late probes and shutdown are
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+//Largest UDP payload
+const MAX_PACKET: usize = 65_536;
+
+/// An argument of an OSC message. Only the types the server understands are supported.
+#[derive(Clone, Debug, PartialEq)]
+pub enum OscArg {
+    Int(i32),
+    Float(f32),
+    String(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OscMessage {
+    pub address: String,
+    pub args: Vec<OscArg>,
+}
+
+#[derive(Debug)]
+pub enum OscError {
+    /// The packet ended in the middle of a field
+    Truncated,
+    /// The packet has no type tag string or one with types other than `i`, `f` and `s`
+    UnsupportedType(char),
+    BadString,
+    /// The address doesn't start with `/` or bundle content isn't `#bundle`
+    Malformed,
+}
+
+impl OscMessage {
+    pub fn new(address: impl Into<String>, args: Vec<OscArg>) -> Self {
+        OscMessage {
+            address: address.into(),
+            args,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut packet = Vec::new();
+        write_string(&mut packet, &self.address);
+        let mut tags = String::from(",");
+        for arg in self.args.iter() {
+            tags.push(match arg {
+                OscArg::Int(_) => 'i',
+                OscArg::Float(_) => 'f',
+                OscArg::String(_) => 's',
+            });
+        }
+        write_string(&mut packet, &tags);
+        for arg in self.args.iter() {
+            match arg {
+                OscArg::Int(value) => packet.extend_from_slice(&value.to_be_bytes()),
+                OscArg::Float(value) => packet.extend_from_slice(&value.to_be_bytes()),
+                OscArg::String(value) => write_string(&mut packet, value),
+            }
+        }
+        packet
+    }
+
+    /// Decodes a packet into the messages in it, bundles are flattened and their time tags
+    /// ignored.
+    pub fn decode(packet: &[u8]) -> Result<Vec<OscMessage>, OscError> {
+        let mut messages = Vec::new();
+        decode_packet(packet, &mut messages)?;
+        Ok(messages)
+    }
+}
+
+fn decode_packet(packet: &[u8], messages: &mut Vec<OscMessage>) -> Result<(), OscError> {
+    if packet.first() == Some(&b'#') {
+        let mut offset = 0;
+        if read_string(packet, &mut offset)? != "#bundle" {
+            return Err(OscError::Malformed);
+        }
+        //Time tag
+        read_bytes(packet, &mut offset, 8)?;
+        while offset < packet.len() {
+            let length =
+                u32::from_be_bytes(read_bytes(packet, &mut offset, 4)?.try_into().unwrap());
+            let element = read_bytes(packet, &mut offset, length as usize)?;
+            decode_packet(element, messages)?;
+        }
+        return Ok(());
+    }
+    let mut offset = 0;
+    let address = read_string(packet, &mut offset)?;
+    if !address.starts_with('/') {
+        return Err(OscError::Malformed);
+    }
+    let mut args = Vec::new();
+    //Very old clients leave the type tags out, treat that as no arguments
+    if offset < packet.len() {
+        let tags = read_string(packet, &mut offset)?;
+        let mut tags = tags.chars();
+        if tags.next() != Some(',') {
+            return Err(OscError::Malformed);
+        }
+        for tag in tags {
+            args.push(match tag {
+                'i' => OscArg::Int(i32::from_be_bytes(
+                    read_bytes(packet, &mut offset, 4)?.try_into().unwrap(),
+                )),
+                'f' => OscArg::Float(f32::from_be_bytes(
+                    read_bytes(packet, &mut offset, 4)?.try_into().unwrap(),
+                )),
+                's' => OscArg::String(read_string(packet, &mut offset)?),
+                other => return Err(OscError::UnsupportedType(other)),
+            });
+        }
+    }
+    messages.push(OscMessage { address, args });
+    Ok(())
+}
+
+//Strings are null terminated and padded to a multiple of four bytes
+fn write_string(packet: &mut Vec<u8>, value: &str) {
+    packet.extend_from_slice(value.as_bytes());
+    packet.push(0);
+    while packet.len() % 4 != 0 {
+        packet.push(0);
+    }
+}
+
+fn read_string(packet: &[u8], offset: &mut usize) -> Result<String, OscError> {
+    let rest = packet.get(*offset..).ok_or(OscError::Truncated)?;
+    let end = rest
+        .iter()
+        .position(|b| *b == 0)
+        .ok_or(OscError::Truncated)?;
+    let value = std::str::from_utf8(&rest[..end]).map_err(|_| OscError::BadString)?;
+    *offset += (end + 4) & !3;
+    Ok(value.to_string())
+}
+
+fn read_bytes<'a>(
+    packet: &'a [u8],
+    offset: &mut usize,
+    length: usize,
+) -> Result<&'a [u8], OscError> {
+    let bytes = packet
+        .get(*offset..*offset + length)
+        .ok_or(OscError::Truncated)?;
+    *offset += length;
+    Ok(bytes)
+}
+
+/// Matches an OSC address pattern part against a name. Supports `*`, `?`, `[abc]`, `[a-z]`,
+/// `[!abc]` and `{foo,bar}`.
+pub fn pattern_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    matches_from(&pattern, &name)
+}
+
+fn matches_from(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some('*') => (0..=name.len()).any(|i| matches_from(&pattern[1..], &name[i..])),
+        Some('?') => !name.is_empty() && matches_from(&pattern[1..], &name[1..]),
+        Some('[') => {
+            let close = match pattern.iter().position(|c| *c == ']') {
+                Some(close) => close,
+                None => return false,
+            };
+            let Some(c) = name.first() else { return false };
+            let mut set = &pattern[1..close];
+            let negate = set.first() == Some(&'!');
+            if negate {
+                set = &set[1..];
+            }
+            let mut found = false;
+            let mut i = 0;
+            while i < set.len() {
+                if i + 2 < set.len() && set[i + 1] == '-' {
+                    found |= set[i] <= *c && *c <= set[i + 2];
+                    i += 3;
+                } else {
+                    found |= set[i] == *c;
+                    i += 1;
+                }
+            }
+            found != negate && matches_from(&pattern[close + 1..], &name[1..])
+        }
+        Some('{') => {
+            let close = match pattern.iter().position(|c| *c == '}') {
+                Some(close) => close,
+                None => return false,
+            };
+            let rest = &pattern[close + 1..];
+            pattern[1..close]
+                .split(|c| *c == ',')
+                .any(|option| name.starts_with(option) && matches_from(rest, &name[option.len()..]))
+        }
+        Some(c) => name.first() == Some(c) && matches_from(&pattern[1..], &name[1..]),
+    }
+}
+
+/// Serves OSC on a UDP socket until it is dropped.
+pub struct OscServer {
+    address: SocketAddr,
+    probes: Arc<Mutex<Vec<Probe>>>,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+#[derive(Clone, PartialEq)]
+struct Probe {
+    target: SocketAddr,
+    id: usize,
+    input: String,
+}
+
+impl OscServer {
+    /// Binds to `address` and starts serving `engine`. Use port 0 to let the system pick one,
+    /// `local_addr` tells which.
+    pub fn bind(engine: Arc<Mutex<Engine>>, address: impl ToSocketAddrs) -> io::Result<Self> {
+        let socket = UdpSocket::bind(address)?;
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+        let address = socket.local_addr()?;
+        let probes = Arc::new(Mutex::new(Vec::new()));
+        let running = Arc::new(AtomicBool::new(true));
+        let thread = {
+            let probes = Arc::clone(&probes);
+            let running = Arc::clone(&running);
+            thread::Builder::new()
+                .name(String::from("proto-osc"))
+                .spawn(move || serve(socket, engine, probes, running))?
+        };
+        Ok(OscServer {
+            address,
+            probes,
+            running,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Sends `target` the level at input `input` of component `id` every 50ms
+    pub fn add_probe(&self, target: SocketAddr, id: usize, input: &str) {
+        let probe = Probe {
+            target,
+            id,
+            input: input.to_string(),
+        };
+        let mut probes = self.probes.lock();
+        if !probes.contains(&probe) {
+            probes.push(probe);
+        }
+    }
+
+    pub fn remove_probe(&self, target: SocketAddr, id: usize, input: &str) {
+        self.probes
+            .lock()
+            .retain(|p| !(p.target == target && p.id == id && p.input == input));
+    }
+}
+
+impl Drop for OscServer {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+//How often probe values are sent
+const PROBE_INTERVAL: Duration = Duration::from_millis(50);
+
+fn serve(
+    socket: UdpSocket,
+    engine: Arc<Mutex<Engine>>,
+    probes: Arc<Mutex<Vec<Probe>>>,
+    running: Arc<AtomicBool>,
+) {
+    let mut buffer = vec![0; MAX_PACKET];
+    let mut last_probe = Instant::now();
+    while running.load(Ordering::Acquire) {
+        if let Ok((length, sender)) = socket.recv_from(&mut buffer) {
+            let result = OscMessage::decode(&buffer[..length])
+                .map_err(|e| format!("{:?}", e))
+                .and_then(|messages| {
+                    messages
+                        .into_iter()
+                        .try_for_each(|m| handle(&m, sender, &engine, &probes))
+                });
+            if let Err(error) = result {
+                let reply = OscMessage::new("/proto/error", vec![OscArg::String(error)]);
+                let _ = socket.send_to(&reply.encode(), sender);
+            }
+        }
+        if last_probe.elapsed() >= PROBE_INTERVAL {
+            last_probe = Instant::now();
+            let probes = probes.lock().clone();
+            for probe in probes {
+                let value = engine.lock().probe(probe.id, &probe.input);
+                if let Some(value) = value {
+                    let message = OscMessage::new(
+                        format!("/proto/probe/{}/{}", probe.id, probe.input),
+                        vec![OscArg::Float(value)],
+                    );
+                    let _ = socket.send_to(&message.encode(), probe.target);
+                }
+            }
+        }
+    }
+}
+
+fn handle(
+    message: &OscMessage,
+    sender: SocketAddr,
+    engine: &Mutex<Engine>,
+    probes: &Mutex<Vec<Probe>>,
+) -> Result<(), String> {
+    let parts: Vec<&str> = message.address.split('/').skip(1).collect();
+    match (parts.as_slice(), message.args.as_slice()) {
+        (["proto", command @ ("connect" | "disconnect")], args) => {
+            let connection = connection(&engine.lock(), args)?;
+            let mut engine = engine.lock();
+            if *command == "connect" {
+                engine
+                    .add_connection(connection)
+                    .map_err(|e| format!("{:?}", e))
+            } else {
+                match engine.remove_connection(connection) {
+                    Ok(true) => Ok(()),
+                    Ok(false) => Err(String::from("NotConnected")),
+                    Err(e) => Err(format!("{:?}", e)),
+                }
+            }
+        }
+        (["proto", command @ ("probe" | "unprobe")], [OscArg::Int(id), OscArg::String(input)]) => {
+            let probe = Probe {
+                target: sender,
+                id: *id as usize,
+                input: input.clone(),
+            };
+            let mut probes = probes.lock();
+            probes.retain(|p| *p != probe);
+            if *command == "probe" {
+                probes.push(probe);
+            }
+            Ok(())
+        }
+        (["proto", id, parameter], [value]) => {
+            let value = match value {
+                OscArg::Float(value) => *value,
+                OscArg::Int(value) => *value as f32,
+                OscArg::String(_) => return Err(String::from("ParameterNotANumber")),
+            };
+            let mut engine = engine.lock();
+            let mut matched = false;
+            engine.begin_group();
+            for component in engine.components() {
+                if !pattern_matches(id, &component.to_string()) {
+                    continue;
+                }
+                let names: Vec<String> = engine
+                    .parameters(component)
+                    .map(|p| p.into_keys().collect())
+                    .unwrap_or_default();
+                for name in names.iter().filter(|n| pattern_matches(parameter, n)) {
+                    matched |= engine.set_parameter(component, name, value);
+                }
+            }
+            engine.end_group();
+            if matched {
+                Ok(())
+            } else {
+                Err(format!("NoParameter {}", message.address))
+            }
+        }
+        _ => Err(format!("UnknownAddress {}", message.address)),
+    }
+}
+
+//Builds a connection from `i s i s` arguments, looking the port types up in the graph
+fn connection(engine: &Engine, args: &[OscArg]) -> Result<Connection, String> {
+    let (from, from_name, to, to_name) = match args {
+        [OscArg::Int(from), OscArg::String(from_name), OscArg::Int(to), OscArg::String(to_name)] => {
+            (*from as usize, from_name, *to as usize, to_name)
+        }
+        _ => return Err(String::from("ExpectedArguments isis")),
+    };
+    let port = |id: usize, name: &String, input: bool| {
+        let info = engine
+            .component_info(id)
+            .ok_or_else(|| String::from("ControllerNotInGraph"))?;
+        let ports = if input { info.inputs } else { info.outputs };
+        ports.into_iter().find(|p| p.name == *name).ok_or_else(|| {
+            if input {
+                String::from("InputNotInComponent")
+            } else {
+                String::from("OutputNotInComponent")
+            }
+        })
+    };
+    let from: Port = port(from, from_name, false)?;
+    let to: Port = port(to, to_name, true)?;
+    Ok(Connection { from, to })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backend::NullBackend,
+        builder::IntoHolder,
+        model_utils::{ConstantAmplifier, DuoSignalMixer},
+    };
+
+    fn string(value: &str) -> OscArg {
+        OscArg::String(value.to_string())
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let message = OscMessage::new(
+            "/proto/connect",
+            vec![OscArg::Int(-3), OscArg::Float(0.25), string("Output")],
+        );
+        let packet = message.encode();
+        assert_eq!(packet.len() % 4, 0);
+        assert_eq!(OscMessage::decode(&packet).unwrap(), vec![message]);
+    }
+
+    #[test]
+    fn encodes_padded_strings_and_big_endian_numbers() {
+        let packet = OscMessage::new("/ab", vec![OscArg::Int(1), string("abcd")]).encode();
+        assert_eq!(packet, b"/ab\0,is\0\0\0\0\x01abcd\0\0\0\0".to_vec(),);
+    }
+
+    #[test]
+    fn decodes_bundles_and_messages_without_type_tags() {
+        let first = OscMessage::new("/a", vec![OscArg::Float(1.)]).encode();
+        let second = OscMessage::new("/b", vec![]).encode();
+        let mut bundle = Vec::new();
+        write_string(&mut bundle, "#bundle");
+        bundle.extend_from_slice(&[0; 8]);
+        for element in [&first, &second] {
+            bundle.extend_from_slice(&(element.len() as u32).to_be_bytes());
+            bundle.extend_from_slice(element);
+        }
+        let messages = OscMessage::decode(&bundle).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].address, "/a");
+        assert_eq!(messages[1].address, "/b");
+
+        let mut untagged = Vec::new();
+        write_string(&mut untagged, "/c");
+        assert_eq!(
+            OscMessage::decode(&untagged).unwrap(),
+            vec![OscMessage::new("/c", vec![])]
+        );
+    }
+
+    #[test]
+    fn rejects_broken_packets() {
+        let packet = OscMessage::new("/a", vec![OscArg::Int(1)]).encode();
+        assert!(matches!(
+            OscMessage::decode(&packet[..packet.len() - 1]),
+            Err(OscError::Truncated)
+        ));
+        let mut packet = Vec::new();
+        write_string(&mut packet, "/a");
+        write_string(&mut packet, ",d");
+        assert!(matches!(
+            OscMessage::decode(&packet),
+            Err(OscError::UnsupportedType('d'))
+        ));
+        let mut packet = Vec::new();
+        write_string(&mut packet, "a");
+        assert!(matches!(
+            OscMessage::decode(&packet),
+            Err(OscError::Malformed)
+        ));
+        let mut packet = Vec::new();
+        write_string(&mut packet, "#bundel");
+        assert!(matches!(
+            OscMessage::decode(&packet),
+            Err(OscError::Malformed)
+        ));
+    }
+
+    #[test]
+    fn patterns() {
+        assert!(pattern_matches("Gain", "Gain"));
+        assert!(!pattern_matches("Gain", "Gains"));
+        assert!(pattern_matches("*", ""));
+        assert!(pattern_matches("*", "12"));
+        assert!(pattern_matches("Input*Gain", "Input1Gain"));
+        assert!(pattern_matches("?", "7"));
+        assert!(!pattern_matches("?", "17"));
+        assert!(pattern_matches("[0-4]", "3"));
+        assert!(!pattern_matches("[0-4]", "5"));
+        assert!(pattern_matches("[!0-4]", "5"));
+        assert!(pattern_matches("[abc]x", "bx"));
+        assert!(pattern_matches("{Gain,Input1Gain}", "Input1Gain"));
+        assert!(!pattern_matches("{Gain,Input1Gain}", "Input2Gain"));
+        assert!(!pattern_matches("[0-4", "3"));
+    }
+
+    //An engine with an amplifier feeding the first input of a mixer, and a server for it
+    struct Fixture {
+        engine: Arc<Mutex<Engine>>,
+        amp: usize,
+        mixer: usize,
+        server: OscServer,
+        client: UdpSocket,
+    }
+
+    fn fixture() -> Fixture {
+        let (mut engine, _) = Engine::with_backend(Box::new(NullBackend::new(48000, 1, 64)));
+        let amp = engine.add_model(ConstantAmplifier::new(0.5).into_holder());
+        let mixer = engine.add_model(DuoSignalMixer::new(1., 1.).into_holder());
+        let engine = Arc::new(Mutex::new(engine));
+        let server = OscServer::bind(Arc::clone(&engine), "127.0.0.1:0").unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        Fixture {
+            engine,
+            amp,
+            mixer,
+            server,
+            client,
+        }
+    }
+
+    impl Fixture {
+        fn receive(&self) -> OscMessage {
+            let mut buffer = vec![0; MAX_PACKET];
+            let length = self.client.recv(&mut buffer).expect("no reply");
+            OscMessage::decode(&buffer[..length]).unwrap().remove(0)
+        }
+
+        //Sends `message` and returns the error it was answered with. The server handles
+        //packets in order, so the reply to a second, unknown address means the first is done.
+        fn send(&self, message: OscMessage) -> Option<String> {
+            let ping = OscMessage::new("/proto/ping", vec![]);
+            for message in [message, ping] {
+                self.client
+                    .send_to(&message.encode(), self.server.local_addr())
+                    .unwrap();
+            }
+            let mut error = None;
+            loop {
+                let reply = self.receive();
+                if reply.address != "/proto/error" {
+                    continue;
+                }
+                match reply.args.as_slice() {
+                    [OscArg::String(e)] if e == "UnknownAddress /proto/ping" => return error,
+                    [OscArg::String(e)] => error = Some(e.clone()),
+                    _ => panic!("bad error reply {:?}", reply),
+                }
+            }
+        }
+
+        fn connect(&self, command: &str, input: &str) -> Option<String> {
+            self.send(OscMessage::new(
+                format!("/proto/{}", command),
+                vec![
+                    OscArg::Int(self.amp as i32),
+                    string("Output"),
+                    OscArg::Int(self.mixer as i32),
+                    string(input),
+                ],
+            ))
+        }
+    }
+
+    #[test]
+    fn sets_parameters_by_pattern() {
+        let fixture = fixture();
+        let address = format!("/proto/{}/Gain", fixture.amp);
+        assert_eq!(
+            fixture.send(OscMessage::new(address, vec![OscArg::Float(0.75)])),
+            None
+        );
+        let engine = fixture.engine.lock();
+        assert_eq!(engine.parameters(fixture.amp).unwrap()["Gain"], 0.75);
+        drop(engine);
+
+        let message = OscMessage::new("/proto/*/Input?Gain", vec![OscArg::Int(2)]);
+        assert_eq!(fixture.send(message), None);
+        let parameters = fixture.engine.lock().parameters(fixture.mixer).unwrap();
+        assert_eq!(parameters["Input1Gain"], 2.);
+        assert_eq!(parameters["Input2Gain"], 2.);
+        //Everything one message set is undone at once
+        assert!(fixture.engine.lock().undo().unwrap());
+        let parameters = fixture.engine.lock().parameters(fixture.mixer).unwrap();
+        assert_eq!(parameters["Input1Gain"], 1.);
+        assert_eq!(parameters["Input2Gain"], 1.);
+
+        let message = OscMessage::new("/proto/*/Volume", vec![OscArg::Float(1.)]);
+        assert_eq!(
+            fixture.send(message).as_deref(),
+            Some("NoParameter /proto/*/Volume")
+        );
+        let message = OscMessage::new("/proto/1/Gain", vec![string("loud")]);
+        assert_eq!(
+            fixture.send(message).as_deref(),
+            Some("ParameterNotANumber")
+        );
+    }
+
+    #[test]
+    fn connects_and_disconnects() {
+        let fixture = fixture();
+        assert_eq!(fixture.connect("connect", "Input1"), None);
+        assert_eq!(fixture.engine.lock().connections().len(), 1);
+        assert_eq!(
+            fixture.connect("connect", "Input1").as_deref(),
+            Some("InputOccupied")
+        );
+        assert_eq!(
+            fixture.connect("connect", "Input3").as_deref(),
+            Some("InputNotInComponent")
+        );
+        assert_eq!(fixture.connect("disconnect", "Input1"), None);
+        assert!(fixture.engine.lock().connections().is_empty());
+        assert_eq!(
+            fixture.connect("disconnect", "Input1").as_deref(),
+            Some("NotConnected")
+        );
+        let message = OscMessage::new("/proto/connect", vec![OscArg::Int(1)]);
+        assert_eq!(
+            fixture.send(message).as_deref(),
+            Some("ExpectedArguments isis")
+        );
+    }
+
+    #[test]
+    fn probes_reply_to_the_sender_until_stopped() {
+        let fixture = fixture();
+        assert_eq!(fixture.connect("connect", "Input1"), None);
+        fixture.engine.lock().start().unwrap();
+        let probe = vec![OscArg::Int(fixture.mixer as i32), string("Input1")];
+        assert_eq!(
+            fixture.send(OscMessage::new("/proto/probe", probe.clone())),
+            None
+        );
+        let reply = fixture.receive();
+        assert_eq!(
+            reply.address,
+            format!("/proto/probe/{}/Input1", fixture.mixer)
+        );
+        assert!(matches!(reply.args.as_slice(), [OscArg::Float(_)]));
+
+        assert_eq!(fixture.send(OscMessage::new("/proto/unprobe", probe)), None);
+        //Probes sent before the unprobe was handled may still be on their way
+        fixture
+            .client
+            .set_read_timeout(Some(PROBE_INTERVAL * 4))
+            .unwrap();
+        let mut buffer = vec![0; MAX_PACKET];
+        let deadline = Instant::now() + PROBE_INTERVAL * 2;
+        while fixture.client.recv(&mut buffer).is_ok() {
+            assert!(Instant::now() < deadline, "probes kept coming");
+        }
+    }
+}
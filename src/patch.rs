@@ -0,0 +1,1006 @@
+//! A small language for describing patches. Statements end at a `;` or the end of the line:
+//!
+//! ```text
+//! # Everything after # or // is a comment
+//! amp = ConstantAmplifier(0.5); tone = Tone(Resistance = 50000)
+//! amp.Output -> tone.Input
+//! tone.Output -> out.Audio
+//! tone.Resistance = 47000
+//! ```
+//!
+//! Models are made by name from a `ModelRegistry`. Arguments are given in order or by name,
+//! the ones left out take their defaults. `out` is the engine's output unless the patch names
+//! a component `out` itself.
+
+use std::{collections::HashMap, fmt};
+
+use crate::{
+    registry::{Context, ModelRegistry, RegistryError},
+    transaction::Edit,
+    Connection, ConnectionError, Engine, IOType, ModelHolder, Port, TransactionError,
+};
+
+/// Name the engine's output goes by in a patch
+pub const OUTPUT_NAME: &str = "out";
+
+/// Where something is in the source, both counting from 1
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// A parsed patch that can be added to an engine with `apply`
+pub struct Patch {
+    statements: Vec<Statement>,
+}
+
+//A name and where it was written
+type Name = (String, Position);
+
+enum Statement {
+    Model {
+        name: Name,
+        model: Name,
+        arguments: Vec<Argument>,
+    },
+    Connection {
+        from: (Name, Name),
+        to: (Name, Name),
+        at: Position,
+    },
+    Parameter {
+        component: Name,
+        name: Name,
+        value: f32,
+    },
+}
+
+struct Argument {
+    name: Option<Name>,
+    value: f32,
+    at: Position,
+}
+
+#[derive(Debug)]
+pub enum PatchError {
+    Syntax {
+        at: Position,
+        message: String,
+    },
+    DuplicateName {
+        at: Position,
+        name: String,
+    },
+    UnknownComponent {
+        at: Position,
+        name: String,
+        suggestion: Option<String>,
+    },
+    UnknownModel {
+        at: Position,
+        name: String,
+        suggestion: Option<String>,
+    },
+    UnknownArgument {
+        at: Position,
+        model: String,
+        argument: String,
+        suggestion: Option<String>,
+    },
+    TooManyArguments {
+        at: Position,
+        model: String,
+        expected: usize,
+    },
+    UnknownPort {
+        at: Position,
+        component: String,
+        port: String,
+        suggestion: Option<String>,
+    },
+    UnknownParameter {
+        at: Position,
+        component: String,
+        parameter: String,
+        suggestion: Option<String>,
+    },
+    /// The registry couldn't make the model
+    Model {
+        at: Position,
+        error: RegistryError,
+    },
+    Connection {
+        at: Position,
+        error: ConnectionError,
+    },
+    /// The connections form a loop, or the engine changed while the patch was applied
+    Transaction(TransactionError),
+}
+
+impl Patch {
+    pub fn parse(source: &str) -> Result<Self, PatchError> {
+        let mut parser = Parser {
+            tokens: lex(source)?,
+            position: 0,
+        };
+        let mut statements = Vec::new();
+        while let Some(statement) = parser.statement()? {
+            statements.push(statement);
+        }
+        Ok(Patch { statements })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.statements.is_empty()
+    }
+
+    /// Adds every component and connection of the patch to `engine` as one transaction, so
+    /// either all of it is added or none. Returns the IDs the components got by name.
+    pub fn apply(
+        &self,
+        engine: &mut Engine,
+        registry: &ModelRegistry,
+    ) -> Result<HashMap<String, usize>, PatchError> {
+        let mut names = HashMap::new();
+        self.apply_to(engine, registry, &mut names)?;
+        Ok(names)
+    }
+
+    /// Like `apply`, but the patch can also refer to components in `names` that are already
+    /// in the engine. New components are added to `names` if the patch applied.
+    pub fn apply_to(
+        &self,
+        engine: &mut Engine,
+        registry: &ModelRegistry,
+        names: &mut HashMap<String, usize>,
+    ) -> Result<(), PatchError> {
+        let context = Context {
+            sample_rate: engine.sample_rate(),
+            buffer_size: engine.buffer_size(),
+            status: engine.status_callback(),
+        };
+        let mut transaction = engine.transaction();
+        //Where every edit came from, to tell where a failed edit was written
+        let mut positions = Vec::new();
+        let mut added: HashMap<String, (usize, ModelHolder)> = HashMap::new();
+        for statement in self.statements.iter() {
+            let components = Components {
+                engine: &*engine,
+                names: &*names,
+                added: &added,
+            };
+            match statement {
+                Statement::Model {
+                    name: (name, at),
+                    model,
+                    arguments,
+                } => {
+                    if components.id(name).is_some() {
+                        return Err(PatchError::DuplicateName {
+                            at: *at,
+                            name: name.clone(),
+                        });
+                    }
+                    let arguments = resolve_arguments(registry, model, arguments)?;
+                    let holder = registry
+                        .create(&model.0, &arguments, &context)
+                        .map_err(|error| PatchError::Model { at: model.1, error })?;
+                    let id = transaction.add_model(holder.clone());
+                    transaction
+                        .edits
+                        .push(Edit::SetLabel(id, Some(name.clone())));
+                    positions.extend([model.1, model.1]);
+                    added.insert(name.clone(), (id, holder));
+                }
+                Statement::Connection { from, to, at } => {
+                    let from = components.port(from, false)?;
+                    let to = components.port(to, true)?;
+                    transaction.add_connection(Connection { from, to });
+                    positions.push(*at);
+                }
+                Statement::Parameter {
+                    component,
+                    name: (name, at),
+                    value,
+                } => {
+                    let (id, parameters) = components.parameters(component)?;
+                    if !parameters.contains_key(name) {
+                        return Err(PatchError::UnknownParameter {
+                            at: *at,
+                            component: component.0.clone(),
+                            parameter: name.clone(),
+                            suggestion: suggest(name, parameters.keys()),
+                        });
+                    }
+                    transaction
+                        .edits
+                        .push(Edit::SetParameter(id, name.clone(), *value));
+                    positions.push(*at);
+                }
+            }
+        }
+        engine.commit(transaction).map_err(|error| match error {
+            TransactionError::Edit { index, error } => PatchError::Connection {
+                at: positions[index],
+                error,
+            },
+            error => PatchError::Transaction(error),
+        })?;
+        names.extend(added.into_iter().map(|(name, (id, _))| (name, id)));
+        Ok(())
+    }
+}
+
+//The components a patch can refer to while it is applied
+struct Components<'a> {
+    engine: &'a Engine,
+    names: &'a HashMap<String, usize>,
+    added: &'a HashMap<String, (usize, ModelHolder)>,
+}
+
+impl Components<'_> {
+    fn id(&self, name: &str) -> Option<usize> {
+        match (self.added.get(name), self.names.get(name)) {
+            (Some((id, _)), _) | (None, Some(id)) => Some(*id),
+            _ if name == OUTPUT_NAME => Some(self.engine.output_id()),
+            _ => None,
+        }
+    }
+
+    fn unknown(&self, (name, at): &Name) -> PatchError {
+        let known = self
+            .added
+            .keys()
+            .chain(self.names.keys())
+            .map(|n| n.as_str())
+            .chain([OUTPUT_NAME]);
+        PatchError::UnknownComponent {
+            at: *at,
+            name: name.clone(),
+            suggestion: suggest(name, known),
+        }
+    }
+
+    fn format(
+        &self,
+        component: &Name,
+        input: bool,
+    ) -> Result<(usize, HashMap<String, IOType>), PatchError> {
+        if let Some((id, model)) = self.added.get(&component.0) {
+            let model = model.lock();
+            let format = if input {
+                model.input_format()
+            } else {
+                model.output_format()
+            };
+            return Ok((*id, format));
+        }
+        let info = self
+            .id(&component.0)
+            .and_then(|id| self.engine.component_info(id))
+            .ok_or_else(|| self.unknown(component))?;
+        let ports = if input { info.inputs } else { info.outputs };
+        Ok((info.id, ports.into_iter().map(|p| (p.name, p.io)).collect()))
+    }
+
+    fn port(
+        &self,
+        (component, (name, at)): &(Name, Name),
+        input: bool,
+    ) -> Result<Port, PatchError> {
+        let (id, mut format) = self.format(component, input)?;
+        match format.remove(name) {
+            Some(io) => Ok(Port {
+                id,
+                io,
+                name: name.clone(),
+            }),
+            None => Err(PatchError::UnknownPort {
+                at: *at,
+                component: component.0.clone(),
+                port: name.clone(),
+                suggestion: suggest(name, format.keys()),
+            }),
+        }
+    }
+
+    fn parameters(&self, component: &Name) -> Result<(usize, HashMap<String, f32>), PatchError> {
+        if let Some((id, model)) = self.added.get(&component.0) {
+            return Ok((*id, model.lock().parameters()));
+        }
+        self.id(&component.0)
+            .and_then(|id| Some((id, self.engine.parameters(id)?)))
+            .ok_or_else(|| self.unknown(component))
+    }
+}
+
+//Puts named arguments in their place and checks there aren't too many
+fn resolve_arguments(
+    registry: &ModelRegistry,
+    (model, at): &Name,
+    arguments: &[Argument],
+) -> Result<Vec<f32>, PatchError> {
+    let declared = registry
+        .arguments(model)
+        .ok_or_else(|| PatchError::UnknownModel {
+            at: *at,
+            name: model.clone(),
+            suggestion: suggest(model, registry.names()),
+        })?;
+    let mut values: Vec<Option<f32>> = vec![None; declared.len()];
+    for (index, argument) in arguments.iter().enumerate() {
+        let slot = match &argument.name {
+            None if index < declared.len() => index,
+            None => {
+                return Err(PatchError::TooManyArguments {
+                    at: argument.at,
+                    model: model.clone(),
+                    expected: declared.len(),
+                })
+            }
+            Some((name, at)) => declared
+                .iter()
+                .position(|(declared, _)| declared == name)
+                .ok_or_else(|| PatchError::UnknownArgument {
+                    at: *at,
+                    model: model.clone(),
+                    argument: name.clone(),
+                    suggestion: suggest(name, declared.iter().map(|(n, _)| n)),
+                })?,
+        };
+        values[slot] = Some(argument.value);
+    }
+    Ok(values
+        .into_iter()
+        .zip(declared.iter())
+        .map(|(value, (_, default))| value.unwrap_or(*default))
+        .collect())
+}
+
+/// The closest candidate to `name` if it is close enough to be a typo
+pub fn suggest<S: AsRef<str>>(
+    name: &str,
+    candidates: impl IntoIterator<Item = S>,
+) -> Option<String> {
+    let lowercase = name.to_lowercase();
+    candidates
+        .into_iter()
+        .map(|c| {
+            let distance = edit_distance(&lowercase, &c.as_ref().to_lowercase());
+            (distance, c.as_ref().to_string())
+        })
+        .filter(|(distance, _)| {
+            *distance < name.chars().count() && *distance <= usize::max(2, name.chars().count() / 3)
+        })
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, b) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(a != *b);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Identifier(String),
+    Number(f32),
+    Arrow,
+    Equals,
+    Dot,
+    Comma,
+    Open,
+    Close,
+    //A `;` or a line break
+    End,
+    Eof,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Identifier(name) => write!(f, "`{}`", name),
+            Token::Number(value) => write!(f, "`{}`", value),
+            Token::Arrow => write!(f, "`->`"),
+            Token::Equals => write!(f, "`=`"),
+            Token::Dot => write!(f, "`.`"),
+            Token::Comma => write!(f, "`,`"),
+            Token::Open => write!(f, "`(`"),
+            Token::Close => write!(f, "`)`"),
+            Token::End => write!(f, "the end of the statement"),
+            Token::Eof => write!(f, "the end of the patch"),
+        }
+    }
+}
+
+fn lex(source: &str) -> Result<Vec<(Token, Position)>, PatchError> {
+    let mut tokens = Vec::new();
+    for (index, line) in source.lines().enumerate() {
+        let chars: Vec<char> = line.chars().collect();
+        let mut column = 0;
+        while column < chars.len() {
+            let at = Position {
+                line: index + 1,
+                column: column + 1,
+            };
+            let c = chars[column];
+            let next = chars.get(column + 1).copied();
+            let token = match c {
+                _ if c.is_whitespace() => {
+                    column += 1;
+                    continue;
+                }
+                '#' => break,
+                '/' if next == Some('/') => break,
+                '-' if next == Some('>') => {
+                    column += 2;
+                    Token::Arrow
+                }
+                _ if is_number_start(c, next) => {
+                    let start = column;
+                    column += 1;
+                    while column < chars.len()
+                        && (chars[column].is_ascii_digit()
+                            || matches!(chars[column], '.' | 'e' | 'E')
+                            || (matches!(chars[column], '-' | '+')
+                                && matches!(chars[column - 1], 'e' | 'E')))
+                    {
+                        column += 1;
+                    }
+                    let text: String = chars[start..column].iter().collect();
+                    Token::Number(text.parse().map_err(|_| PatchError::Syntax {
+                        at,
+                        message: format!("`{}` is not a number", text),
+                    })?)
+                }
+                _ if c.is_alphabetic() || c == '_' => {
+                    let start = column;
+                    while column < chars.len()
+                        && (chars[column].is_alphanumeric() || chars[column] == '_')
+                    {
+                        column += 1;
+                    }
+                    Token::Identifier(chars[start..column].iter().collect())
+                }
+                _ => {
+                    column += 1;
+                    match c {
+                        '=' => Token::Equals,
+                        '.' => Token::Dot,
+                        ',' => Token::Comma,
+                        '(' => Token::Open,
+                        ')' => Token::Close,
+                        ';' => Token::End,
+                        _ => {
+                            return Err(PatchError::Syntax {
+                                at,
+                                message: format!("unexpected `{}`", c),
+                            })
+                        }
+                    }
+                }
+            };
+            tokens.push((token, at));
+        }
+        tokens.push((
+            Token::End,
+            Position {
+                line: index + 1,
+                column: chars.len() + 1,
+            },
+        ));
+    }
+    let end = match tokens.last() {
+        Some((_, at)) => *at,
+        None => Position { line: 1, column: 1 },
+    };
+    tokens.push((Token::Eof, end));
+    Ok(tokens)
+}
+
+//Numbers start with a digit, or a sign followed by a digit or a point
+fn is_number_start(c: char, next: Option<char>) -> bool {
+    match c {
+        '0'..='9' => true,
+        '-' | '+' => next.is_some_and(|n| n.is_ascii_digit() || n == '.'),
+        _ => false,
+    }
+}
+
+struct Parser {
+    tokens: Vec<(Token, Position)>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &(Token, Position) {
+        &self.tokens[self.position]
+    }
+
+    fn next(&mut self) -> (Token, Position) {
+        let token = self.tokens[self.position].clone();
+        if token.0 != Token::Eof {
+            self.position += 1;
+        }
+        token
+    }
+
+    fn expected(&self, what: &str) -> PatchError {
+        let (found, at) = self.peek();
+        PatchError::Syntax {
+            at: *at,
+            message: format!("expected {}, found {}", what, found),
+        }
+    }
+
+    fn expect(&mut self, token: Token, what: &str) -> Result<(), PatchError> {
+        if self.peek().0 == token {
+            self.next();
+            Ok(())
+        } else {
+            Err(self.expected(what))
+        }
+    }
+
+    fn identifier(&mut self, what: &str) -> Result<Name, PatchError> {
+        match self.peek().clone() {
+            (Token::Identifier(name), at) => {
+                self.next();
+                Ok((name, at))
+            }
+            _ => Err(self.expected(what)),
+        }
+    }
+
+    fn number(&mut self, what: &str) -> Result<f32, PatchError> {
+        match self.peek().0 {
+            Token::Number(value) => {
+                self.next();
+                Ok(value)
+            }
+            _ => Err(self.expected(what)),
+        }
+    }
+
+    fn statement(&mut self) -> Result<Option<Statement>, PatchError> {
+        while self.peek().0 == Token::End {
+            self.next();
+        }
+        if self.peek().0 == Token::Eof {
+            return Ok(None);
+        }
+        let first = self.identifier("a component name")?;
+        let statement = match self.peek().0 {
+            Token::Equals => {
+                self.next();
+                let model = self.identifier("a model name after `=`")?;
+                let arguments = if self.peek().0 == Token::Open {
+                    self.next();
+                    self.arguments()?
+                } else {
+                    Vec::new()
+                };
+                Statement::Model {
+                    name: first,
+                    model,
+                    arguments,
+                }
+            }
+            Token::Dot => {
+                self.next();
+                let port = self.identifier(&format!("a port or parameter of `{}`", first.0))?;
+                match self.peek().0 {
+                    Token::Arrow => {
+                        let at = self.next().1;
+                        let to = self.port()?;
+                        Statement::Connection {
+                            from: (first, port),
+                            to,
+                            at,
+                        }
+                    }
+                    Token::Equals => {
+                        self.next();
+                        let value = self.number("a number after `=`")?;
+                        Statement::Parameter {
+                            component: first,
+                            name: port,
+                            value,
+                        }
+                    }
+                    _ => {
+                        return Err(
+                            self.expected(&format!("`->` or `=` after `{}.{}`", first.0, port.0))
+                        )
+                    }
+                }
+            }
+            _ => return Err(self.expected(&format!("`=` or `.` after `{}`", first.0))),
+        };
+        match self.peek().0 {
+            Token::End | Token::Eof => Ok(Some(statement)),
+            _ => Err(self.expected("`;` or a new line after the statement")),
+        }
+    }
+
+    fn port(&mut self) -> Result<(Name, Name), PatchError> {
+        let component = self.identifier("a component name after `->`")?;
+        self.expect(Token::Dot, &format!("`.` after `{}`", component.0))?;
+        let port = self.identifier(&format!("an input of `{}`", component.0))?;
+        Ok((component, port))
+    }
+
+    fn arguments(&mut self) -> Result<Vec<Argument>, PatchError> {
+        let mut arguments: Vec<Argument> = Vec::new();
+        if self.peek().0 == Token::Close {
+            self.next();
+            return Ok(arguments);
+        }
+        loop {
+            let at = self.peek().1;
+            let argument = match self.peek().0.clone() {
+                Token::Identifier(name) => {
+                    self.next();
+                    self.expect(Token::Equals, &format!("`=` after `{}`", name))?;
+                    Argument {
+                        name: Some((name, at)),
+                        value: self.number("a number")?,
+                        at,
+                    }
+                }
+                Token::Number(value) => {
+                    if arguments.iter().any(|a| a.name.is_some()) {
+                        return Err(PatchError::Syntax {
+                            at,
+                            message: String::from(
+                                "arguments without a name have to come before named ones",
+                            ),
+                        });
+                    }
+                    self.next();
+                    Argument {
+                        name: None,
+                        value,
+                        at,
+                    }
+                }
+                _ => return Err(self.expected("an argument")),
+            };
+            arguments.push(argument);
+            match self.peek().0 {
+                Token::Comma => {
+                    self.next();
+                }
+                Token::Close => {
+                    self.next();
+                    return Ok(arguments);
+                }
+                _ => return Err(self.expected("`,` or `)`")),
+            }
+        }
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+impl PatchError {
+    /// Where in the source the error is, if it is about one spot
+    pub fn position(&self) -> Option<Position> {
+        match self {
+            PatchError::Syntax { at, .. }
+            | PatchError::DuplicateName { at, .. }
+            | PatchError::UnknownComponent { at, .. }
+            | PatchError::UnknownModel { at, .. }
+            | PatchError::UnknownArgument { at, .. }
+            | PatchError::TooManyArguments { at, .. }
+            | PatchError::UnknownPort { at, .. }
+            | PatchError::UnknownParameter { at, .. }
+            | PatchError::Model { at, .. }
+            | PatchError::Connection { at, .. } => Some(*at),
+            PatchError::Transaction(_) => None,
+        }
+    }
+
+    /// The error with its position, the line it is on and a marker under the spot
+    pub fn report(&self, source: &str) -> String {
+        let at = match self.position() {
+            Some(at) => at,
+            None => return self.to_string(),
+        };
+        let line = source.lines().nth(at.line - 1).unwrap_or_default();
+        let gutter = at.line.to_string();
+        format!(
+            "{}: {}\n{} | {}\n{} | {}^",
+            at,
+            self,
+            gutter,
+            line,
+            " ".repeat(gutter.len()),
+            " ".repeat(at.column - 1)
+        )
+    }
+}
+
+//Ends a message with ", did you mean `x`?" when there is a suggestion
+fn did_you_mean(f: &mut fmt::Formatter<'_>, suggestion: &Option<String>) -> fmt::Result {
+    match suggestion {
+        Some(suggestion) => write!(f, ", did you mean `{}`?", suggestion),
+        None => Ok(()),
+    }
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatchError::Syntax { message, .. } => write!(f, "{}", message),
+            PatchError::DuplicateName { name, .. } => write!(f, "`{}` is already defined", name),
+            PatchError::UnknownComponent {
+                name, suggestion, ..
+            } => {
+                write!(f, "there is no component called `{}`", name)?;
+                did_you_mean(f, suggestion)
+            }
+            PatchError::UnknownModel {
+                name, suggestion, ..
+            } => {
+                write!(f, "unknown model `{}`", name)?;
+                did_you_mean(f, suggestion)
+            }
+            PatchError::UnknownArgument {
+                model,
+                argument,
+                suggestion,
+                ..
+            } => {
+                write!(f, "`{}` has no argument `{}`", model, argument)?;
+                did_you_mean(f, suggestion)
+            }
+            PatchError::TooManyArguments {
+                model, expected, ..
+            } => write!(f, "`{}` takes at most {} arguments", model, expected),
+            PatchError::UnknownPort {
+                component,
+                port,
+                suggestion,
+                ..
+            } => {
+                write!(f, "`{}` has no port `{}`", component, port)?;
+                did_you_mean(f, suggestion)
+            }
+            PatchError::UnknownParameter {
+                component,
+                parameter,
+                suggestion,
+                ..
+            } => {
+                write!(f, "`{}` has no parameter `{}`", component, parameter)?;
+                did_you_mean(f, suggestion)
+            }
+            PatchError::Model { error, .. } => match error {
+                RegistryError::UnknownModel(model) => write!(f, "unknown model `{}`", model),
+                RegistryError::TooManyArguments {
+                    model, expected, ..
+                } => write!(f, "`{}` takes at most {} arguments", model, expected),
+                RegistryError::Failed { model, message } => {
+                    write!(f, "can't create `{}`: {}", model, message)
+                }
+            },
+            PatchError::Connection { error, .. } => match error {
+                ConnectionError::LoopingConnection => {
+                    write!(f, "this connection would make a loop")
+                }
+                ConnectionError::ControllerNotInGraph => {
+                    write!(f, "the component isn't in the graph anymore")
+                }
+                ConnectionError::InputOccupied => write!(f, "the input is already connected"),
+                ConnectionError::MismatchedConnectionTypes => {
+                    write!(f, "can't connect a voltage port to a MIDI port")
+                }
+                ConnectionError::InputNotInComponent => write!(f, "there is no such input"),
+                ConnectionError::OutputNotInComponent => write!(f, "there is no such output"),
+            },
+            PatchError::Transaction(TransactionError::LoopingConnection) => {
+                write!(f, "the connections make a loop")
+            }
+            PatchError::Transaction(TransactionError::Stale) => {
+                write!(f, "the graph changed while the patch was applied")
+            }
+            PatchError::Transaction(error) => write!(f, "{:?}", error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backend::NullBackend,
+        builder::IntoHolder,
+        ports::{Jack, Process},
+        Config, Model,
+    };
+
+    //A model with nothing but a MIDI output
+    #[derive(Model)]
+    struct Keys {
+        #[output(midi)]
+        midi: Jack,
+    }
+
+    impl Process for Keys {
+        fn process(&mut self, _ports: KeysPorts, _config: &Config) {}
+    }
+
+    fn engine() -> Engine {
+        Engine::with_backend(Box::new(NullBackend::new(48000, 1, 64))).0
+    }
+
+    fn registry() -> ModelRegistry {
+        let mut registry = ModelRegistry::with_builtins();
+        registry.register("Keys", &[], |_, _| Ok(Keys { midi: Jack }.into_holder()));
+        registry
+    }
+
+    fn apply(source: &str) -> Result<HashMap<String, usize>, PatchError> {
+        Patch::parse(source)?.apply(&mut engine(), &registry())
+    }
+
+    fn at(line: usize, column: usize) -> Option<Position> {
+        Some(Position { line, column })
+    }
+
+    #[test]
+    fn applies_models_connections_and_parameters() {
+        let mut engine = engine();
+        let source = "amp = ConstantAmplifier(0.5); tone = Tone(Resistance = 50000)\n\
+                      amp.Output -> tone.Input\n\
+                      # a comment\n\
+                      tone.Output -> out.Audio // another\n\
+                      tone.Resistance = 47000\n";
+        let names = Patch::parse(source)
+            .unwrap()
+            .apply(&mut engine, &registry())
+            .unwrap();
+        assert_eq!(names.len(), 2);
+        assert_eq!(engine.parameters(names["amp"]).unwrap()["Gain"], 0.5);
+        assert_eq!(
+            engine.parameters(names["tone"]).unwrap()["Resistance"],
+            47000.
+        );
+        assert_eq!(engine.connections().len(), 2);
+        assert_eq!(
+            engine.upstream(engine.output_id()).unwrap(),
+            vec![names["tone"]]
+        );
+    }
+
+    #[test]
+    fn syntax_errors_point_at_the_token() {
+        let error = Patch::parse("amp = ConstantAmplifier(0.5").err().unwrap();
+        assert_eq!(error.position(), at(1, 28));
+        assert_eq!(
+            error.to_string(),
+            "expected `,` or `)`, found the end of the statement"
+        );
+
+        let error = Patch::parse("\namp.Output -> out.Audio $").err().unwrap();
+        assert_eq!(error.position(), at(2, 25));
+        assert_eq!(error.to_string(), "unexpected `$`");
+
+        let error = Patch::parse("amp.Output out.Audio").err().unwrap();
+        assert_eq!(error.position(), at(1, 12));
+
+        let error = Patch::parse("amp = ConstantAmplifier(Gain = 1, 2)")
+            .err()
+            .unwrap();
+        assert_eq!(error.position(), at(1, 35));
+    }
+
+    #[test]
+    fn reports_mark_the_spot() {
+        let source = "amp = ConstantAmplifier\namp.Outptu -> out.Audio";
+        let error = apply(source).err().unwrap();
+        assert_eq!(
+            error.report(source),
+            "2:5: `amp` has no port `Outptu`, did you mean `Output`?\n\
+             2 | amp.Outptu -> out.Audio\n  |     ^"
+        );
+    }
+
+    #[test]
+    fn unknown_names_get_suggestions() {
+        match apply("amp = ConstantAmplifer(0.5)").err().unwrap() {
+            PatchError::UnknownModel { at, suggestion, .. } => {
+                assert_eq!(Some(at), self::at(1, 7));
+                assert_eq!(suggestion.as_deref(), Some("ConstantAmplifier"));
+            }
+            error => panic!("unexpected {:?}", error),
+        }
+        match apply("amp = ConstantAmplifier(Gian = 2)").err().unwrap() {
+            PatchError::UnknownArgument { at, suggestion, .. } => {
+                assert_eq!(Some(at), self::at(1, 25));
+                assert_eq!(suggestion.as_deref(), Some("Gain"));
+            }
+            error => panic!("unexpected {:?}", error),
+        }
+        match apply("amp = ConstantAmplifier\napm.Output -> out.Audio")
+            .err()
+            .unwrap()
+        {
+            PatchError::UnknownComponent { at, suggestion, .. } => {
+                assert_eq!(Some(at), self::at(2, 1));
+                assert_eq!(suggestion.as_deref(), Some("amp"));
+            }
+            error => panic!("unexpected {:?}", error),
+        }
+        match apply("tone = Tone\ntone.Resistence = 3").err().unwrap() {
+            PatchError::UnknownParameter { at, suggestion, .. } => {
+                assert_eq!(Some(at), self::at(2, 6));
+                assert_eq!(suggestion.as_deref(), Some("Resistance"));
+            }
+            error => panic!("unexpected {:?}", error),
+        }
+        match apply("amp = ConstantAmplifier\namp.Output -> out.Volume")
+            .err()
+            .unwrap()
+        {
+            PatchError::UnknownPort { suggestion, .. } => assert_eq!(suggestion, None),
+            error => panic!("unexpected {:?}", error),
+        }
+    }
+
+    #[test]
+    fn connection_errors_point_at_the_arrow() {
+        let mut engine = engine();
+        let source = "a = ConstantAmplifier\nb = ConstantAmplifier\n\
+                      a.Output -> out.Audio\nb.Output -> out.Audio";
+        match Patch::parse(source)
+            .unwrap()
+            .apply(&mut engine, &registry())
+            .err()
+            .unwrap()
+        {
+            PatchError::Connection { at, error } => {
+                assert_eq!(Some(at), self::at(4, 10));
+                assert!(matches!(error, ConnectionError::InputOccupied));
+            }
+            error => panic!("unexpected {:?}", error),
+        }
+        //Nothing of a patch that failed is added
+        assert_eq!(engine.components(), vec![engine.output_id()]);
+    }
+
+    #[test]
+    fn midi_outputs_cannot_feed_voltage_inputs() {
+        let mut engine = engine();
+        let source = "keys = Keys\nkeys.Midi -> out.Audio";
+        match Patch::parse(source)
+            .unwrap()
+            .apply(&mut engine, &registry())
+            .err()
+            .unwrap()
+        {
+            PatchError::Connection { at, error } => {
+                assert_eq!(Some(at), self::at(2, 11));
+                assert!(matches!(error, ConnectionError::MismatchedConnectionTypes));
+            }
+            error => panic!("unexpected {:?}", error),
+        }
+        assert!(engine.connections().is_empty());
+    }
+}
@@ -0,0 +1,165 @@
+//! What `#[derive(Model)]` builds on.
+//!
+//! The derive writes `input_format`, `output_format`, `parameters` and `set_parameter` from
+//! the struct's fields and hands the model a struct with the buffers of its ports:
+//!
+//! ```
+//! # use proto::{ports::{Jack, Process}, testing::ModelTester, Config, Model};
+//! #[derive(Model)]
+//! pub struct Vca {
+//!     #[input(voltage)]
+//!     input: Jack,
+//!     #[input(voltage)]
+//!     control: Jack,
+//!     #[output(voltage)]
+//!     output: Jack,
+//!     #[parameter(min = 0.0)]
+//!     gain: f32,
+//! }
+//!
+//! impl Process for Vca {
+//!     fn process(&mut self, ports: VcaPorts, _config: &Config) {
+//!         for (i, output) in ports.output.iter_mut().enumerate() {
+//!             *output = ports.input[i] * ports.control[i] * self.gain;
+//!         }
+//!     }
+//! }
+//! # let vca = Vca { input: Jack, control: Jack, output: Jack, gain: 2. };
+//! # let mut tester = ModelTester::new(vca, 48000, 64);
+//! # tester.constant("Input", 0.5).constant("Control", 0.5).run_blocks(1);
+//! # tester.output("Output").assert_peak(0.5, 1e-6);
+//! ```
+
+use crate::Config;
+
+/// The type of port fields of a `#[derive(Model)]` struct. It holds nothing, the buffers of
+/// the ports are handed to `Process::process`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Jack;
+
+/// A parameter of a `#[derive(Model)]` model, listed in its `PARAMETERS`. Values set from
+/// outside are clamped to `min..=max`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ParameterInfo {
+    pub name: &'static str,
+    pub min: f32,
+    pub max: f32,
+}
+
+/// Implemented by `#[derive(Model)]`, `Ports` is the generated `<Name>Ports` struct.
+pub trait HasPorts {
+    type Ports<'a>;
+}
+
+/// The DSP of a `#[derive(Model)]` model.
+pub trait Process: HasPorts {
+    /// Fills the outputs in `ports` from its inputs, like `Model::evaluate`
+    fn process(&mut self, ports: Self::Ports<'_>, config: &Config);
+
+    /// See `Model::reset`
+    fn reset(&mut self) {}
+
+    /// See `Model::next_event`
+    fn next_event(&self, _time: u64, _frames: usize) -> Option<usize> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        panic::{self, AssertUnwindSafe},
+        sync::Arc,
+    };
+
+    use super::*;
+    use crate::{diagnostics::Diagnostics, IOType, Input, Model, Output};
+
+    #[derive(Model)]
+    struct Broken {
+        #[output(voltage)]
+        left: Jack,
+        #[output(voltage)]
+        right: Jack,
+        #[output(midi)]
+        midi: Jack,
+    }
+
+    impl Process for Broken {
+        fn process(&mut self, ports: BrokenPorts, _config: &Config) {
+            ports.left.fill(1.);
+            panic!("the model broke");
+        }
+    }
+
+    #[test]
+    fn outputs_stay_in_place_when_a_model_panics() {
+        let (voltages, midi_events) = (HashMap::new(), HashMap::new());
+        let config = Config {
+            buffer_size: 64,
+            delta: 1. / 48000.,
+            time: 0,
+            diagnostics: Arc::new(Diagnostics::new()),
+        };
+        let mut model = Broken {
+            left: Jack,
+            right: Jack,
+            midi: Jack,
+        };
+        let mut output = Output::new(model.output_format(), 64);
+        let evaluated = panic::catch_unwind(AssertUnwindSafe(|| {
+            let input = Input {
+                voltages: &voltages,
+                midi_events: &midi_events,
+            };
+            model.evaluate(64, input, &mut output, &config)
+        }));
+        assert!(evaluated.is_err());
+        //Downstream components read full blocks, with what the model wrote before it panicked
+        assert_eq!(output.voltages["Left"], vec![1.; 64]);
+        assert_eq!(output.voltages["Right"].len(), 64);
+        assert_eq!(output.midi_events["Midi"].len(), 64);
+    }
+
+    #[test]
+    fn models_missing_a_port_are_left_out() {
+        let mut model = Broken {
+            left: Jack,
+            right: Jack,
+            midi: Jack,
+        };
+        //No `Right`, so the model that would panic is never processed
+        let mut output = Output::new(HashMap::from([(String::from("Left"), IOType::Voltage)]), 64);
+        let (voltages, midi_events) = (HashMap::new(), HashMap::new());
+        let config = Config {
+            buffer_size: 64,
+            delta: 1. / 48000.,
+            time: 0,
+            diagnostics: Arc::new(Diagnostics::new()),
+        };
+        let input = Input {
+            voltages: &voltages,
+            midi_events: &midi_events,
+        };
+        model.evaluate(64, input, &mut output, &config);
+        assert_eq!(output.voltages["Left"], vec![0.; 64]);
+    }
+
+    #[test]
+    fn output_buffers_are_lent_once_each() {
+        let mut output = Output::new(
+            Broken::OUTPUTS
+                .iter()
+                .map(|(n, io)| (n.to_string(), io.clone()))
+                .collect(),
+            8,
+        );
+        let ([left, again, missing], [midi]) =
+            output.buffers_mut(["Left", "Left", "Nope"], ["Midi"]);
+        assert_eq!(left.map(|b| b.len()), Some(8));
+        assert!(again.is_none());
+        assert!(missing.is_none());
+        assert_eq!(midi.map(|b| b.len()), Some(8));
+    }
+}
@@ -0,0 +1,187 @@
+use std::{collections::HashMap, sync::Arc};
+
+use parking_lot::Mutex;
+
+use crate::{
+    backend::StatusCallback,
+    model_utils::{ConstantAmplifier, DuoSignalMixer, Tone, Vca},
+    ModelHolder,
+};
+
+/// What a factory gets to know about the engine the model is made for
+#[derive(Clone)]
+pub struct Context {
+    pub sample_rate: usize,
+    pub buffer_size: usize,
+    /// Reports to the engine's `status_events`, for models with streams of their own
+    pub status: StatusCallback,
+}
+
+type Factory = Box<dyn Fn(&[f32], &Context) -> Result<ModelHolder, String> + Send + Sync>;
+
+struct Entry {
+    arguments: Vec<(String, f32)>,
+    factory: Factory,
+}
+
+#[derive(Debug)]
+pub enum RegistryError {
+    UnknownModel(String),
+    TooManyArguments {
+        model: String,
+        expected: usize,
+        got: usize,
+    },
+    /// The factory couldn't make the model, like an `AudioInput` without an input device
+    Failed {
+        model: String,
+        message: String,
+    },
+}
+
+/// Makes models by name so patches can be written without Rust, see `patch::Patch`.
+pub struct ModelRegistry {
+    entries: HashMap<String, Entry>,
+}
+
+impl ModelRegistry {
+    pub fn new() -> Self {
+        ModelRegistry {
+            entries: HashMap::new(),
+        }
+    }
+
+    /// A registry with every model in `model_utils`, and `AudioInput` recording from the
+    /// default input device when cpal is enabled.
+    pub fn with_builtins() -> Self {
+        let mut registry = ModelRegistry::new();
+        registry.register("ConstantAmplifier", &[("Gain", 1.0)], |args, _| {
+            Ok(holder(ConstantAmplifier::new(args[0])))
+        });
+        registry.register(
+            "DuoSignalMixer",
+            &[("Input1Gain", 1.0), ("Input2Gain", 1.0)],
+            |args, _| Ok(holder(DuoSignalMixer::new(args[0], args[1]))),
+        );
+        registry.register("Vca", &[], |_, _| Ok(holder(Vca::new())));
+        registry.register("Tone", &[("Resistance", 100_000.)], |args, _| {
+            Ok(Tone::new(args[0]))
+        });
+        #[cfg(feature = "cpal")]
+        registry.register("AudioInput", &[], |_, context| {
+            crate::audio_io::AudioInput::new(
+                context.sample_rate,
+                context.buffer_size,
+                context.status.clone(),
+            )
+            .map_err(|e| format!("{:?}", e))
+        });
+        registry
+    }
+
+    /// Registers `factory` under `name`, replacing what was there. `arguments` are the names
+    /// and default values of the numbers the factory takes, missing ones are filled in with
+    /// the defaults before the factory is called. Arguments that set a parameter should be
+    /// named like it, so patches spell the knob the same way when making and setting it.
+    pub fn register<F>(&mut self, name: &str, arguments: &[(&str, f32)], factory: F)
+    where
+        F: Fn(&[f32], &Context) -> Result<ModelHolder, String> + Send + Sync + 'static,
+    {
+        self.entries.insert(
+            name.to_string(),
+            Entry {
+                arguments: arguments
+                    .iter()
+                    .map(|(name, default)| (name.to_string(), *default))
+                    .collect(),
+                factory: Box::new(factory),
+            },
+        );
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.entries.contains_key(name)
+    }
+
+    /// Every registered model name, sorted
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.entries.keys().map(|n| n.as_str()).collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// The names and default values of the arguments a model takes
+    pub fn arguments(&self, name: &str) -> Option<&[(String, f32)]> {
+        self.entries.get(name).map(|e| e.arguments.as_slice())
+    }
+
+    pub fn create(
+        &self,
+        name: &str,
+        arguments: &[f32],
+        context: &Context,
+    ) -> Result<ModelHolder, RegistryError> {
+        let entry = self
+            .entries
+            .get(name)
+            .ok_or_else(|| RegistryError::UnknownModel(name.to_string()))?;
+        if arguments.len() > entry.arguments.len() {
+            return Err(RegistryError::TooManyArguments {
+                model: name.to_string(),
+                expected: entry.arguments.len(),
+                got: arguments.len(),
+            });
+        }
+        let mut filled = arguments.to_vec();
+        filled.extend(
+            entry.arguments[arguments.len()..]
+                .iter()
+                .map(|(_, default)| *default),
+        );
+        (entry.factory)(&filled, context).map_err(|message| RegistryError::Failed {
+            model: name.to_string(),
+            message,
+        })
+    }
+}
+
+impl Default for ModelRegistry {
+    fn default() -> Self {
+        ModelRegistry::new()
+    }
+}
+
+fn holder(model: impl crate::Model + Send + Sync + 'static) -> ModelHolder {
+    Arc::new(Mutex::new(Box::new(model)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_arguments_are_named_like_parameters() {
+        let registry = ModelRegistry::with_builtins();
+        let context = Context {
+            sample_rate: 48000,
+            buffer_size: 64,
+            status: Arc::new(|_| {}),
+        };
+        for name in registry.names() {
+            //AudioInput needs an input device
+            let Ok(model) = registry.create(name, &[], &context) else {
+                continue;
+            };
+            let parameters = model.lock().parameters();
+            for (argument, _) in registry.arguments(name).unwrap() {
+                assert!(
+                    parameters.contains_key(argument),
+                    "{} takes `{}`, which isn't one of its parameters {:?}",
+                    name,
+                    argument,
+                    parameters.keys()
+                );
+            }
+        }
+    }
+}
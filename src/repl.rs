@@ -0,0 +1,492 @@
+//! An interactive prompt for patching a running engine.
+//!
+//! Anything that isn't a command is parsed as patch statements, see `patch`, so components
+//! are added with `amp = ConstantAmplifier(0.5)`, connected with `amp.Output -> out.Audio` and
+//! tweaked with `amp.Gain = 0.25`. Components are named by their labels.
+
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    thread,
+    time::{Duration, Instant},
+};
+
+use rustyline::{
+    completion::Completer, error::ReadlineError, highlight::Highlighter, hint::Hinter,
+    history::DefaultHistory, validate::Validator, Editor,
+};
+
+use crate::{
+    export::short_type_name,
+    patch::{Patch, OUTPUT_NAME},
+    registry::ModelRegistry,
+    Engine, IOType,
+};
+
+const PROMPT: &str = "proto> ";
+const METER_INTERVAL: Duration = Duration::from_millis(50);
+const METER_WIDTH: usize = 30;
+//Lowest level a meter shows, in dB
+const METER_FLOOR: f32 = -60.;
+
+const COMMANDS: [&str; 12] = [
+    "help",
+    "list",
+    "ports",
+    "disconnect",
+    "remove",
+    "reset",
+    "meter",
+    "undo",
+    "redo",
+    "dot",
+    "stats",
+    "quit",
+];
+
+const HELP: &str = "\
+Commands:
+  list                              Components with their model and ID
+  ports <name>                      Ports, connections and parameters of a component
+  disconnect <a.Output> <b.Input>   Removes a connection
+  remove <name>                     Removes a component
+  reset <name>                      Resets a component and clears its fault
+  meter <name.Input>... [seconds]   Shows the level at inputs, 5 seconds by default
+  undo, redo                        Steps through the edit history
+  dot                               Prints the patch as a Graphviz digraph
+  stats                             DSP load, xruns and faults
+  quit                              Leaves the prompt
+Anything else is a patch statement:
+  amp = ConstantAmplifier(0.5)      Adds a component
+  amp.Output -> out.Audio           Connects an output to an input
+  amp.Gain = 0.25                   Sets a parameter
+Tab completes commands, component names, model names and ports.";
+
+/// A prompt that edits `engine` until `quit` or end of input.
+pub struct Repl<'a> {
+    engine: &'a mut Engine,
+    registry: ModelRegistry,
+}
+
+impl<'a> Repl<'a> {
+    pub fn new(engine: &'a mut Engine, registry: ModelRegistry) -> Self {
+        Repl { engine, registry }
+    }
+
+    /// Reads and runs lines from the terminal until `quit`, Ctrl-D or Ctrl-C
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut editor: Editor<Completion, DefaultHistory> =
+            Editor::new().map_err(readline_error)?;
+        editor.set_helper(Some(self.completion()));
+        let mut stdout = io::stdout();
+        loop {
+            let line = match editor.readline(PROMPT) {
+                Ok(line) => line,
+                Err(ReadlineError::Eof | ReadlineError::Interrupted) => return Ok(()),
+                Err(e) => return Err(readline_error(e)),
+            };
+            if !line.trim().is_empty() {
+                let _ = editor.add_history_entry(line.as_str());
+            }
+            if !self.execute(&line, &mut stdout)? {
+                return Ok(());
+            }
+            editor.set_helper(Some(self.completion()));
+        }
+    }
+
+    /// Runs one line, writing what it prints and any error to `out`. Returns false once the
+    /// line asked to quit.
+    pub fn execute(&mut self, line: &str, out: &mut dyn Write) -> io::Result<bool> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let is_statement = line.contains('=') || line.contains("->");
+        let result = match words.as_slice() {
+            [] => Ok(()),
+            ["quit" | "exit"] => return Ok(false),
+            ["help"] => writeln!(out, "{}", HELP).map_err(Error::Io),
+            ["list"] => self.list(out),
+            ["ports", name] => self.ports(name, out),
+            ["disconnect", from, to] => self.disconnect(from, to),
+            ["remove", name] => self.remove(name),
+            ["reset", name] => self.reset(name),
+            ["meter", targets @ ..] if !targets.is_empty() => self.meter(targets, out),
+            ["undo"] => self.step(true),
+            ["redo"] => self.step(false),
+            ["dot"] => write!(out, "{}", self.engine.to_dot()).map_err(Error::Io),
+            ["stats"] => self.stats(out),
+            [command, ..] if !is_statement && COMMANDS.contains(command) => Err(Error::Message(
+                format!("wrong arguments for `{}`, see `help`", command),
+            )),
+            _ => self.statements(line, out),
+        };
+        match result {
+            Ok(()) => Ok(true),
+            Err(Error::Io(e)) => Err(e),
+            Err(Error::Message(message)) => {
+                writeln!(out, "error: {}", message)?;
+                Ok(true)
+            }
+        }
+    }
+
+    //Components by label, and the output as `out`
+    fn names(&self) -> HashMap<String, usize> {
+        let mut names: HashMap<String, usize> = self
+            .engine
+            .components()
+            .into_iter()
+            .filter_map(|id| Some((self.engine.component_info(id)?.label?, id)))
+            .collect();
+        names
+            .entry(String::from(OUTPUT_NAME))
+            .or_insert(self.engine.output_id());
+        names
+    }
+
+    fn name(&self, id: usize) -> String {
+        if id == self.engine.output_id() {
+            return String::from(OUTPUT_NAME);
+        }
+        self.engine
+            .component_info(id)
+            .and_then(|info| info.label)
+            .unwrap_or_else(|| format!("#{}", id))
+    }
+
+    fn id(&self, name: &str) -> Result<usize, Error> {
+        self.names()
+            .get(name)
+            .copied()
+            .ok_or_else(|| Error::Message(format!("there is no component called `{}`", name)))
+    }
+
+    fn port(&self, text: &str) -> Result<(usize, String), Error> {
+        let (component, port) = text.split_once('.').ok_or_else(|| {
+            Error::Message(format!("expected `component.port`, found `{}`", text))
+        })?;
+        Ok((self.id(component)?, port.to_string()))
+    }
+
+    fn statements(&mut self, line: &str, out: &mut dyn Write) -> Result<(), Error> {
+        let applied = Patch::parse(line).and_then(|patch| {
+            let mut names = self.names();
+            patch.apply_to(self.engine, &self.registry, &mut names)
+        });
+        match applied {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                writeln!(out, "error: {}", e)?;
+                if let Some(at) = e.position() {
+                    writeln!(out, "  {}", line)?;
+                    writeln!(out, "  {}^", " ".repeat(at.column - 1))?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn list(&self, out: &mut dyn Write) -> Result<(), Error> {
+        for id in self.engine.components() {
+            let info = match self.engine.component_info(id) {
+                Some(info) => info,
+                None => continue,
+            };
+            write!(
+                out,
+                "{:<16} #{:<4} {:<20}",
+                self.name(id),
+                id,
+                short_type_name(info.type_name)
+            )?;
+            if info.fault.is_some() {
+                write!(out, " (faulted)")?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+
+    fn ports(&self, name: &str, out: &mut dyn Write) -> Result<(), Error> {
+        let id = self.id(name)?;
+        let info = self
+            .engine
+            .component_info(id)
+            .ok_or_else(|| Error::Message(format!("there is no component called `{}`", name)))?;
+        writeln!(
+            out,
+            "{} (#{}, {})",
+            name,
+            id,
+            short_type_name(info.type_name)
+        )?;
+        if let Some(fault) = info.fault {
+            writeln!(out, "  faulted: {:?}, `reset {}` to clear it", fault, name)?;
+        }
+        writeln!(out, "  inputs:")?;
+        for port in info.inputs.iter() {
+            let sources: Vec<String> = info
+                .in_connections
+                .iter()
+                .filter(|c| c.to.name == port.name)
+                .map(|c| format!("{}.{}", self.name(c.from.id), c.from.name))
+                .collect();
+            write!(out, "    {:<16} {:<8}", port.name, io_name(&port.io))?;
+            if !sources.is_empty() {
+                write!(out, " <- {}", sources.join(", "))?;
+            }
+            writeln!(out)?;
+        }
+        writeln!(out, "  outputs:")?;
+        for port in info.outputs.iter() {
+            let targets: Vec<String> = info
+                .out_connections
+                .iter()
+                .filter(|c| c.from.name == port.name)
+                .map(|c| format!("{}.{}", self.name(c.to.id), c.to.name))
+                .collect();
+            write!(out, "    {:<16} {:<8}", port.name, io_name(&port.io))?;
+            if !targets.is_empty() {
+                write!(out, " -> {}", targets.join(", "))?;
+            }
+            writeln!(out)?;
+        }
+        let mut parameters: Vec<(String, f32)> = self
+            .engine
+            .parameters(id)
+            .unwrap_or_default()
+            .into_iter()
+            .collect();
+        parameters.sort_by(|a, b| a.0.cmp(&b.0));
+        if !parameters.is_empty() {
+            writeln!(out, "  parameters:")?;
+            for (name, value) in parameters {
+                writeln!(out, "    {} = {}", name, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn disconnect(&mut self, from: &str, to: &str) -> Result<(), Error> {
+        let (from_id, from_name) = self.port(from)?;
+        let (to_id, to_name) = self.port(to)?;
+        let connection = self
+            .engine
+            .connections()
+            .into_iter()
+            .find(|c| {
+                c.from.id == from_id
+                    && c.from.name == from_name
+                    && c.to.id == to_id
+                    && c.to.name == to_name
+            })
+            .ok_or_else(|| Error::Message(format!("`{}` isn't connected to `{}`", from, to)))?;
+        self.engine
+            .remove_connection(connection)
+            .map_err(|e| Error::Message(format!("{:?}", e)))?;
+        Ok(())
+    }
+
+    fn remove(&mut self, name: &str) -> Result<(), Error> {
+        let id = self.id(name)?;
+        if id == self.engine.output_id() {
+            return Err(Error::Message(String::from("the output can't be removed")));
+        }
+        self.engine.remove_model(id);
+        Ok(())
+    }
+
+    fn reset(&mut self, name: &str) -> Result<(), Error> {
+        let id = self.id(name)?;
+        self.engine.reset_component(id);
+        Ok(())
+    }
+
+    fn step(&mut self, undo: bool) -> Result<(), Error> {
+        let stepped = if undo {
+            self.engine.undo()
+        } else {
+            self.engine.redo()
+        };
+        match stepped {
+            Ok(true) => Ok(()),
+            Ok(false) if undo => Err(Error::Message(String::from("nothing to undo"))),
+            Ok(false) => Err(Error::Message(String::from("nothing to redo"))),
+            Err(e) => Err(Error::Message(format!("{:?}", e))),
+        }
+    }
+
+    fn stats(&self, out: &mut dyn Write) -> Result<(), Error> {
+        let stats = self.engine.stats();
+        writeln!(
+            out,
+            "dsp load {:.1}% (peak {:.1}%), xruns {}, faults {}",
+            stats.dsp_load * 100.,
+            stats.peak_dsp_load * 100.,
+            stats.xruns(),
+            stats.faults
+        )?;
+        Ok(())
+    }
+
+    fn meter(&self, targets: &[&str], out: &mut dyn Write) -> Result<(), Error> {
+        //A number at the end is how long to show the meters
+        let (targets, seconds) = match targets.split_last() {
+            Some((last, rest)) if !rest.is_empty() => match last.parse::<f32>() {
+                Ok(seconds) => (rest, seconds),
+                Err(_) => (targets, 5.),
+            },
+            _ => (targets, 5.),
+        };
+        let mut inputs = Vec::new();
+        for target in targets {
+            let (id, input) = self.port(target)?;
+            if self.engine.peak(id, &input).is_none() {
+                return Err(Error::Message(format!("`{}` is not an input", target)));
+            }
+            inputs.push((target, id, input));
+        }
+        let started = Instant::now();
+        while started.elapsed().as_secs_f32() < seconds {
+            let meters: Vec<String> = inputs
+                .iter()
+                .map(|(target, id, input)| {
+                    let peak = self.engine.peak(*id, input).unwrap_or(0.);
+                    format!("{} {}", target, bar(peak))
+                })
+                .collect();
+            write!(out, "\r{}", meters.join("  "))?;
+            out.flush()?;
+            thread::sleep(METER_INTERVAL);
+        }
+        writeln!(out)?;
+        Ok(())
+    }
+
+    fn completion(&self) -> Completion {
+        let mut ports = HashMap::new();
+        for (name, id) in self.names() {
+            let mut names: Vec<String> = match self.engine.component_info(id) {
+                Some(info) => info
+                    .inputs
+                    .into_iter()
+                    .chain(info.outputs)
+                    .map(|p| p.name)
+                    .collect(),
+                None => continue,
+            };
+            names.extend(self.engine.parameters(id).unwrap_or_default().into_keys());
+            names.sort_unstable();
+            names.dedup();
+            ports.insert(name, names);
+        }
+        Completion {
+            models: self
+                .registry
+                .names()
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            ports,
+        }
+    }
+}
+
+enum Error {
+    Io(io::Error),
+    //Shown to the user, the prompt carries on
+    Message(String),
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+fn readline_error(error: ReadlineError) -> io::Error {
+    match error {
+        ReadlineError::Io(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+fn io_name(io: &IOType) -> &'static str {
+    match io {
+        IOType::Voltage => "voltage",
+        IOType::Midi => "midi",
+    }
+}
+
+fn bar(peak: f32) -> String {
+    let db = 20. * peak.max(1e-6).log10();
+    let filled = (((db - METER_FLOOR) / -METER_FLOOR).clamp(0., 1.) * METER_WIDTH as f32) as usize;
+    let clip = if peak >= 1. { "CLIP" } else { "" };
+    format!(
+        "[{}{}] {:>6.1} dB {:<4}",
+        "#".repeat(filled),
+        " ".repeat(METER_WIDTH - filled),
+        db.max(METER_FLOOR),
+        clip
+    )
+}
+
+//What tab completes, taken from the engine after every line
+struct Completion {
+    models: Vec<String>,
+    //Ports and parameters of every named component
+    ports: HashMap<String, Vec<String>>,
+}
+
+impl Completer for Completion {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &rustyline::Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let before = &line[..pos];
+        let start = before
+            .rfind(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.'))
+            .map_or(0, |i| i + 1);
+        let word = &before[start..];
+        //The statement being typed, up to the word
+        let statement = before[..start].rsplit(';').next().unwrap_or_default();
+        let matching = |candidates: &mut dyn Iterator<Item = &str>, prefix: &str| {
+            let mut matching: Vec<String> = candidates
+                .filter(|c| c.starts_with(prefix))
+                .map(String::from)
+                .collect();
+            matching.sort_unstable();
+            matching
+        };
+        if let Some((component, port)) = word.split_once('.') {
+            let ports = match self.ports.get(component) {
+                Some(ports) => ports,
+                None => return Ok((pos, Vec::new())),
+            };
+            let start = start + component.len() + 1;
+            return Ok((start, matching(&mut ports.iter().map(|p| p.as_str()), port)));
+        }
+        let components = self.ports.keys().map(|c| c.as_str());
+        let candidates = if statement.trim_end().ends_with('=') && !statement.contains('.') {
+            matching(&mut self.models.iter().map(|m| m.as_str()), word)
+        } else if statement.trim().is_empty() {
+            matching(&mut COMMANDS.into_iter().chain(components), word)
+        } else {
+            matching(&mut components.into_iter(), word)
+        };
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for Completion {
+    type Hint = String;
+}
+
+impl Highlighter for Completion {}
+
+impl Validator for Completion {}
+
+impl rustyline::Helper for Completion {}
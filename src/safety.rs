@@ -0,0 +1,198 @@
+use std::f32::consts::PI;
+
+/// Settings for the safety stage between the graph and the output device.
+///
+/// Every output channel has its own settings, see `Engine::set_channel_safety`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SafetyConfig {
+    /// When off the graph's output goes to the device untouched, NaNs included
+    pub enabled: bool,
+    pub dc_blocker: bool,
+    /// Cutoff of the DC blocker in Hz
+    pub dc_cutoff: f32,
+    pub limiter: bool,
+    /// Level the limiter keeps peaks under
+    pub ceiling: f32,
+    /// Seconds the limiter takes to let the gain recover by about 63%
+    pub release: f32,
+    /// Whatever still gets past is clamped to plus or minus this
+    pub clamp: f32,
+    /// Seconds to fade in from silence when the engine starts or resumes
+    pub fade_in: f32,
+}
+
+impl Default for SafetyConfig {
+    fn default() -> Self {
+        SafetyConfig {
+            enabled: true,
+            dc_blocker: true,
+            dc_cutoff: 10.,
+            limiter: true,
+            ceiling: 0.9,
+            release: 0.05,
+            clamp: 1.,
+            fade_in: 0.05,
+        }
+    }
+}
+
+impl SafetyConfig {
+    pub fn disabled() -> Self {
+        SafetyConfig {
+            enabled: false,
+            ..SafetyConfig::default()
+        }
+    }
+}
+
+/// The safety stage for every output channel.
+pub(crate) struct OutputSafety {
+    sample_rate: usize,
+    //Used for channels that appear after `set_all`, for example once the engine starts
+    default: SafetyConfig,
+    //Kept for every channel that was ever configured so settings survive switching devices
+    channels: Vec<ChannelSafety>,
+}
+
+impl OutputSafety {
+    pub fn new(sample_rate: usize) -> Self {
+        OutputSafety {
+            sample_rate,
+            default: SafetyConfig::default(),
+            channels: Vec::new(),
+        }
+    }
+
+    /// Gets ready for a stream with `channels` channels and starts the fade in.
+    /// Allocates, so it has to be called off the audio thread.
+    pub fn restart(&mut self, sample_rate: usize, channels: usize) {
+        self.sample_rate = sample_rate;
+        if self.channels.len() < channels {
+            let default = &self.default;
+            self.channels
+                .resize_with(channels, || ChannelSafety::new(default.clone()));
+        }
+        for channel in self.channels.iter_mut() {
+            channel.reset();
+        }
+    }
+
+    pub fn config(&self, channel: usize) -> Option<&SafetyConfig> {
+        self.channels.get(channel).map(|c| &c.config)
+    }
+
+    pub fn set_config(&mut self, channel: usize, config: SafetyConfig) {
+        if self.channels.len() <= channel {
+            let default = &self.default;
+            self.channels
+                .resize_with(channel + 1, || ChannelSafety::new(default.clone()));
+        }
+        self.channels[channel].config = config;
+    }
+
+    pub fn set_all(&mut self, config: SafetyConfig) {
+        for channel in self.channels.iter_mut() {
+            channel.config = config.clone();
+        }
+        self.default = config;
+    }
+
+    /// Runs an interleaved buffer with `channels` channels through the stage in place.
+    pub fn process(&mut self, data: &mut [f32], channels: usize) {
+        let sample_rate = self.sample_rate as f32;
+        for frame in data.chunks_mut(channels) {
+            for (sample, channel) in frame.iter_mut().zip(self.channels.iter_mut()) {
+                *sample = channel.process(*sample, sample_rate);
+            }
+        }
+    }
+}
+
+struct ChannelSafety {
+    config: SafetyConfig,
+    //Last input and output of the DC blocker
+    previous_input: f32,
+    previous_output: f32,
+    envelope: f32,
+    fade: f32,
+}
+
+impl ChannelSafety {
+    fn new(config: SafetyConfig) -> Self {
+        ChannelSafety {
+            config,
+            previous_input: 0.,
+            previous_output: 0.,
+            envelope: 0.,
+            fade: 0.,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.previous_input = 0.;
+        self.previous_output = 0.;
+        self.envelope = 0.;
+        self.fade = 0.;
+    }
+
+    fn process(&mut self, sample: f32, sample_rate: f32) -> f32 {
+        let config = &self.config;
+        if !config.enabled {
+            return sample;
+        }
+        //NaN and infinity would stick in the filter states forever
+        let mut sample = if sample.is_finite() { sample } else { 0. };
+        if config.dc_blocker {
+            let pole = 1. - (2. * PI * config.dc_cutoff / sample_rate);
+            let output = sample - self.previous_input + pole * self.previous_output;
+            self.previous_input = sample;
+            self.previous_output = if output.is_finite() { output } else { 0. };
+            sample = self.previous_output;
+        }
+        if config.limiter {
+            //Instant attack so no peak ever gets past the ceiling, exponential release
+            let release = (-1. / (config.release * sample_rate)).exp();
+            self.envelope = f32::max(sample.abs(), self.envelope * release);
+            if self.envelope > config.ceiling {
+                sample *= config.ceiling / self.envelope;
+            }
+        }
+        if self.fade < 1. {
+            self.fade = if config.fade_in > 0. {
+                f32::min(self.fade + 1. / (config.fade_in * sample_rate), 1.)
+            } else {
+                1.
+            };
+            sample *= self.fade;
+        }
+        //`clamp` is public so it can be negative or NaN, which `f32::clamp` would panic on
+        let clamp = config.clamp.abs();
+        sample.max(-clamp).min(clamp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn odd_clamps_dont_panic() {
+        let mut safety = OutputSafety::new(48000);
+        safety.restart(48000, 1);
+        let clamp = |clamp: f32| SafetyConfig {
+            limiter: false,
+            dc_blocker: false,
+            fade_in: 0.,
+            clamp,
+            ..SafetyConfig::default()
+        };
+        let mut data = [2., -2.];
+        safety.set_all(clamp(-0.5));
+        safety.process(&mut data, 1);
+        assert_eq!(data, [0.5, -0.5]);
+        let mut data = [2., -2.];
+        safety.set_all(clamp(f32::NAN));
+        safety.process(&mut data, 1);
+        assert_eq!(data, [2., -2.]);
+    }
+}
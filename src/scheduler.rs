@@ -0,0 +1,805 @@
+use std::{
+    cell::UnsafeCell,
+    collections::HashMap,
+    hint,
+    panic::{self, AssertUnwindSafe},
+    ptr,
+    sync::{
+        atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Instant,
+};
+
+use midi_types::MidiMessage;
+
+use crate::{
+    diagnostics::{ComponentMonitor, Fault},
+    Config, IOType, Input, ModelHolder, Output,
+};
+
+/// A snapshot of the graph that can be evaluated without touching the `Graph` itself.
+///
+/// Everything in here is built on the control thread whenever the topology changes, so
+/// evaluating it on the audio thread (or the worker threads) never allocates for scheduling.
+pub(crate) struct Schedule {
+    nodes: Vec<Node>,
+    roots: Vec<usize>,
+    ready: ReadyQueue,
+    remaining: AtomicUsize,
+    //Boxed so the input tables can point at it after the schedule is moved
+    silence: Box<UnsafeCell<Silence>>,
+}
+
+// The unsafe cells in a schedule are only touched according to the dependency counters,
+// see `Schedule::evaluate_node`.
+unsafe impl Sync for Schedule {}
+unsafe impl Send for Schedule {}
+
+struct Node {
+    id: usize,
+    model: ModelHolder,
+    inputs: Vec<NodeInput>,
+    //What the model is handed as its `Input`
+    table: UnsafeCell<InputTable>,
+    output: UnsafeCell<Output>,
+    dependents: Vec<usize>,
+    dependencies: usize,
+    pending: AtomicUsize,
+    monitor: Arc<ComponentMonitor>,
+    //Ramps of the connections a removed model is fading out on, empty for models in the graph
+    fading_out: Vec<Arc<Ramp>>,
+}
+
+struct NodeInput {
+    name: String,
+    io: IOType,
+    //(index of the node it comes from, output name, ramp)
+    sources: Vec<(usize, String, Option<Arc<Ramp>>)>,
+    //Inputs that are fading or fed by more than one output are summed into their own buffer
+    mixed: bool,
+    mix: UnsafeCell<Vec<f32>>,
+}
+
+/// The maps lent to a model as its `Input`. Their keys are set when the schedule is built and
+/// before every evaluation the references are pointed at the buffers of the block, so no
+/// strings are cloned on the audio thread.
+struct InputTable {
+    voltages: HashMap<String, &'static Vec<f32>>,
+    midi_events: HashMap<String, &'static Vec<Option<MidiMessage>>>,
+    //The buffer behind each entry of the maps, in the order the maps iterate in. The buffers
+    //are never moved while the schedule exists.
+    voltage_sources: Vec<*const Vec<f32>>,
+    midi_sources: Vec<*const Vec<Option<MidiMessage>>>,
+}
+
+//What the tables hold until they are first pointed at a block
+static NO_VOLTAGES: Vec<f32> = Vec::new();
+static NO_MIDI: Vec<Option<MidiMessage>> = Vec::new();
+
+#[derive(Default)]
+struct Silence {
+    voltage: Vec<f32>,
+    midi: Vec<Option<MidiMessage>>,
+}
+
+/// Description of one component handed to `Schedule::build`.
+pub(crate) struct ScheduledComponent<'a> {
+    pub id: usize,
+    pub model: &'a ModelHolder,
+    pub inputs: Vec<ScheduledInput>,
+    pub monitor: &'a Arc<ComponentMonitor>,
+    pub fading_out: Vec<Arc<Ramp>>,
+}
+
+/// One connection into a scheduled component. Several can feed the same input while one of
+/// them fades out, their signals are summed.
+pub(crate) struct ScheduledInput {
+    pub name: String,
+    pub from: usize,
+    pub from_name: String,
+    pub io: IOType,
+    pub ramp: Option<Arc<Ramp>>,
+}
+
+/// A gain moving linearly between two values, used to crossfade connections.
+///
+/// The control thread creates it and keeps it across schedules so a fade carries on when the
+/// topology changes again. Only the node reading the connection advances it.
+pub(crate) struct Ramp {
+    from: f32,
+    to: f32,
+    //Seconds
+    length: f32,
+    //f32 bits going from 0 to 1
+    progress: AtomicU32,
+}
+
+impl Ramp {
+    pub fn new(from: f32, to: f32, length: f32) -> Self {
+        Ramp {
+            from,
+            to,
+            length,
+            progress: AtomicU32::new(0f32.to_bits()),
+        }
+    }
+
+    pub fn gain(&self) -> f32 {
+        let progress = f32::from_bits(self.progress.load(Ordering::Relaxed));
+        self.from + (self.to - self.from) * progress
+    }
+
+    pub fn finished(&self) -> bool {
+        f32::from_bits(self.progress.load(Ordering::Relaxed)) >= 1.
+    }
+
+    /// Moves the ramp on by `frames` samples and returns the gain at the start and the end.
+    fn advance(&self, frames: usize, delta: f32) -> (f32, f32) {
+        let start = self.gain();
+        let progress = f32::from_bits(self.progress.load(Ordering::Relaxed));
+        let progress = if self.length > 0. {
+            f32::min(progress + frames as f32 * delta / self.length, 1.)
+        } else {
+            1.
+        };
+        self.progress.store(progress.to_bits(), Ordering::Relaxed);
+        (start, self.gain())
+    }
+}
+
+impl Schedule {
+    /// Builds a schedule from components given in topological order.
+    pub fn build(components: Vec<ScheduledComponent>, buffer_size: usize) -> Self {
+        let index: HashMap<usize, usize> = components
+            .iter()
+            .enumerate()
+            .map(|(i, c)| (c.id, i))
+            .collect();
+        let mut nodes: Vec<Node> = Vec::with_capacity(components.len());
+        for component in components.iter() {
+            let model = component.model.lock();
+            let input_format = model.input_format();
+            let mut inputs: Vec<NodeInput> = Vec::new();
+            for input in component.inputs.iter() {
+                let source = (
+                    index[&input.from],
+                    input.from_name.clone(),
+                    input.ramp.clone(),
+                );
+                match inputs.iter_mut().find(|i| i.name == input.name) {
+                    Some(existing) => existing.sources.push(source),
+                    None => inputs.push(NodeInput {
+                        name: input.name.clone(),
+                        io: input.io.clone(),
+                        sources: vec![source],
+                        mixed: false,
+                        mix: UnsafeCell::new(Vec::new()),
+                    }),
+                }
+            }
+            for input in inputs.iter_mut() {
+                //MIDI can't be faded or summed, it only ever has the one connection
+                input.mixed = input.io == IOType::Voltage
+                    && (input.sources.len() > 1 || input.sources[0].2.is_some());
+                if input.mixed {
+                    *input.mix.get_mut() = Vec::with_capacity(buffer_size);
+                }
+            }
+            let mut table = InputTable {
+                voltages: HashMap::new(),
+                midi_events: HashMap::new(),
+                voltage_sources: Vec::new(),
+                midi_sources: Vec::new(),
+            };
+            let connected = inputs.iter().map(|i| (i.name.clone(), i.io.clone()));
+            for (name, io) in input_format.into_iter().chain(connected) {
+                match io {
+                    IOType::Voltage => {
+                        table.voltages.insert(name, &NO_VOLTAGES);
+                    }
+                    IOType::Midi => {
+                        table.midi_events.insert(name, &NO_MIDI);
+                    }
+                }
+            }
+            let output = Output::new(model.output_format(), buffer_size);
+            let dependencies = inputs.iter().map(|i| i.sources.len()).sum();
+            nodes.push(Node {
+                id: component.id,
+                model: component.model.clone(),
+                inputs,
+                table: UnsafeCell::new(table),
+                output: UnsafeCell::new(output),
+                dependents: Vec::new(),
+                dependencies,
+                pending: AtomicUsize::new(0),
+                monitor: Arc::clone(component.monitor),
+                fading_out: component.fading_out.clone(),
+            });
+        }
+        for i in 0..nodes.len() {
+            for j in 0..nodes[i].inputs.len() {
+                for k in 0..nodes[i].inputs[j].sources.len() {
+                    let from = nodes[i].inputs[j].sources[k].0;
+                    nodes[from].dependents.push(i);
+                }
+            }
+        }
+        let roots = (0..nodes.len())
+            .filter(|i| nodes[*i].dependencies == 0)
+            .collect();
+        let ready = ReadyQueue::new(nodes.len());
+        let silence = Box::new(UnsafeCell::new(Silence {
+            voltage: Vec::with_capacity(buffer_size),
+            midi: Vec::with_capacity(buffer_size),
+        }));
+        //Every buffer has its final address now, point the tables at them
+        for i in 0..nodes.len() {
+            let (voltage_sources, midi_sources) = {
+                let node = &nodes[i];
+                let table = unsafe { &*node.table.get() };
+                let silence = unsafe { &*silence.get() };
+                let voltage_sources = table
+                    .voltages
+                    .keys()
+                    .map(|name| match node.inputs.iter().find(|i| &i.name == name) {
+                        Some(input) if input.mixed => input.mix.get() as *const Vec<f32>,
+                        Some(input) => {
+                            let (from, from_name, _) = &input.sources[0];
+                            let output = unsafe { &*nodes[*from].output.get() };
+                            &output.voltages[from_name] as *const Vec<f32>
+                        }
+                        None => &silence.voltage as *const Vec<f32>,
+                    })
+                    .collect();
+                let midi_sources = table
+                    .midi_events
+                    .keys()
+                    .map(|name| match node.inputs.iter().find(|i| &i.name == name) {
+                        Some(input) => {
+                            let (from, from_name, _) = &input.sources[0];
+                            let output = unsafe { &*nodes[*from].output.get() };
+                            &output.midi_events[from_name] as *const Vec<Option<MidiMessage>>
+                        }
+                        None => &silence.midi as *const Vec<Option<MidiMessage>>,
+                    })
+                    .collect();
+                (voltage_sources, midi_sources)
+            };
+            let table = nodes[i].table.get_mut();
+            table.voltage_sources = voltage_sources;
+            table.midi_sources = midi_sources;
+        }
+        Schedule {
+            nodes,
+            roots,
+            ready,
+            remaining: AtomicUsize::new(0),
+            silence,
+        }
+    }
+
+    /// The buffer feeding input `name` of component `id` in the last evaluated block, or `None`
+    /// if nothing is connected to it. Must not be called while the schedule is evaluating.
+    pub fn input(&self, id: usize, name: &str) -> Option<&Vec<f32>> {
+        let node = self.nodes.iter().find(|n| n.id == id)?;
+        let input = node.inputs.iter().find(|i| i.name == name)?;
+        //Safety: nothing is evaluating the schedule
+        if input.mixed {
+            return Some(unsafe { &*input.mix.get() });
+        }
+        let (from, from_name, _) = &input.sources[0];
+        let from_output = unsafe { &*self.nodes[*from].output.get() };
+        from_output.voltages.get(from_name)
+    }
+
+    /// The earliest event any model announces strictly inside the next `frames` samples
+    pub fn next_event(&self, time: u64, frames: usize) -> Option<usize> {
+        let mut next: Option<usize> = None;
+        for node in self.nodes.iter() {
+            if let Some(event) = node.model.lock().next_event(time, frames) {
+                if event > 0 && event < frames && next.is_none_or(|n| event < n) {
+                    next = Some(event);
+                }
+            }
+        }
+        next
+    }
+
+    /// Prepares the shared buffers for a block. Must only be called while nothing else is
+    /// evaluating this schedule.
+    fn begin(&self, buffer_size: usize) {
+        //Safety: nothing else is evaluating the schedule
+        let silence = unsafe { &mut *self.silence.get() };
+        silence.voltage.clear();
+        silence.voltage.resize(buffer_size, 0.);
+        silence.midi.clear();
+        silence.midi.resize(buffer_size, None);
+    }
+
+    /// Evaluates every node in order on the calling thread.
+    pub fn run_sequential(&self, config: &Config) {
+        self.begin(config.buffer_size);
+        for i in 0..self.nodes.len() {
+            self.evaluate_node(i, config);
+        }
+    }
+
+    /// Resets the dependency counters and queues the nodes that can start straight away.
+    fn reset(&self, buffer_size: usize) {
+        self.begin(buffer_size);
+        self.ready.clear();
+        for node in self.nodes.iter() {
+            node.pending.store(node.dependencies, Ordering::Relaxed);
+        }
+        self.remaining.store(self.nodes.len(), Ordering::Release);
+        for root in self.roots.iter() {
+            self.ready.push(*root);
+        }
+    }
+
+    /// Takes ready nodes off the queue until every node of the block has been evaluated.
+    fn work(&self, config: &Config) {
+        while self.remaining.load(Ordering::Acquire) > 0 {
+            match self.ready.pop() {
+                Some(i) => {
+                    self.evaluate_node(i, config);
+                    for dependent in self.nodes[i].dependents.iter() {
+                        if self.nodes[*dependent]
+                            .pending
+                            .fetch_sub(1, Ordering::AcqRel)
+                            == 1
+                        {
+                            self.ready.push(*dependent);
+                        }
+                    }
+                    self.remaining.fetch_sub(1, Ordering::AcqRel);
+                }
+                None => hint::spin_loop(),
+            }
+        }
+    }
+
+    fn evaluate_node(&self, i: usize, config: &Config) {
+        let node = &self.nodes[i];
+        //Safety: a node is only evaluated once all of the nodes it reads from are finished and
+        //before any of the nodes reading from it start, so its output is never aliased here.
+        let output = unsafe { &mut *node.output.get() };
+        for buffer in output.voltages.values_mut() {
+            buffer.clear();
+            buffer.resize(config.buffer_size, 0.);
+        }
+        for buffer in output.midi_events.values_mut() {
+            buffer.clear();
+            buffer.resize(config.buffer_size, None);
+        }
+        //Mix the inputs that are fed by more than one output or are fading
+        for node_input in node.inputs.iter().filter(|i| i.mixed) {
+            //Safety: only this node writes its mix buffers
+            let mix = unsafe { &mut *node_input.mix.get() };
+            mix.clear();
+            mix.resize(config.buffer_size, 0.);
+            for (from, from_name, ramp) in node_input.sources.iter() {
+                //Safety: outputs of finished nodes are only read until the block ends
+                let from_output = unsafe { &*self.nodes[*from].output.get() };
+                let Some(value) = from_output.voltages.get(from_name) else {
+                    continue;
+                };
+                let (start, end) = match ramp {
+                    Some(ramp) => ramp.advance(config.buffer_size, config.delta),
+                    None => (1., 1.),
+                };
+                if start == 0. && end == 0. {
+                    continue;
+                }
+                let step = (end - start) / config.buffer_size as f32;
+                for (i, (mixed, sample)) in mix.iter_mut().zip(value.iter()).enumerate() {
+                    *mixed += sample * (start + step * i as f32);
+                }
+            }
+        }
+        //Safety: only this node uses its table, and the buffers it points at are the outputs of
+        //finished nodes, this node's mix buffers and the silence, which stay put until the
+        //block ends
+        let table = unsafe { &mut *node.table.get() };
+        for (slot, source) in table.voltages.values_mut().zip(&table.voltage_sources) {
+            *slot = unsafe { &**source };
+        }
+        for (slot, source) in table.midi_events.values_mut().zip(&table.midi_sources) {
+            *slot = unsafe { &**source };
+        }
+        let input = Input {
+            voltages: &table.voltages,
+            midi_events: &table.midi_events,
+        };
+        //Faulted components stay silent until they are reset
+        if node.monitor.fault().is_some() {
+            return;
+        }
+        //Removed models are no longer heard once they have faded out
+        if !node.fading_out.is_empty() && node.fading_out.iter().all(|r| r.finished()) {
+            return;
+        }
+        let start = Instant::now();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            node.model
+                .lock()
+                .evaluate(config.buffer_size, input, output, config)
+        }));
+        node.monitor.record(start.elapsed());
+        let fault = if result.is_err() {
+            Some(Fault::Panicked)
+        } else if output
+            .voltages
+            .values()
+            .any(|buffer| buffer.iter().any(|sample| !sample.is_finite()))
+        {
+            Some(Fault::NonFinite)
+        } else {
+            None
+        };
+        if fault.is_some() {
+            //Don't let whatever made it into the outputs reach the rest of the graph
+            for buffer in output.voltages.values_mut() {
+                buffer.fill(0.);
+            }
+            for buffer in output.midi_events.values_mut() {
+                buffer.fill(None);
+            }
+            node.monitor.set_fault(fault);
+            config.report_fault();
+        }
+    }
+}
+
+/// Fixed capacity queue of node indices. Every node is pushed at most once per block so it
+/// never needs to wrap around.
+struct ReadyQueue {
+    slots: Vec<AtomicUsize>,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+impl ReadyQueue {
+    fn new(capacity: usize) -> Self {
+        ReadyQueue {
+            slots: (0..capacity).map(|_| AtomicUsize::new(0)).collect(),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    fn clear(&self) {
+        for slot in self.slots.iter() {
+            slot.store(0, Ordering::Relaxed);
+        }
+        self.head.store(0, Ordering::Relaxed);
+        self.tail.store(0, Ordering::Relaxed);
+    }
+
+    fn push(&self, index: usize) {
+        let tail = self.tail.fetch_add(1, Ordering::AcqRel);
+        //Slots hold index + 1 so that 0 can mean "not written yet"
+        self.slots[tail].store(index + 1, Ordering::Release);
+    }
+
+    fn pop(&self) -> Option<usize> {
+        loop {
+            let head = self.head.load(Ordering::Acquire);
+            if head >= self.tail.load(Ordering::Acquire) {
+                return None;
+            }
+            let value = self.slots[head].load(Ordering::Acquire);
+            if value == 0 {
+                return None;
+            }
+            if self
+                .head
+                .compare_exchange(head, head + 1, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+            {
+                return Some(value - 1);
+            }
+        }
+    }
+}
+
+struct PoolShared {
+    //The block being evaluated, only valid while `open` is set
+    schedule: AtomicPtr<Schedule>,
+    config: AtomicPtr<Config>,
+    open: AtomicBool,
+    generation: AtomicUsize,
+    active: AtomicUsize,
+    shutdown: AtomicBool,
+}
+
+/// Threads that help the audio thread evaluate independent branches of the graph.
+///
+/// Blocks are handed over through atomics instead of a lock, so the audio thread never waits
+/// for a worker that was descheduled while holding it.
+pub(crate) struct WorkerPool {
+    shared: Arc<PoolShared>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    pub fn new(threads: usize) -> Self {
+        let shared = Arc::new(PoolShared {
+            schedule: AtomicPtr::new(ptr::null_mut()),
+            config: AtomicPtr::new(ptr::null_mut()),
+            open: AtomicBool::new(false),
+            generation: AtomicUsize::new(0),
+            active: AtomicUsize::new(0),
+            shutdown: AtomicBool::new(false),
+        });
+        let threads = (0..threads)
+            .map(|i| {
+                let shared = Arc::clone(&shared);
+                thread::Builder::new()
+                    .name(format!("proto-worker-{}", i))
+                    .spawn(move || worker_loop(shared))
+                    .unwrap()
+            })
+            .collect();
+        WorkerPool { shared, threads }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads.len()
+    }
+
+    /// Evaluates the schedule, splitting the work between the calling thread and the pool.
+    pub fn run(&self, schedule: &Schedule, config: &Config) {
+        if self.threads.is_empty() || schedule.nodes.len() < 2 {
+            schedule.run_sequential(config);
+            return;
+        }
+        schedule.reset(config.buffer_size);
+        //Workers only read through these, the pointers are mutable because `AtomicPtr` is
+        let shared = &self.shared;
+        shared.schedule.store(
+            schedule as *const Schedule as *mut Schedule,
+            Ordering::Relaxed,
+        );
+        shared
+            .config
+            .store(config as *const Config as *mut Config, Ordering::Relaxed);
+        shared.open.store(true, Ordering::SeqCst);
+        shared.generation.fetch_add(1, Ordering::AcqRel);
+        for thread in self.threads.iter() {
+            thread.thread().unpark();
+        }
+        schedule.work(config);
+        //Stop late workers from joining and wait for the ones still inside the schedule. A
+        //worker either sees `open` cleared or is counted in `active` before this reads it.
+        shared.open.store(false, Ordering::SeqCst);
+        while shared.active.load(Ordering::SeqCst) > 0 {
+            hint::spin_loop();
+        }
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, Ordering::Release);
+        for thread in self.threads.drain(..) {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+fn worker_loop(shared: Arc<PoolShared>) {
+    let mut seen = 0;
+    loop {
+        let generation = shared.generation.load(Ordering::Acquire);
+        if shared.shutdown.load(Ordering::Acquire) {
+            return;
+        }
+        if generation == seen {
+            thread::park();
+            continue;
+        }
+        seen = generation;
+        shared.active.fetch_add(1, Ordering::SeqCst);
+        if shared.open.load(Ordering::SeqCst) {
+            //Safety: the audio thread keeps both alive until `active` drops back to 0
+            let schedule = unsafe { &*shared.schedule.load(Ordering::Relaxed) };
+            let config = unsafe { &*shared.config.load(Ordering::Relaxed) };
+            schedule.work(config);
+        }
+        shared.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        cell::Cell,
+        sync::Arc,
+    };
+
+    use parking_lot::Mutex;
+
+    use super::*;
+    use crate::{
+        diagnostics::Diagnostics,
+        model_utils::DuoSignalMixer,
+        ports::{Jack, Process},
+        Model,
+    };
+
+    //Counts the allocations of each thread so tests can check the audio path doesn't allocate
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|a| a.set(a.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    fn allocations() -> usize {
+        ALLOCATIONS.with(|a| a.get())
+    }
+
+    //A sine that is different for every component and every block
+    #[derive(Model)]
+    struct Source {
+        #[output(voltage)]
+        output: Jack,
+        #[parameter]
+        step: f32,
+    }
+
+    impl Process for Source {
+        fn process(&mut self, ports: SourcePorts, config: &Config) {
+            for (i, sample) in ports.output.iter_mut().enumerate() {
+                *sample = ((config.time() + i as u64) as f32 * self.step).sin();
+            }
+        }
+    }
+
+    //Eight sources fanning into four mixers, fanning into a last mixer. Every mixer has more
+    //than one connection on `Input1`.
+    fn build(buffer_size: usize, diagnostics: &Arc<Diagnostics>) -> Schedule {
+        let mut models: Vec<ModelHolder> = Vec::new();
+        let mut inputs: Vec<Vec<(&str, usize)>> = Vec::new();
+        for i in 0..8 {
+            let source = Source {
+                output: Jack,
+                step: 0.01 * (i + 1) as f32,
+            };
+            models.push(Arc::new(Mutex::new(Box::new(source))));
+            inputs.push(Vec::new());
+        }
+        for k in 0..4 {
+            models.push(Arc::new(Mutex::new(Box::new(DuoSignalMixer::new(0.5, 1.)))));
+            inputs.push(vec![
+                ("Input1", 2 * k),
+                ("Input1", 2 * k + 1),
+                ("Input2", (2 * k + 3) % 8),
+            ]);
+        }
+        models.push(Arc::new(Mutex::new(Box::new(DuoSignalMixer::new(1., 1.)))));
+        inputs.push(vec![
+            ("Input1", 8),
+            ("Input1", 9),
+            ("Input1", 10),
+            ("Input2", 11),
+        ]);
+        let monitors: Vec<_> = (0..models.len())
+            .map(|id| diagnostics.add_component(id))
+            .collect();
+        let components = models
+            .iter()
+            .zip(&monitors)
+            .zip(&inputs)
+            .enumerate()
+            .map(|(id, ((model, monitor), inputs))| ScheduledComponent {
+                id,
+                model,
+                inputs: inputs
+                    .iter()
+                    .map(|(name, from)| ScheduledInput {
+                        name: name.to_string(),
+                        from: *from,
+                        from_name: String::from("Output"),
+                        io: IOType::Voltage,
+                        ramp: None,
+                    })
+                    .collect(),
+                monitor,
+                fading_out: Vec::new(),
+            })
+            .collect();
+        Schedule::build(components, buffer_size)
+    }
+
+    fn config(buffer_size: usize, time: u64, diagnostics: &Arc<Diagnostics>) -> Config {
+        Config {
+            buffer_size,
+            delta: 1. / 48000.,
+            time,
+            diagnostics: Arc::clone(diagnostics),
+        }
+    }
+
+    fn outputs(schedule: &Schedule) -> Vec<Vec<f32>> {
+        schedule
+            .nodes
+            .iter()
+            .map(|node| unsafe { &*node.output.get() }.voltages["Output"].clone())
+            .collect()
+    }
+
+    #[test]
+    fn parallel_matches_sequential() {
+        let diagnostics = Arc::new(Diagnostics::new());
+        let sequential = build(64, &diagnostics);
+        let parallel = build(64, &diagnostics);
+        let pool = WorkerPool::new(3);
+        let mut time = 0;
+        //Blocks shorter than the buffer size happen when callbacks are split
+        for length in [64, 64, 17, 64, 1, 40].into_iter().cycle().take(60) {
+            let config = config(length, time, &diagnostics);
+            sequential.run_sequential(&config);
+            pool.run(&parallel, &config);
+            assert_eq!(outputs(&sequential), outputs(&parallel));
+            time += length as u64;
+        }
+        assert!(outputs(&parallel)[12].iter().any(|s| *s != 0.));
+    }
+
+    #[test]
+    fn fan_in_is_summed() {
+        let diagnostics = Arc::new(Diagnostics::new());
+        let schedule = build(32, &diagnostics);
+        let pool = WorkerPool::new(2);
+        pool.run(&schedule, &config(32, 0, &diagnostics));
+        let outputs = outputs(&schedule);
+        let mixed = schedule.input(12, "Input1").unwrap();
+        for i in 0..32 {
+            assert_eq!(
+                mixed[i],
+                0. + outputs[8][i] + outputs[9][i] + outputs[10][i]
+            );
+        }
+        let mixed = schedule.input(8, "Input1").unwrap();
+        for i in 0..32 {
+            assert_eq!(mixed[i], 0. + outputs[0][i] + outputs[1][i]);
+            assert_eq!(outputs[8][i], mixed[i] * 0.5 + outputs[3][i]);
+        }
+    }
+
+    #[test]
+    fn evaluating_does_not_allocate() {
+        let diagnostics = Arc::new(Diagnostics::new());
+        let schedule = build(64, &diagnostics);
+        let pool = WorkerPool::new(2);
+        let config = config(64, 0, &diagnostics);
+        schedule.run_sequential(&config);
+        pool.run(&schedule, &config);
+        let before = allocations();
+        for _ in 0..10 {
+            schedule.run_sequential(&config);
+            pool.run(&schedule, &config);
+        }
+        assert_eq!(allocations(), before);
+    }
+}
@@ -0,0 +1,623 @@
+//! Running a single model outside an engine, for testing models.
+//!
+//! ```
+//! # use std::f32::consts::TAU;
+//! # use proto::{model_utils::Vca, testing::ModelTester};
+//! let mut tester = ModelTester::new(Vca::new(), 48000, 256);
+//! tester
+//!     .input_fn("Input", |t| (TAU * 440. * t).sin())
+//!     .constant("Control", 0.5)
+//!     .run_seconds(0.5);
+//! let output = tester.output("Output");
+//! output.assert_finite();
+//! output.assert_peak(0.5, 0.01);
+//! output.assert_frequency(440., 1.);
+//! ```
+//!
+//! The tester panics with a message listing what the model has when it is given a port or
+//! parameter the model doesn't have, and the assertions panic like `assert!`.
+
+use std::{collections::HashMap, sync::Arc};
+
+use midi_types::MidiMessage;
+
+use crate::{
+    builder::IntoHolder, diagnostics::Diagnostics, Config, IOType, Input, ModelHolder, Output,
+};
+
+/// Peak level below which a signal counts as silent, -100 dB
+pub const SILENCE: f32 = 1e-5;
+
+/// Feeds a model block by block and keeps everything it outputs.
+pub struct ModelTester {
+    model: ModelHolder,
+    sample_rate: usize,
+    buffer_size: usize,
+    //Samples evaluated so far
+    time: u64,
+    diagnostics: Arc<Diagnostics>,
+    inputs: HashMap<String, Source>,
+    //Events waiting to be played, by the sample they are played at
+    midi_inputs: HashMap<String, Vec<(u64, MidiMessage)>>,
+    outputs: HashMap<String, Vec<f32>>,
+    midi_outputs: HashMap<String, Vec<(u64, MidiMessage)>>,
+}
+
+enum Source {
+    //Samples from `start` on, silence after them
+    Samples { start: u64, samples: Vec<f32> },
+    //Called with the time in seconds
+    Function(Box<dyn FnMut(f32) -> f32>),
+}
+
+impl ModelTester {
+    /// A tester evaluating `model` in blocks of `buffer_size` samples. Inputs are silent until
+    /// they are given a signal.
+    pub fn new(model: impl IntoHolder, sample_rate: usize, buffer_size: usize) -> Self {
+        let model = model.into_holder();
+        let (inputs, outputs) = {
+            let model = model.lock();
+            (model.input_format(), model.output_format())
+        };
+        ModelTester {
+            model,
+            sample_rate,
+            buffer_size,
+            time: 0,
+            diagnostics: Arc::new(Diagnostics::new()),
+            inputs: HashMap::new(),
+            midi_inputs: inputs
+                .into_iter()
+                .filter(|(_, io)| *io == IOType::Midi)
+                .map(|(name, _)| (name, Vec::new()))
+                .collect(),
+            outputs: outputs
+                .iter()
+                .filter(|(_, io)| **io == IOType::Voltage)
+                .map(|(name, _)| (name.clone(), Vec::new()))
+                .collect(),
+            midi_outputs: outputs
+                .into_iter()
+                .filter(|(_, io)| *io == IOType::Midi)
+                .map(|(name, _)| (name, Vec::new()))
+                .collect(),
+        }
+    }
+
+    /// Feeds `samples` into a voltage input from now on, followed by silence
+    #[track_caller]
+    pub fn input(&mut self, name: &str, samples: impl IntoIterator<Item = f32>) -> &mut Self {
+        self.check_input(name, IOType::Voltage);
+        let source = Source::Samples {
+            start: self.time,
+            samples: samples.into_iter().collect(),
+        };
+        self.inputs.insert(name.to_string(), source);
+        self
+    }
+
+    /// Feeds a voltage input with `signal`, called with the time in seconds since the tester
+    /// was made
+    #[track_caller]
+    pub fn input_fn(&mut self, name: &str, signal: impl FnMut(f32) -> f32 + 'static) -> &mut Self {
+        self.check_input(name, IOType::Voltage);
+        self.inputs
+            .insert(name.to_string(), Source::Function(Box::new(signal)));
+        self
+    }
+
+    #[track_caller]
+    pub fn constant(&mut self, name: &str, value: f32) -> &mut Self {
+        self.input_fn(name, move |_| value)
+    }
+
+    /// Plays `message` on a MIDI input `delay` samples from now. Like `MidiInput`, a message
+    /// landing on a sample that already has one is moved back a sample.
+    #[track_caller]
+    pub fn midi(&mut self, name: &str, delay: u64, message: MidiMessage) -> &mut Self {
+        self.check_input(name, IOType::Midi);
+        let events = self.midi_inputs.get_mut(name).expect("checked above");
+        let time = self.time + delay;
+        let index = events.partition_point(|(t, _)| *t <= time);
+        events.insert(index, (time, message));
+        self
+    }
+
+    #[track_caller]
+    pub fn set_parameter(&mut self, name: &str, value: f32) -> &mut Self {
+        let mut model = self.model.lock();
+        if !model.set_parameter(name, value) {
+            let mut parameters: Vec<String> = model.parameters().into_keys().collect();
+            parameters.sort_unstable();
+            panic!(
+                "the model has no parameter `{}`, it has {:?}",
+                name, parameters
+            );
+        }
+        drop(model);
+        self
+    }
+
+    /// Evaluates `frames` samples, in blocks of the buffer size and a shorter one for the rest
+    pub fn run(&mut self, frames: usize) -> &mut Self {
+        let mut done = 0;
+        while done < frames {
+            let length = usize::min(frames - done, self.buffer_size);
+            self.evaluate(length);
+            done += length;
+        }
+        self
+    }
+
+    pub fn run_blocks(&mut self, blocks: usize) -> &mut Self {
+        self.run(blocks * self.buffer_size)
+    }
+
+    pub fn run_seconds(&mut self, seconds: f32) -> &mut Self {
+        self.run((seconds * self.sample_rate as f32).round() as usize)
+    }
+
+    /// Everything a voltage output put out so far
+    #[track_caller]
+    pub fn output(&self, name: &str) -> Signal<'_> {
+        match self.outputs.get(name) {
+            Some(samples) => Signal::new(samples, self.sample_rate),
+            None => panic!(
+                "the model has no voltage output `{}`, it has {:?}",
+                name,
+                sorted(self.outputs.keys())
+            ),
+        }
+    }
+
+    /// Every message a MIDI output put out so far, with the sample it was put out at
+    #[track_caller]
+    pub fn midi_output(&self, name: &str) -> &[(u64, MidiMessage)] {
+        match self.midi_outputs.get(name) {
+            Some(events) => events,
+            None => panic!(
+                "the model has no MIDI output `{}`, it has {:?}",
+                name,
+                sorted(self.midi_outputs.keys())
+            ),
+        }
+    }
+
+    /// Forgets the outputs so far, like after letting the model settle
+    pub fn clear_outputs(&mut self) -> &mut Self {
+        self.outputs.values_mut().for_each(Vec::clear);
+        self.midi_outputs.values_mut().for_each(Vec::clear);
+        self
+    }
+
+    /// Calls `Model::reset`, the tester's clock and outputs carry on
+    pub fn reset(&mut self) -> &mut Self {
+        self.model.lock().reset();
+        self
+    }
+
+    /// The model, to check its state or change it directly
+    pub fn model(&self) -> &ModelHolder {
+        &self.model
+    }
+
+    /// Samples evaluated so far
+    pub fn time(&self) -> u64 {
+        self.time
+    }
+
+    /// Under- and overruns the model reported through `Config::report_xrun`
+    pub fn xruns(&self) -> u64 {
+        self.diagnostics.stats().model_xruns
+    }
+
+    #[track_caller]
+    fn check_input(&self, name: &str, io: IOType) {
+        let inputs = self.model.lock().input_format();
+        if inputs.get(name) != Some(&io) {
+            let names = sorted(
+                inputs
+                    .iter()
+                    .filter(|(_, i)| **i == io)
+                    .map(|(name, _)| name),
+            );
+            let kind = match io {
+                IOType::Voltage => "voltage",
+                IOType::Midi => "MIDI",
+            };
+            panic!(
+                "the model has no {} input `{}`, it has {:?}",
+                kind, name, names
+            );
+        }
+    }
+
+    fn evaluate(&mut self, length: usize) {
+        let model = Arc::clone(&self.model);
+        let mut model = model.lock();
+        let delta = 1.0 / self.sample_rate as f32;
+
+        let mut voltages = HashMap::new();
+        let mut midi_events = HashMap::new();
+        for (name, io) in model.input_format() {
+            match io {
+                IOType::Voltage => {
+                    let buffer: Vec<f32> = (0..length as u64)
+                        .map(|i| self.sample(&name, self.time + i, delta))
+                        .collect();
+                    voltages.insert(name, buffer);
+                }
+                IOType::Midi => {
+                    let buffer = self.midi_block(&name, length);
+                    midi_events.insert(name, buffer);
+                }
+            }
+        }
+        let voltages = voltages.iter().map(|(n, b)| (n.clone(), b)).collect();
+        let midi_events = midi_events.iter().map(|(n, b)| (n.clone(), b)).collect();
+        let input = Input {
+            voltages: &voltages,
+            midi_events: &midi_events,
+        };
+        let mut output = Output::new(model.output_format(), length);
+        let config = Config {
+            buffer_size: length,
+            delta,
+            time: self.time,
+            diagnostics: Arc::clone(&self.diagnostics),
+        };
+        model.evaluate(length, input, &mut output, &config);
+
+        for (name, buffer) in output.voltages {
+            if let Some(samples) = self.outputs.get_mut(&name) {
+                samples.extend(buffer);
+            }
+        }
+        for (name, buffer) in output.midi_events {
+            if let Some(events) = self.midi_outputs.get_mut(&name) {
+                events.extend(
+                    buffer
+                        .into_iter()
+                        .enumerate()
+                        .filter_map(|(i, m)| Some((self.time + i as u64, m?))),
+                );
+            }
+        }
+        self.time += length as u64;
+    }
+
+    fn sample(&mut self, name: &str, time: u64, delta: f32) -> f32 {
+        match self.inputs.get_mut(name) {
+            Some(Source::Samples { start, samples }) => time
+                .checked_sub(*start)
+                .and_then(|i| samples.get(i as usize))
+                .copied()
+                .unwrap_or(0.),
+            Some(Source::Function(signal)) => signal(time as f32 * delta),
+            None => 0.,
+        }
+    }
+
+    //Takes the events of the next `length` samples off a MIDI input
+    fn midi_block(&mut self, name: &str, length: usize) -> Vec<Option<MidiMessage>> {
+        let mut buffer = vec![None; length];
+        let events = match self.midi_inputs.get_mut(name) {
+            Some(events) => events,
+            None => return buffer,
+        };
+        let mut played = 0;
+        for (time, message) in events.iter() {
+            let index = time.saturating_sub(self.time) as usize;
+            if index >= length {
+                break;
+            }
+            //Only one message fits in a sample, anything else is moved back a sample
+            match (index..length).find(|i| buffer[*i].is_none()) {
+                Some(free) => buffer[free] = Some(*message),
+                None => break,
+            }
+            played += 1;
+        }
+        events.drain(..played);
+        buffer
+    }
+}
+
+/// A stretch of samples with measurements and assertions for tests
+#[derive(Clone, Copy, Debug)]
+pub struct Signal<'a> {
+    samples: &'a [f32],
+    sample_rate: usize,
+}
+
+impl<'a> Signal<'a> {
+    pub fn new(samples: &'a [f32], sample_rate: usize) -> Self {
+        Signal {
+            samples,
+            sample_rate,
+        }
+    }
+
+    pub fn samples(&self) -> &'a [f32] {
+        self.samples
+    }
+
+    /// The signal without its first `seconds`, like a filter settling
+    pub fn skip(&self, seconds: f32) -> Signal<'a> {
+        let skipped = (seconds * self.sample_rate as f32).round() as usize;
+        Signal::new(
+            &self.samples[skipped.min(self.samples.len())..],
+            self.sample_rate,
+        )
+    }
+
+    pub fn rms(&self) -> f32 {
+        if self.samples.is_empty() {
+            return 0.;
+        }
+        let sum: f64 = self.samples.iter().map(|s| (*s as f64).powi(2)).sum();
+        (sum / self.samples.len() as f64).sqrt() as f32
+    }
+
+    /// The largest absolute sample
+    pub fn peak(&self) -> f32 {
+        self.samples
+            .iter()
+            .fold(0., |peak: f32, s| peak.max(s.abs()))
+    }
+
+    /// The frequency in Hz from how often the signal crosses its mean going up, `None` if it
+    /// doesn't cross it twice. Good for periodic signals that cross their mean once a period.
+    pub fn frequency(&self) -> Option<f32> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        let mean = self.samples.iter().sum::<f32>() / self.samples.len() as f32;
+        let mut first = None;
+        let mut last = 0.;
+        let mut crossings = 0;
+        for (i, pair) in self.samples.windows(2).enumerate() {
+            let (a, b) = (pair[0] - mean, pair[1] - mean);
+            if a < 0. && b >= 0. {
+                //Where the line between the two samples crosses
+                let at = i as f32 + a / (a - b);
+                first.get_or_insert(at);
+                last = at;
+                crossings += 1;
+            }
+        }
+        let first = first?;
+        if crossings < 2 {
+            return None;
+        }
+        Some((crossings - 1) as f32 * self.sample_rate as f32 / (last - first))
+    }
+
+    /// Whether no sample is louder than `SILENCE`
+    pub fn is_silent(&self) -> bool {
+        self.peak() < SILENCE
+    }
+
+    /// Whether there is no NaN or infinity, which the engine treats as a fault
+    pub fn is_finite(&self) -> bool {
+        self.samples.iter().all(|s| s.is_finite())
+    }
+
+    #[track_caller]
+    pub fn assert_rms(&self, expected: f32, tolerance: f32) {
+        let rms = self.rms();
+        assert!(
+            (rms - expected).abs() <= tolerance,
+            "RMS is {}, expected {} ± {}",
+            rms,
+            expected,
+            tolerance
+        );
+    }
+
+    #[track_caller]
+    pub fn assert_peak(&self, expected: f32, tolerance: f32) {
+        let peak = self.peak();
+        assert!(
+            (peak - expected).abs() <= tolerance,
+            "peak is {}, expected {} ± {}",
+            peak,
+            expected,
+            tolerance
+        );
+    }
+
+    #[track_caller]
+    pub fn assert_frequency(&self, expected: f32, tolerance: f32) {
+        match self.frequency() {
+            Some(frequency) => assert!(
+                (frequency - expected).abs() <= tolerance,
+                "frequency is {} Hz, expected {} ± {} Hz",
+                frequency,
+                expected,
+                tolerance
+            ),
+            None => panic!(
+                "the signal has no frequency, expected {} ± {} Hz",
+                expected, tolerance
+            ),
+        }
+    }
+
+    #[track_caller]
+    pub fn assert_silent(&self) {
+        assert!(
+            self.is_silent(),
+            "expected silence, the peak is {}",
+            self.peak()
+        );
+    }
+
+    #[track_caller]
+    pub fn assert_not_silent(&self) {
+        assert!(!self.is_silent(), "expected a signal, it is silent");
+    }
+
+    #[track_caller]
+    pub fn assert_finite(&self) {
+        if let Some(index) = self.samples.iter().position(|s| !s.is_finite()) {
+            panic!(
+                "sample {} is {}, expected only finite samples",
+                index, self.samples[index]
+            );
+        }
+    }
+}
+
+fn sorted<'a>(names: impl Iterator<Item = &'a String>) -> Vec<&'a String> {
+    let mut names: Vec<&String> = names.collect();
+    names.sort_unstable();
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::{FRAC_1_SQRT_2, TAU};
+
+    use midi_types::MidiMessage;
+
+    use super::*;
+    use crate::{
+        model_utils::ConstantAmplifier,
+        ports::{Jack, Process},
+        Model,
+    };
+
+    //Passes MIDI through and reports an xrun every block a message comes through
+    #[derive(Model)]
+    struct Thru {
+        #[input(midi)]
+        input: Jack,
+        #[output(midi)]
+        output: Jack,
+    }
+
+    impl Process for Thru {
+        fn process(&mut self, ports: ThruPorts, config: &Config) {
+            ports.output.copy_from_slice(ports.input);
+            if ports.input.iter().any(Option::is_some) {
+                config.report_xrun();
+            }
+        }
+    }
+
+    fn thru() -> ModelTester {
+        ModelTester::new(
+            Thru {
+                input: Jack,
+                output: Jack,
+            },
+            48000,
+            4,
+        )
+    }
+
+    fn note(key: u8) -> MidiMessage {
+        MidiMessage::NoteOn(0.into(), key.into(), 100.into())
+    }
+
+    #[test]
+    fn samples_are_followed_by_silence() {
+        let mut tester = ModelTester::new(ConstantAmplifier::new(1.), 48000, 2);
+        tester.run(1).input("Input", [1., 2., 3.]).run(5);
+        assert_eq!(tester.output("Output").samples(), [0., 1., 2., 3., 0., 0.]);
+        assert_eq!(tester.time(), 6);
+    }
+
+    #[test]
+    fn runs_in_blocks_with_a_shorter_last_one() {
+        let mut tester = ModelTester::new(ConstantAmplifier::new(1.), 48000, 64);
+        //Functions get the time in seconds since the tester was made
+        tester.input_fn("Input", |t| t * 48000.).run(100);
+        let samples = tester.output("Output").samples();
+        assert!(samples
+            .iter()
+            .enumerate()
+            .all(|(i, s)| s.round() == i as f32));
+        assert_eq!(samples.len(), 100);
+        tester.clear_outputs().run_blocks(2).run_seconds(0.001);
+        assert_eq!(tester.output("Output").samples().len(), 128 + 48);
+        assert_eq!(tester.time(), 276);
+    }
+
+    #[test]
+    fn midi_is_played_at_its_sample() {
+        let mut tester = thru();
+        tester
+            .run(2)
+            .midi("Input", 3, note(60))
+            .midi("Input", 3, note(62));
+        tester.midi("Input", 0, note(64)).run(8);
+        //The second message on sample 5 is moved back to 6
+        assert_eq!(
+            tester.midi_output("Output"),
+            [(2, note(64)), (5, note(60)), (6, note(62))]
+        );
+        //Messages showed up in two blocks
+        assert_eq!(tester.xruns(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "the model has no voltage input `Inptu`, it has [\"Input\"]")]
+    fn unknown_inputs_panic() {
+        ModelTester::new(ConstantAmplifier::new(1.), 48000, 64).constant("Inptu", 1.);
+    }
+
+    #[test]
+    #[should_panic(expected = "the model has no voltage input `Input`, it has []")]
+    fn inputs_of_the_wrong_type_panic() {
+        thru().constant("Input", 1.);
+    }
+
+    #[test]
+    #[should_panic(expected = "the model has no parameter `Gian`, it has [\"Gain\"]")]
+    fn unknown_parameters_panic() {
+        ModelTester::new(ConstantAmplifier::new(1.), 48000, 64).set_parameter("Gian", 1.);
+    }
+
+    #[test]
+    #[should_panic(expected = "the model has no voltage output `Outptu`")]
+    fn unknown_outputs_panic() {
+        ModelTester::new(ConstantAmplifier::new(1.), 48000, 64).output("Outptu");
+    }
+
+    #[test]
+    fn signals_are_measured() {
+        let samples: Vec<f32> = (0..48000)
+            .map(|i| (TAU * 100. * i as f32 / 48000.).sin())
+            .collect();
+        let signal = Signal::new(&samples, 48000);
+        signal.assert_rms(FRAC_1_SQRT_2, 1e-3);
+        signal.assert_peak(1., 1e-3);
+        signal.assert_frequency(100., 0.1);
+        signal.assert_not_silent();
+        signal.assert_finite();
+        assert_eq!(signal.skip(0.5).samples().len(), 24000);
+        assert_eq!(signal.skip(2.).samples().len(), 0);
+
+        let silence = Signal::new(&[0., 1e-6, -1e-6], 48000);
+        silence.assert_silent();
+        assert_eq!(silence.frequency(), None);
+        assert_eq!(Signal::new(&[], 48000).rms(), 0.);
+    }
+
+    #[test]
+    #[should_panic(expected = "sample 1 is NaN, expected only finite samples")]
+    fn assert_finite_points_at_the_sample() {
+        Signal::new(&[0., f32::NAN], 48000).assert_finite();
+    }
+
+    #[test]
+    #[should_panic(expected = "frequency is 100 Hz, expected 200 ± 1 Hz")]
+    fn assert_frequency_tells_what_it_found() {
+        let samples: Vec<f32> = (0..4800)
+            .map(|i| (TAU * 100. * i as f32 / 48000.).sin())
+            .collect();
+        Signal::new(&samples, 48000).assert_frequency(200., 1.);
+    }
+}
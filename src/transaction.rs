@@ -0,0 +1,73 @@
+use crate::{Connection, ConnectionError, ModelHolder};
+
+/// A batch of graph edits that is applied as a whole or not at all, see `Engine::commit`.
+///
+/// Models added to a transaction get their IDs straight away so later edits in the same
+/// batch can connect them.
+pub struct Transaction {
+    //ID the graph hands out next, the transaction is stale once the graph has moved on
+    pub(crate) base: usize,
+    pub(crate) edits: Vec<Edit>,
+    next_id: usize,
+}
+
+pub(crate) enum Edit {
+    AddModel(ModelHolder),
+    //Puts a model back under the ID it had, used by undo and redo
+    InsertModel(usize, ModelHolder),
+    RemoveModel(usize),
+    AddConnection(Connection),
+    RemoveConnection(Connection),
+    SetLabel(usize, Option<String>),
+    SetParameter(usize, String, f32),
+}
+
+#[derive(Debug)]
+pub enum TransactionError {
+    /// The graph added models after the transaction was started so the IDs it handed out
+    /// are taken
+    Stale,
+    /// The edit at `index` could not be applied
+    Edit {
+        index: usize,
+        error: ConnectionError,
+    },
+    /// The edits would leave a loop in the graph
+    LoopingConnection,
+    /// The edit at `index` sets a parameter the model doesn't have
+    UnknownParameter { index: usize, name: String },
+}
+
+impl Transaction {
+    pub(crate) fn new(base: usize) -> Self {
+        Transaction {
+            base,
+            edits: Vec::new(),
+            next_id: base,
+        }
+    }
+
+    /// Returns the ID the model will have once the transaction is committed
+    pub fn add_model(&mut self, model: ModelHolder) -> usize {
+        self.edits.push(Edit::AddModel(model));
+        self.next_id += 1;
+        self.next_id - 1
+    }
+
+    pub fn remove_model(&mut self, id: usize) {
+        self.edits.push(Edit::RemoveModel(id));
+    }
+
+    pub fn add_connection(&mut self, connection: Connection) {
+        self.edits.push(Edit::AddConnection(connection));
+    }
+
+    /// Removing a connection that doesn't exist is not an error, like `Engine::remove_connection`
+    pub fn remove_connection(&mut self, connection: Connection) {
+        self.edits.push(Edit::RemoveConnection(connection));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.edits.is_empty()
+    }
+}
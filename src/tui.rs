@@ -0,0 +1,830 @@
+//! A terminal editor for a running engine.
+//!
+//! The left pane lists the components, the middle one shows the ports, connections and
+//! parameters of the selected component, and the right one has level meters of its inputs
+//! above a scope. Components are added from a list of the registry's models, cables are
+//! plugged by picking one port and then the other, and `:` takes patch statements like the
+//! REPL does, see `patch`.
+
+use std::{collections::HashMap, io, time::Duration};
+
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Modifier, Style},
+    symbols::Marker,
+    text::{Line, Span},
+    widgets::{
+        Axis, Block, Chart, Clear, Dataset, GraphType, List, ListItem, ListState, Paragraph,
+    },
+    DefaultTerminal, Frame,
+};
+
+use crate::{
+    export::short_type_name,
+    patch::{Patch, OUTPUT_NAME},
+    registry::ModelRegistry,
+    Connection, Engine, IOType, Port,
+};
+
+//How often the screen is redrawn when no key is pressed
+const FRAME_INTERVAL: Duration = Duration::from_millis(33);
+//Lowest level a meter shows, in dB
+const METER_FLOOR: f32 = -60.;
+//Fraction of its value a parameter changes by per key press, ten times as much with shift
+const PARAMETER_STEP: f32 = 0.01;
+//Smallest change per key press, so parameters at zero can move
+const MIN_PARAMETER_STEP: f32 = 0.001;
+
+/// A full screen editor for `engine`, until `q` is pressed.
+pub struct Tui<'a> {
+    engine: &'a mut Engine,
+    registry: ModelRegistry,
+    focus: Focus,
+    //Selected component, as an index into `Engine::components`
+    component: usize,
+    //Selected row of the ports pane
+    row: usize,
+    mode: Mode,
+    //Result of the last action, shown above the keys
+    message: Option<(String, Color)>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Focus {
+    Components,
+    Ports,
+}
+
+enum Mode {
+    Normal,
+    //Picking a model to add, the index is into `ModelRegistry::names`
+    Adding(usize),
+    //One end of a cable is plugged in, waiting for the other
+    Cabling { port: Port, output: bool },
+    //Typing patch statements
+    Command(String),
+    //Typing a new value for a parameter of the selected component
+    Value { name: String, text: String },
+    Quit,
+}
+
+//What a key did to a line being typed
+enum Typed {
+    Editing(String),
+    Entered(String),
+    Cancelled,
+}
+
+enum Row {
+    //The port and the ports connected to it
+    Input(Port, Vec<Port>),
+    Output(Port, Vec<Port>),
+    Parameter(String, f32),
+}
+
+impl<'a> Tui<'a> {
+    pub fn new(engine: &'a mut Engine, registry: ModelRegistry) -> Self {
+        Tui {
+            engine,
+            registry,
+            focus: Focus::Components,
+            component: 0,
+            row: 0,
+            mode: Mode::Normal,
+            message: None,
+        }
+    }
+
+    /// Takes over the terminal until the editor is quit, then restores it
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut terminal = ratatui::try_init()?;
+        let result = self.event_loop(&mut terminal);
+        ratatui::restore();
+        result
+    }
+
+    fn event_loop(&mut self, terminal: &mut DefaultTerminal) -> io::Result<()> {
+        while !matches!(self.mode, Mode::Quit) {
+            terminal.draw(|frame| self.draw(frame))?;
+            if event::poll(FRAME_INTERVAL)? {
+                if let Event::Key(key) = event::read()? {
+                    if key.kind == KeyEventKind::Press {
+                        self.key(key);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn selected(&self) -> Option<usize> {
+        self.engine.components().get(self.component).copied()
+    }
+
+    //Components by label, and the output as `out`
+    fn names(&self) -> HashMap<String, usize> {
+        let mut names: HashMap<String, usize> = self
+            .engine
+            .components()
+            .into_iter()
+            .filter_map(|id| Some((self.engine.component_info(id)?.label?, id)))
+            .collect();
+        names
+            .entry(String::from(OUTPUT_NAME))
+            .or_insert(self.engine.output_id());
+        names
+    }
+
+    fn name(&self, id: usize) -> String {
+        if id == self.engine.output_id() {
+            return String::from(OUTPUT_NAME);
+        }
+        self.engine
+            .component_info(id)
+            .and_then(|info| info.label)
+            .unwrap_or_else(|| format!("#{}", id))
+    }
+
+    fn rows(&self, id: usize) -> Vec<Row> {
+        let info = match self.engine.component_info(id) {
+            Some(info) => info,
+            None => return Vec::new(),
+        };
+        let mut rows = Vec::new();
+        for port in info.inputs {
+            let sources = info
+                .in_connections
+                .iter()
+                .filter(|c| c.to.name == port.name)
+                .map(|c| c.from.clone())
+                .collect();
+            rows.push(Row::Input(port, sources));
+        }
+        for port in info.outputs {
+            let targets = info
+                .out_connections
+                .iter()
+                .filter(|c| c.from.name == port.name)
+                .map(|c| c.to.clone())
+                .collect();
+            rows.push(Row::Output(port, targets));
+        }
+        let mut parameters: Vec<(String, f32)> = self
+            .engine
+            .parameters(id)
+            .unwrap_or_default()
+            .into_iter()
+            .collect();
+        parameters.sort_by(|a, b| a.0.cmp(&b.0));
+        rows.extend(
+            parameters
+                .into_iter()
+                .map(|(name, value)| Row::Parameter(name, value)),
+        );
+        rows
+    }
+
+    fn error(&mut self, message: String) {
+        self.message = Some((message, Color::Red));
+    }
+
+    fn info(&mut self, message: String) {
+        self.message = Some((message, Color::Green));
+    }
+
+    fn key(&mut self, key: KeyEvent) {
+        if key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('c') {
+            self.mode = Mode::Quit;
+            return;
+        }
+        self.message = None;
+        match std::mem::replace(&mut self.mode, Mode::Normal) {
+            Mode::Normal => self.normal_key(key),
+            Mode::Adding(selected) => self.adding_key(key, selected),
+            Mode::Cabling { port, output } => self.cabling_key(key, port, output),
+            Mode::Command(text) => match edit(key, text) {
+                Typed::Editing(text) => self.mode = Mode::Command(text),
+                Typed::Entered(text) => {
+                    if self.statements(&text) {
+                        self.info(text);
+                    }
+                }
+                Typed::Cancelled => {}
+            },
+            Mode::Value { name, text } => match edit(key, text) {
+                Typed::Editing(text) => self.mode = Mode::Value { name, text },
+                Typed::Entered(text) => self.enter_value(&name, &text),
+                Typed::Cancelled => {}
+            },
+            Mode::Quit => self.mode = Mode::Quit,
+        }
+        self.clamp();
+    }
+
+    //Keeps the selection on screen after components or rows went away
+    fn clamp(&mut self) {
+        let count = self.engine.components().len();
+        self.component = self.component.min(count.saturating_sub(1));
+        let rows = self.selected().map_or(0, |id| self.rows(id).len());
+        self.row = self.row.min(rows.saturating_sub(1));
+    }
+
+    //Moving around, shared by the normal and cabling modes. Returns false if the key isn't
+    //for moving.
+    fn navigate(&mut self, key: KeyEvent) -> bool {
+        let step: isize = match key.code {
+            KeyCode::Up | KeyCode::Char('k') => -1,
+            KeyCode::Down | KeyCode::Char('j') => 1,
+            KeyCode::PageUp => -10,
+            KeyCode::PageDown => 10,
+            KeyCode::Tab | KeyCode::BackTab => {
+                self.focus = match self.focus {
+                    Focus::Components => Focus::Ports,
+                    Focus::Ports => Focus::Components,
+                };
+                return true;
+            }
+            KeyCode::Left | KeyCode::Char('h') if self.focus == Focus::Ports => {
+                self.focus = Focus::Components;
+                return true;
+            }
+            KeyCode::Right | KeyCode::Char('l') if self.focus == Focus::Components => {
+                self.focus = Focus::Ports;
+                return true;
+            }
+            _ => return false,
+        };
+        let selection = match self.focus {
+            Focus::Components => &mut self.component,
+            Focus::Ports => &mut self.row,
+        };
+        *selection = selection.saturating_add_signed(step);
+        if self.focus == Focus::Components {
+            self.row = 0;
+        }
+        true
+    }
+
+    fn normal_key(&mut self, key: KeyEvent) {
+        let id = match self.selected() {
+            Some(id) => id,
+            None => return,
+        };
+        let row = self.rows(id).into_iter().nth(self.row);
+        let on_ports = self.focus == Focus::Ports;
+        let coarse = key.modifiers.contains(KeyModifiers::SHIFT);
+        match (key.code, row) {
+            (KeyCode::Char('q'), _) => self.mode = Mode::Quit,
+            (KeyCode::Char('a'), _) => self.mode = Mode::Adding(0),
+            (KeyCode::Char(':'), _) => self.mode = Mode::Command(String::new()),
+            (KeyCode::Char('u'), _) => self.step(true),
+            (KeyCode::Char('U'), _) => self.step(false),
+            (KeyCode::Char('r'), _) if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                self.step(false)
+            }
+            (KeyCode::Char('R'), _) => {
+                self.engine.reset_component(id);
+                self.info(format!("reset {}", self.name(id)));
+            }
+            (KeyCode::Enter, _) if !on_ports => self.focus = Focus::Ports,
+            (KeyCode::Char('x') | KeyCode::Delete, _) if !on_ports => self.remove(id),
+            (
+                KeyCode::Left | KeyCode::Char('h') | KeyCode::Char('-'),
+                Some(Row::Parameter(name, value)),
+            ) if on_ports => self.nudge(id, &name, value, -1., coarse),
+            (
+                KeyCode::Right | KeyCode::Char('l') | KeyCode::Char('+') | KeyCode::Char('='),
+                Some(Row::Parameter(name, value)),
+            ) if on_ports => self.nudge(id, &name, value, 1., coarse),
+            (KeyCode::Enter, Some(Row::Parameter(name, value))) if on_ports => {
+                self.mode = Mode::Value {
+                    name,
+                    text: value.to_string(),
+                }
+            }
+            (KeyCode::Enter | KeyCode::Char('c'), Some(Row::Input(port, _))) if on_ports => {
+                self.mode = Mode::Cabling {
+                    port,
+                    output: false,
+                }
+            }
+            (KeyCode::Enter | KeyCode::Char('c'), Some(Row::Output(port, _))) if on_ports => {
+                self.mode = Mode::Cabling { port, output: true }
+            }
+            (KeyCode::Char('x') | KeyCode::Delete, Some(Row::Input(port, sources))) if on_ports => {
+                let cables = sources
+                    .into_iter()
+                    .map(|from| Connection {
+                        from,
+                        to: port.clone(),
+                    })
+                    .collect();
+                self.unplug(cables);
+            }
+            (KeyCode::Char('x') | KeyCode::Delete, Some(Row::Output(port, targets)))
+                if on_ports =>
+            {
+                let cables = targets
+                    .into_iter()
+                    .map(|to| Connection {
+                        from: port.clone(),
+                        to,
+                    })
+                    .collect();
+                self.unplug(cables);
+            }
+            _ => {
+                self.navigate(key);
+            }
+        }
+    }
+
+    fn adding_key(&mut self, key: KeyEvent, selected: usize) {
+        let count = self.registry.names().len();
+        match key.code {
+            KeyCode::Esc => {}
+            KeyCode::Up | KeyCode::Char('k') => {
+                self.mode = Mode::Adding(selected.saturating_sub(1))
+            }
+            KeyCode::Down | KeyCode::Char('j') => {
+                self.mode = Mode::Adding((selected + 1).min(count.saturating_sub(1)))
+            }
+            KeyCode::Enter => {
+                if let Some(model) = self.registry.names().get(selected).map(|m| m.to_string()) {
+                    self.add(&model);
+                }
+            }
+            _ => self.mode = Mode::Adding(selected),
+        }
+    }
+
+    fn cabling_key(&mut self, key: KeyEvent, port: Port, output: bool) {
+        let row = self
+            .selected()
+            .and_then(|id| self.rows(id).into_iter().nth(self.row));
+        match (key.code, row) {
+            (KeyCode::Esc, _) => {}
+            (KeyCode::Enter | KeyCode::Char('c'), Some(Row::Input(to, _))) if output => {
+                self.plug(Connection { from: port, to })
+            }
+            (KeyCode::Enter | KeyCode::Char('c'), Some(Row::Output(from, _))) if !output => {
+                self.plug(Connection { from, to: port })
+            }
+            (KeyCode::Enter | KeyCode::Char('c'), _) => {
+                let wanted = if output { "an input" } else { "an output" };
+                self.error(format!("pick {} for the other end of the cable", wanted));
+                self.mode = Mode::Cabling { port, output };
+            }
+            _ => {
+                self.navigate(key);
+                self.mode = Mode::Cabling { port, output };
+            }
+        }
+    }
+
+    fn enter_value(&mut self, name: &str, text: &str) {
+        let id = match self.selected() {
+            Some(id) => id,
+            None => return,
+        };
+        match text.trim().parse::<f32>() {
+            Ok(value) if value.is_finite() => self.set_parameter(id, name, value),
+            _ => self.error(format!("`{}` is not a number", text.trim())),
+        }
+    }
+
+    fn add(&mut self, model: &str) {
+        let name = self.new_name(model);
+        let statement = format!("{} = {}", name, model);
+        if self.statements(&statement) {
+            if let Some(index) = self.engine.components().iter().position(|id| {
+                self.engine.component_info(*id).and_then(|info| info.label) == Some(name.clone())
+            }) {
+                self.component = index;
+                self.row = 0;
+            }
+            self.info(format!("added {}", name));
+        }
+    }
+
+    //A name for a new component, like `constant_amplifier1` for a `ConstantAmplifier`
+    fn new_name(&self, model: &str) -> String {
+        let mut base = String::new();
+        for (i, c) in model.chars().enumerate() {
+            if c.is_uppercase() && i > 0 {
+                base.push('_');
+            }
+            base.extend(c.to_lowercase());
+        }
+        let names = self.names();
+        (1..)
+            .map(|n| format!("{}{}", base, n))
+            .find(|name| !names.contains_key(name))
+            .unwrap_or(base)
+    }
+
+    //Applies patch statements, returns whether they worked
+    fn statements(&mut self, text: &str) -> bool {
+        let applied = Patch::parse(text).and_then(|patch| {
+            let mut names = self.names();
+            patch.apply_to(self.engine, &self.registry, &mut names)
+        });
+        match applied {
+            Ok(()) => true,
+            Err(e) => {
+                let message = match e.position() {
+                    Some(at) => format!("{} (column {})", e, at.column),
+                    None => e.to_string(),
+                };
+                self.error(message);
+                false
+            }
+        }
+    }
+
+    fn remove(&mut self, id: usize) {
+        if id == self.engine.output_id() {
+            self.error(String::from("the output can't be removed"));
+            return;
+        }
+        let name = self.name(id);
+        self.engine.remove_model(id);
+        self.info(format!("removed {}", name));
+    }
+
+    fn plug(&mut self, connection: Connection) {
+        let description = self.cable(&connection);
+        match self.engine.add_connection(connection) {
+            Ok(()) => self.info(format!("connected {}", description)),
+            Err(e) => self.error(format!("can't connect {}: {:?}", description, e)),
+        }
+    }
+
+    fn unplug(&mut self, connections: Vec<Connection>) {
+        if connections.is_empty() {
+            self.error(String::from("nothing is plugged in there"));
+            return;
+        }
+        //One undo step puts all of them back
+        self.engine.begin_group();
+        let mut removed = 0;
+        for connection in connections {
+            if let Ok(true) = self.engine.remove_connection(connection) {
+                removed += 1;
+            }
+        }
+        self.engine.end_group();
+        self.info(format!(
+            "removed {} cable{}",
+            removed,
+            if removed == 1 { "" } else { "s" }
+        ));
+    }
+
+    fn cable(&self, connection: &Connection) -> String {
+        format!(
+            "{}.{} -> {}.{}",
+            self.name(connection.from.id),
+            connection.from.name,
+            self.name(connection.to.id),
+            connection.to.name
+        )
+    }
+
+    fn nudge(&mut self, id: usize, name: &str, value: f32, direction: f32, coarse: bool) {
+        let mut step = (value.abs() * PARAMETER_STEP).max(MIN_PARAMETER_STEP);
+        if coarse {
+            step *= 10.;
+        }
+        self.set_parameter(id, name, value + direction * step);
+    }
+
+    fn set_parameter(&mut self, id: usize, name: &str, value: f32) {
+        if self.engine.set_parameter(id, name, value) {
+            self.info(format!("{}.{} = {}", self.name(id), name, value));
+        } else {
+            self.error(format!("{} has no parameter `{}`", self.name(id), name));
+        }
+    }
+
+    fn step(&mut self, undo: bool) {
+        let stepped = if undo {
+            self.engine.undo()
+        } else {
+            self.engine.redo()
+        };
+        match stepped {
+            Ok(true) if undo => self.info(String::from("undone")),
+            Ok(true) => self.info(String::from("redone")),
+            Ok(false) if undo => self.error(String::from("nothing to undo")),
+            Ok(false) => self.error(String::from("nothing to redo")),
+            Err(e) => self.error(format!("{:?}", e)),
+        }
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [main, status, keys] = Layout::vertical([
+            Constraint::Min(0),
+            Constraint::Length(1),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [components, ports, right] = Layout::horizontal([
+            Constraint::Percentage(25),
+            Constraint::Percentage(40),
+            Constraint::Percentage(35),
+        ])
+        .areas(main);
+        let ids = self.engine.components();
+        let selected = ids.get(self.component).copied();
+        let rows = selected.map(|id| self.rows(id)).unwrap_or_default();
+
+        self.draw_components(frame, components, &ids);
+        self.draw_ports(frame, ports, selected, &rows);
+        self.draw_levels(frame, right, selected, &rows);
+
+        let status_line = match &self.mode {
+            Mode::Command(text) => Line::from(format!(":{}_", text)),
+            Mode::Value { name, text } => Line::from(format!("{} = {}_", name, text)),
+            Mode::Cabling { port, .. } => {
+                let mut line = Line::from(Span::styled(
+                    format!("plugging {}.{}  ", self.name(port.id), port.name),
+                    Style::new().fg(Color::Yellow),
+                ));
+                if let Some((message, color)) = &self.message {
+                    line.push_span(Span::styled(message.as_str(), Style::new().fg(*color)));
+                }
+                line
+            }
+            _ => match &self.message {
+                Some((message, color)) => {
+                    Line::from(Span::styled(message.as_str(), Style::new().fg(*color)))
+                }
+                None => Line::default(),
+            },
+        };
+        frame.render_widget(Paragraph::new(status_line), status);
+        frame.render_widget(
+            Paragraph::new(self.keys()).style(Style::new().fg(Color::DarkGray)),
+            keys,
+        );
+
+        if let Mode::Adding(index) = self.mode {
+            self.draw_models(frame, main, index);
+        }
+    }
+
+    //What the keys do in the current mode
+    fn keys(&self) -> &'static str {
+        match self.mode {
+            Mode::Adding(_) => "↑↓ choose  enter add  esc cancel",
+            Mode::Cabling { .. } => {
+                "↑↓←→ tab move  enter plug into the selected port  esc cancel"
+            }
+            Mode::Command(_) => "patch statements like `a.Output -> b.Input`  enter run  esc cancel",
+            Mode::Value { .. } => "enter set  esc cancel",
+            _ if self.focus == Focus::Ports => {
+                "↑↓ move  ←→ adjust (shift: more)  enter edit/plug  x unplug  u/U undo/redo  a add  : patch  q quit"
+            }
+            _ => "↑↓ move  enter ports  x remove  R reset  u/U undo/redo  a add  : patch  q quit",
+        }
+    }
+
+    fn highlight(&self, focus: Focus) -> Style {
+        if self.focus == focus {
+            Style::new().add_modifier(Modifier::REVERSED)
+        } else {
+            Style::new().add_modifier(Modifier::BOLD)
+        }
+    }
+
+    fn draw_components(&self, frame: &mut Frame, area: Rect, ids: &[usize]) {
+        let items: Vec<ListItem> = ids
+            .iter()
+            .map(|id| {
+                let info = self.engine.component_info(*id);
+                let type_name = info
+                    .as_ref()
+                    .map_or("", |info| short_type_name(info.type_name));
+                let mut line = Line::from(vec![
+                    Span::raw(format!("{:<12} ", self.name(*id))),
+                    Span::styled(type_name, Style::new().fg(Color::DarkGray)),
+                ]);
+                if info.and_then(|info| info.fault).is_some() {
+                    line.push_span(Span::styled(" faulted", Style::new().fg(Color::Red)));
+                }
+                ListItem::new(line)
+            })
+            .collect();
+        let list = List::new(items)
+            .block(Block::bordered().title("Components"))
+            .highlight_style(self.highlight(Focus::Components));
+        let mut state = ListState::default().with_selected(Some(self.component));
+        frame.render_stateful_widget(list, area, &mut state);
+    }
+
+    fn draw_ports(&self, frame: &mut Frame, area: Rect, selected: Option<usize>, rows: &[Row]) {
+        let plugging = match &self.mode {
+            Mode::Cabling { port, output } => Some((port, *output)),
+            _ => None,
+        };
+        let ends = |ports: &[Port]| -> String {
+            ports
+                .iter()
+                .map(|p| format!("{}.{}", self.name(p.id), p.name))
+                .collect::<Vec<String>>()
+                .join(", ")
+        };
+        let items: Vec<ListItem> = rows
+            .iter()
+            .map(|row| {
+                let (text, port, output) = match row {
+                    Row::Input(port, sources) => (
+                        format!(
+                            "in  {:<12} {:<8} {}",
+                            port.name,
+                            io_name(&port.io),
+                            arrow("<-", &ends(sources))
+                        ),
+                        Some(port),
+                        false,
+                    ),
+                    Row::Output(port, targets) => (
+                        format!(
+                            "out {:<12} {:<8} {}",
+                            port.name,
+                            io_name(&port.io),
+                            arrow("->", &ends(targets))
+                        ),
+                        Some(port),
+                        true,
+                    ),
+                    Row::Parameter(name, value) => {
+                        (format!("    {} = {}", name, value), None, false)
+                    }
+                };
+                let style = match (plugging, port) {
+                    (Some((plugged, _)), Some(port)) if plugged == port => {
+                        Style::new().fg(Color::Yellow)
+                    }
+                    //Ports the cable can go to
+                    (Some((_, from_output)), Some(_)) if from_output != output => {
+                        Style::new().fg(Color::Cyan)
+                    }
+                    (_, None) => Style::new().fg(Color::Magenta),
+                    _ => Style::new(),
+                };
+                ListItem::new(Line::styled(text, style))
+            })
+            .collect();
+        let title = match selected.and_then(|id| Some((id, self.engine.component_info(id)?))) {
+            Some((id, info)) => format!(
+                "{} (#{}, {})",
+                self.name(id),
+                id,
+                short_type_name(info.type_name)
+            ),
+            None => String::from("Ports"),
+        };
+        let list = List::new(items)
+            .block(Block::bordered().title(title))
+            .highlight_style(self.highlight(Focus::Ports));
+        let mut state = ListState::default().with_selected(Some(self.row));
+        frame.render_stateful_widget(list, area, &mut state);
+    }
+
+    fn draw_levels(&self, frame: &mut Frame, area: Rect, selected: Option<usize>, rows: &[Row]) {
+        let id = match selected {
+            Some(id) => id,
+            None => return,
+        };
+        let inputs: Vec<&Port> = rows
+            .iter()
+            .filter_map(|row| match row {
+                Row::Input(port, _) if port.io == IOType::Voltage => Some(port),
+                _ => None,
+            })
+            .collect();
+        let meters_height = (inputs.len() as u16 + 2).min(area.height / 2);
+        let [meters, scope] =
+            Layout::vertical([Constraint::Length(meters_height), Constraint::Min(0)]).areas(area);
+
+        let width = meters.width.saturating_sub(25) as usize;
+        let lines: Vec<Line> = inputs
+            .iter()
+            .map(|port| {
+                let peak = self.engine.peak(id, &port.name).unwrap_or(0.);
+                meter(&port.name, peak, width)
+            })
+            .collect();
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title("Levels")),
+            meters,
+        );
+
+        //The selected input, what the selected output feeds, or else the first input
+        let target = match rows.get(self.row) {
+            Some(Row::Input(port, _)) if port.io == IOType::Voltage => Some(port.clone()),
+            Some(Row::Output(port, targets)) if port.io == IOType::Voltage => {
+                targets.first().cloned()
+            }
+            _ => None,
+        }
+        .or_else(|| inputs.first().map(|port| (*port).clone()));
+        let (title, samples) = match target {
+            Some(port) => (
+                format!("Scope {}.{}", self.name(port.id), port.name),
+                self.engine.scope(port.id, &port.name).unwrap_or_default(),
+            ),
+            None => (String::from("Scope"), Vec::new()),
+        };
+        let points: Vec<(f64, f64)> = samples
+            .iter()
+            .enumerate()
+            .map(|(i, s)| (i as f64, *s as f64))
+            .collect();
+        //Grows past ±1 so louder signals still fit
+        let range = samples.iter().fold(1.0, |range: f32, s| range.max(s.abs())) as f64;
+        let chart = Chart::new(vec![Dataset::default()
+            .marker(Marker::Braille)
+            .graph_type(GraphType::Line)
+            .style(Style::new().fg(Color::Green))
+            .data(&points)])
+        .block(Block::bordered().title(title))
+        .x_axis(Axis::default().bounds([0., samples.len().max(1) as f64 - 1.]))
+        .y_axis(Axis::default().bounds([-range, range]).labels([
+            format!("{:.1}", -range),
+            String::new(),
+            format!("{:.1}", range),
+        ]));
+        frame.render_widget(chart, scope);
+    }
+
+    fn draw_models(&self, frame: &mut Frame, area: Rect, selected: usize) {
+        let names = self.registry.names();
+        let width = names.iter().map(|n| n.len()).max().unwrap_or(0) as u16 + 4;
+        let height = names.len() as u16 + 2;
+        let popup = Rect {
+            x: area.x + area.width.saturating_sub(width) / 2,
+            y: area.y + area.height.saturating_sub(height) / 2,
+            width: width.max(16).min(area.width),
+            height: height.min(area.height),
+        };
+        let list = List::new(names)
+            .block(Block::bordered().title("Add"))
+            .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        let mut state = ListState::default().with_selected(Some(selected));
+        frame.render_widget(Clear, popup);
+        frame.render_stateful_widget(list, popup, &mut state);
+    }
+}
+
+fn edit(key: KeyEvent, mut text: String) -> Typed {
+    match key.code {
+        KeyCode::Esc => Typed::Cancelled,
+        KeyCode::Enter => Typed::Entered(text),
+        KeyCode::Backspace => {
+            text.pop();
+            Typed::Editing(text)
+        }
+        KeyCode::Char(c) => {
+            text.push(c);
+            Typed::Editing(text)
+        }
+        _ => Typed::Editing(text),
+    }
+}
+
+fn io_name(io: &IOType) -> &'static str {
+    match io {
+        IOType::Voltage => "voltage",
+        IOType::Midi => "midi",
+    }
+}
+
+fn arrow(arrow: &str, ends: &str) -> String {
+    if ends.is_empty() {
+        String::new()
+    } else {
+        format!("{} {}", arrow, ends)
+    }
+}
+
+fn meter(name: &str, peak: f32, width: usize) -> Line<'static> {
+    let db = 20. * peak.max(1e-6).log10();
+    let filled = (((db - METER_FLOOR) / -METER_FLOOR).clamp(0., 1.) * width as f32) as usize;
+    let color = if peak >= 1. {
+        Color::Red
+    } else if db > -6. {
+        Color::Yellow
+    } else {
+        Color::Green
+    };
+    Line::from(vec![
+        Span::raw(format!("{:<12} ", name)),
+        Span::styled("█".repeat(filled), Style::new().fg(color)),
+        Span::raw(" ".repeat(width - filled)),
+        Span::raw(format!(" {:>6.1} dB", db.max(METER_FLOOR))),
+    ])
+}
@@ -0,0 +1,76 @@
+use proto::{
+    ports::{Jack, Process},
+    Config, IOType, Model,
+};
+
+#[test]
+fn bad_attributes_are_reported() {
+    let cases = trybuild::TestCases::new();
+    cases.compile_fail("tests/ui/*.rs");
+}
+
+#[derive(Model)]
+struct Names {
+    #[input(voltage)]
+    input_1: Jack,
+    #[input(midi)]
+    note_on_off: Jack,
+    #[output(voltage, name = "Left")]
+    out_l: Jack,
+    #[output(voltage)]
+    r#type: Jack,
+    #[parameter(min = 0.0, max = 2.0)]
+    input_1_gain: f32,
+    #[parameter]
+    resonance: f32,
+}
+
+impl Process for Names {
+    fn process(&mut self, _ports: NamesPorts, _config: &Config) {}
+}
+
+fn names() -> Names {
+    Names {
+        input_1: Jack,
+        note_on_off: Jack,
+        out_l: Jack,
+        r#type: Jack,
+        input_1_gain: 1.,
+        resonance: 0.,
+    }
+}
+
+fn sorted<T>(names: impl IntoIterator<Item = (String, T)>) -> Vec<String> {
+    let mut names: Vec<String> = names.into_iter().map(|(name, _)| name).collect();
+    names.sort_unstable();
+    names
+}
+
+#[test]
+fn fields_are_named_in_camel_case() {
+    let model = names();
+    assert_eq!(
+        model.input_format(),
+        [
+            (String::from("Input1"), IOType::Voltage),
+            (String::from("NoteOnOff"), IOType::Midi)
+        ]
+        .into()
+    );
+    assert_eq!(sorted(model.output_format()), ["Left", "Type"]);
+    assert_eq!(sorted(model.parameters()), ["Input1Gain", "Resonance"]);
+    assert_eq!(Names::INPUTS[0], ("Input1", IOType::Voltage));
+    assert_eq!(Names::OUTPUTS[1], ("Type", IOType::Voltage));
+    assert_eq!(Names::PARAMETERS[0].name, "Input1Gain");
+}
+
+#[test]
+fn parameters_are_clamped() {
+    let mut model = names();
+    assert!(model.set_parameter("Input1Gain", 5.));
+    assert_eq!(model.parameters()["Input1Gain"], 2.);
+    assert!(model.set_parameter("Input1Gain", -1.));
+    assert_eq!(model.parameters()["Input1Gain"], 0.);
+    assert!(model.set_parameter("Resonance", -1.));
+    assert!(!model.set_parameter("input_1_gain", 1.));
+}
@@ -0,0 +1,17 @@
+use proto::{ports::Jack, Model};
+
+#[derive(Model)]
+struct Mixer {
+    #[input(voltage)]
+    input: Jack,
+    #[input(voltage, name = "Input")]
+    other: Jack,
+    #[output(voltage)]
+    output: Jack,
+    #[parameter]
+    input_gain: f32,
+    #[parameter(name = "InputGain")]
+    other_gain: f32,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use proto::Model;
+
+#[derive(Model)]
+struct Constant {
+    #[parameter]
+    value: f32,
+}
+
+fn main() {}
@@ -0,0 +1,11 @@
+use proto::{ports::Jack, Model};
+
+#[derive(Model)]
+struct Amp(Jack);
+
+#[derive(Model)]
+enum Mode {
+    Loud,
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+use proto::{ports::Jack, Model};
+
+#[derive(Model)]
+struct Amp {
+    #[output(voltage)]
+    #[parameter]
+    output: Jack,
+}
+
+fn main() {}
@@ -0,0 +1,18 @@
+use proto::{
+    ports::{Jack, Process},
+    Config, Model,
+};
+
+#[derive(Model)]
+struct Amp {
+    #[input(voltage)]
+    input: f32,
+    #[output(voltage)]
+    output: Jack,
+}
+
+impl Process for Amp {
+    fn process(&mut self, _ports: AmpPorts, _config: &Config) {}
+}
+
+fn main() {}
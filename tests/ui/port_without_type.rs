@@ -0,0 +1,9 @@
+use proto::{ports::Jack, Model};
+
+#[derive(Model)]
+struct Amp {
+    #[output(name = "Out")]
+    output: Jack,
+}
+
+fn main() {}
@@ -0,0 +1,11 @@
+use proto::{ports::Jack, Model};
+
+#[derive(Model)]
+struct Amp {
+    #[output(voltage)]
+    output: Jack,
+    #[parameter(default = 1.0)]
+    gain: f32,
+}
+
+fn main() {}
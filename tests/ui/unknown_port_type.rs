@@ -0,0 +1,9 @@
+use proto::{ports::Jack, Model};
+
+#[derive(Model)]
+struct Amp {
+    #[input(audio)]
+    input: Jack,
+}
+
+fn main() {}